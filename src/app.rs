@@ -1,17 +1,19 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use chrono::Utc;
 use eframe::egui;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
 
 use crate::audio::AudioPipeline;
-use crate::audio::jitter::JitterBuffer;
+use crate::audio::mixer::{SharedMixer, MAX_GAIN};
+use crate::net::chat;
+use crate::net::control::{HostHandover, ModerationAction};
 use crate::net::manager::{ConnectResult, NetworkManager};
-use crate::net::session::{PeerState, SharedSessionState};
+use crate::net::session::{PeerPath, PeerState, Role, SharedSessionState, Topology};
 use crate::recording;
-use crate::video::VideoPipeline;
+use crate::video::{ladder, VideoPipeline};
 
 #[derive(Debug, Clone, PartialEq)]
 enum AppScreen {
@@ -28,20 +30,39 @@ pub struct HyperZoomApp {
     name_input: String,
     port_input: String,
     host_addr_input: String,
+    /// Optional room password: checked by the host when a guest joins, and
+    /// mixed into the encryption key exchange.
+    passphrase_input: String,
+    /// Host: forward all media through us instead of a full mesh.
+    sfu_mode: bool,
+    /// Host: hold guests in a waiting room until we admit them.
+    waiting_room: bool,
     status_message: String,
 
     // Connection in progress
     connect_rx: Option<oneshot::Receiver<ConnectResult>>,
+    /// Guest: turns true while the host keeps us in its waiting room.
+    join_waiting: Option<watch::Receiver<bool>>,
 
     // InCall state
     session_state: Option<SharedSessionState>,
     // Keep pipeline alive by holding a reference
-    _audio_pipeline: Option<AudioPipeline>,
+    audio_pipeline: Option<AudioPipeline>,
     video_pipeline: Option<VideoPipeline>,
     camera_on: bool,
+    mic_on: bool,
+    /// Guest: the last thing the host did to our mic or camera, until we touch them.
+    host_notice: Option<&'static str>,
+    show_stats: bool,
+    show_chat: bool,
+    chat_input: String,
+    /// A file dropped on the window, sent with the next chat message.
+    chat_attachment: Option<(String, Vec<u8>)>,
+    /// Why the last dropped file wasn't attached.
+    chat_notice: Option<String>,
     // Keep manager pieces alive
-    manager_transport: Option<Arc<crate::net::socket::UdpTransport>>,
-    manager_jitter: Option<Arc<Mutex<JitterBuffer>>>,
+    manager_transport: Option<Arc<dyn crate::net::transport::Transport>>,
+    manager_mixer: Option<SharedMixer>,
     manager_heartbeat_stop: Option<tokio::sync::watch::Sender<bool>>,
     manager_inbound_stop: Option<tokio::sync::watch::Sender<bool>>,
     manager_upnp: Option<crate::net::upnp::PortMapping>,
@@ -52,6 +73,8 @@ pub struct HyperZoomApp {
     // Recording state
     session_dir: Option<PathBuf>,
     session_start_time: Option<chrono::DateTime<Utc>>,
+    /// Our session clock when the local recording started.
+    recording_start_ms: Option<u32>,
     recording_path_display: Option<String>,
 }
 
@@ -63,20 +86,32 @@ impl HyperZoomApp {
             name_input: "User".into(),
             port_input: "9000".into(),
             host_addr_input: "127.0.0.1:9000".into(),
+            passphrase_input: String::new(),
+            sfu_mode: false,
+            waiting_room: false,
             status_message: String::new(),
             connect_rx: None,
+            join_waiting: None,
             session_state: None,
-            _audio_pipeline: None,
+            audio_pipeline: None,
             video_pipeline: None,
             camera_on: true,
+            mic_on: true,
+            host_notice: None,
+            show_stats: false,
+            show_chat: true,
+            chat_input: String::new(),
+            chat_attachment: None,
+            chat_notice: None,
             manager_transport: None,
-            manager_jitter: None,
+            manager_mixer: None,
             manager_heartbeat_stop: None,
             manager_inbound_stop: None,
             manager_upnp: None,
             end_reason: String::new(),
             session_dir: None,
             session_start_time: None,
+            recording_start_ms: None,
             recording_path_display: None,
         }
     }
@@ -96,14 +131,17 @@ impl HyperZoomApp {
 
         let handle = self.runtime.handle().clone();
         let name = self.name_input.clone();
-        NetworkManager::host(handle, name, port, tx);
+        let passphrase = self.passphrase_input.clone();
+        let topology = if self.sfu_mode { Topology::Sfu } else { Topology::Mesh };
+        NetworkManager::host(handle, name, passphrase, port, topology, self.waiting_room, tx);
     }
 
     fn handle_join(&mut self) {
-        let host_addr: SocketAddr = match self.host_addr_input.parse() {
+        // IPv6 hosts are written with brackets, e.g. [2001:db8::1]:5000
+        let host_addr: SocketAddr = match self.host_addr_input.trim().parse() {
             Ok(a) => a,
             Err(_) => {
-                self.status_message = "Invalid host address (use IP:port)".into();
+                self.status_message = "Invalid host address (use IP:port or [IPv6]:port)".into();
                 return;
             }
         };
@@ -123,7 +161,10 @@ impl HyperZoomApp {
 
         let handle = self.runtime.handle().clone();
         let name = self.name_input.clone();
-        NetworkManager::join(handle, name, host_addr, local_port, tx);
+        let passphrase = self.passphrase_input.clone();
+        let (waiting_tx, waiting_rx) = watch::channel(false);
+        self.join_waiting = Some(waiting_rx);
+        NetworkManager::join(handle, name, passphrase, host_addr, local_port, waiting_tx, tx);
     }
 
     fn handle_end_call(&mut self) {
@@ -139,21 +180,34 @@ impl HyperZoomApp {
 
         // Send BYE packets
         if let (Some(state), Some(transport)) = (&self.session_state, &self.manager_transport) {
-            let (my_id, ts, peer_addrs) = {
+            let (my_id, ts, peer_addrs, successor) = {
                 let mut s = state.lock().unwrap();
                 s.ended = true;
-                (s.my_participant_id, s.elapsed_ms(), s.connected_peer_addrs())
+                let successor = match s.role {
+                    Role::Host => s.successor(),
+                    Role::Guest => None,
+                };
+                (s.my_participant_id, s.elapsed_ms(), s.connected_peer_addrs(), successor)
             };
 
             use crate::net::protocol::{Packet, PacketHeader, PacketType};
             let header = PacketHeader::new(PacketType::Bye, my_id, 0, ts, 0);
             let packet = Packet::new(header, Vec::new()).to_bytes();
+            // A leaving host names who takes over, ahead of its BYE
+            let handover = successor.map(|new_host_id| {
+                let payload = HostHandover { new_host_id }.to_bytes();
+                let header = PacketHeader::new(PacketType::Control, my_id, 0, ts, payload.len() as u16);
+                Packet::new(header, payload).to_bytes()
+            });
 
             let transport = transport.clone();
             self.runtime.spawn(async move {
                 for _ in 0..3 {
                     for addr in &peer_addrs {
-                        let _ = transport.send_to(&packet, *addr).await;
+                        if let Some(handover) = &handover {
+                            let _ = transport.send_to(handover, *addr);
+                        }
+                        let _ = transport.send_to(&packet, *addr);
                     }
                     tokio::time::sleep(std::time::Duration::from_millis(50)).await;
                 }
@@ -170,14 +224,33 @@ impl HyperZoomApp {
         // Collect session info before dropping state
         let participants = if let Some(state) = &self.session_state {
             let s = state.lock().unwrap();
+            let my_clock = s.clock_offset_to_host(s.my_participant_id);
             let mut parts = vec![recording::ParticipantInfo {
                 id: s.my_participant_id,
                 name: s.my_name.clone(),
+                clock_offset_ms: my_clock.map(|(offset, _)| offset),
+                clock_uncertainty_ms: my_clock.map(|(_, uncertainty)| uncertainty),
+                network: None,
             }];
             for peer in s.peers.values() {
+                let stats = &peer.stats;
+                let clock = s.clock_offset_to_host(peer.participant_id);
                 parts.push(recording::ParticipantInfo {
                     id: peer.participant_id,
                     name: peer.name.clone(),
+                    clock_offset_ms: clock.map(|(offset, _)| offset),
+                    clock_uncertainty_ms: clock.map(|(_, uncertainty)| uncertainty),
+                    network: Some(recording::NetworkStatsInfo {
+                        rtt_ms: stats.rtt_ms,
+                        audio_loss_percent: stats.audio.total_loss_percent(),
+                        audio_jitter_ms: stats.audio.jitter_ms(),
+                        video_loss_percent: stats.video.total_loss_percent(),
+                        video_jitter_ms: stats.video.jitter_ms(),
+                        packets_in: stats.packets_in,
+                        bytes_in: stats.bytes_in,
+                        packets_out: stats.packets_out,
+                        bytes_out: stats.bytes_out,
+                    }),
                 });
             }
             parts
@@ -189,7 +262,7 @@ impl HyperZoomApp {
         self.video_pipeline = None;
 
         // Drop audio pipeline (stops recorder first, then encode/refill threads)
-        self._audio_pipeline = None;
+        self.audio_pipeline = None;
 
         // Write session metadata
         if let (Some(dir), Some(start_time)) = (&self.session_dir, self.session_start_time) {
@@ -199,6 +272,11 @@ impl HyperZoomApp {
                 .num_milliseconds() as f64
                 / 1000.0;
 
+            let recording_start_host_ms = match (&self.session_state, self.recording_start_ms) {
+                (Some(state), Some(local_ms)) => state.lock().unwrap().to_host_time_ms(local_ms),
+                _ => None,
+            };
+
             let metadata = recording::SessionMetadata {
                 session_id: self
                     .session_state
@@ -208,6 +286,7 @@ impl HyperZoomApp {
                 start_time: start_time.to_rfc3339(),
                 end_time: end_time.to_rfc3339(),
                 duration_seconds: duration,
+                recording_start_host_ms,
                 participants,
                 recording: recording::RecordingInfo {
                     file: recording::recording_filename().to_string(),
@@ -221,12 +300,48 @@ impl HyperZoomApp {
             if let Err(e) = recording::write_metadata(dir, &metadata) {
                 log::error!("Failed to write session metadata: {e}");
             }
+
+            if let Some(state) = &self.session_state {
+                let lines: Vec<recording::ChatLine> = state
+                    .lock()
+                    .unwrap()
+                    .chat
+                    .entries
+                    .iter()
+                    .map(|entry| recording::ChatLine {
+                        time: entry.time.to_rfc3339(),
+                        participant_id: entry.author,
+                        name: entry.author_name.clone(),
+                        text: entry.text.clone(),
+                        attachment: entry.attachment.as_ref().map(|a| recording::ChatAttachmentInfo {
+                            name: a.name.clone(),
+                            size: a.size,
+                            file: match &a.saved {
+                                Some(Ok(path)) => path
+                                    .strip_prefix(dir)
+                                    .ok()
+                                    .map(|p| p.display().to_string()),
+                                _ => None,
+                            },
+                        }),
+                    })
+                    .collect();
+                if !lines.is_empty() {
+                    if let Err(e) = recording::write_chat_log(dir, &lines) {
+                        log::error!("Failed to write chat log: {e}");
+                    }
+                }
+            }
         }
 
         self.session_state = None;
         self.manager_transport = None;
-        self.manager_jitter = None;
+        self.manager_mixer = None;
         self.session_start_time = None;
+        self.chat_input.clear();
+        self.chat_attachment = None;
+        self.chat_notice = None;
+        self.recording_start_ms = None;
 
         self.screen = AppScreen::PostCall;
     }
@@ -240,9 +355,8 @@ impl HyperZoomApp {
         match rx.try_recv() {
             Ok(ConnectResult::Ready {
                 state,
-                socket,
                 transport,
-                jitter,
+                mixer,
                 heartbeat_stop,
                 inbound_stop,
                 upnp,
@@ -265,17 +379,17 @@ impl HyperZoomApp {
                 let handle = self.runtime.handle().clone();
                 match AudioPipeline::new(
                     state.clone(),
-                    socket.clone(),
-                    handle.clone(),
-                    jitter.clone(),
+                    transport.clone(),
+                    mixer.clone(),
                     recording_path.clone(),
                 ) {
                     Ok(audio_pipeline) => {
+                        audio_pipeline.set_mic_enabled(self.mic_on);
                         // Create VideoPipeline
                         let video_pipeline = match VideoPipeline::new(
                             self.camera_on,
                             state.clone(),
-                            socket,
+                            transport.clone(),
                             handle,
                             video_rx,
                         ) {
@@ -291,11 +405,12 @@ impl HyperZoomApp {
 
                         self.status_message =
                             format!("Connected on port {local_port}");
+                        self.recording_start_ms = Some(state.lock().unwrap().elapsed_ms());
                         self.session_state = Some(state);
-                        self._audio_pipeline = Some(audio_pipeline);
+                        self.audio_pipeline = Some(audio_pipeline);
                         self.video_pipeline = video_pipeline;
                         self.manager_transport = Some(transport);
-                        self.manager_jitter = Some(jitter);
+                        self.manager_mixer = Some(mixer);
                         self.manager_heartbeat_stop = Some(heartbeat_stop);
                         self.manager_inbound_stop = Some(inbound_stop);
                         self.manager_upnp = upnp;
@@ -316,11 +431,16 @@ impl HyperZoomApp {
             Err(oneshot::error::TryRecvError::Empty) => {
                 // Not ready yet, put it back
                 self.connect_rx = Some(rx);
+                if self.join_waiting.as_ref().is_some_and(|w| *w.borrow()) {
+                    self.status_message = "Waiting for the host to let you in...".into();
+                }
+                return;
             }
             Err(oneshot::error::TryRecvError::Closed) => {
                 self.status_message = "Connection attempt failed unexpectedly".into();
             }
         }
+        self.join_waiting = None;
     }
 
     fn check_peer_disconnects(&mut self) {
@@ -330,8 +450,10 @@ impl HyperZoomApp {
                 if s.ended {
                     true
                 } else {
+                    // Dropped peers get a while to resume before we give up
                     !s.peers.is_empty()
                         && s.peers.values().all(|p| p.state == PeerState::Disconnected)
+                        && !s.awaiting_resume()
                 }
             };
 
@@ -342,7 +464,7 @@ impl HyperZoomApp {
                     self.end_reason = "All peers disconnected".into();
                     // Drop pipelines first
                     self.video_pipeline = None;
-                    self._audio_pipeline = None;
+                    self.audio_pipeline = None;
                     self.session_state = None;
                     self.screen = AppScreen::PostCall;
                 }
@@ -353,8 +475,9 @@ impl HyperZoomApp {
     /// Update video textures from the latest frames and render the InCall screen.
     fn show_incall(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
         self.check_peer_disconnects();
+        self.apply_host_requests();
 
-        let (my_name, my_id, peers_info, duration_s) = {
+        let (my_name, my_id, peers_info, duration_s, role, public_addr, hidden_video, resuming) = {
             if let Some(state) = &self.session_state {
                 let s = state.lock().unwrap();
                 let peers: Vec<(u8, String, PeerState)> = s
@@ -367,6 +490,10 @@ impl HyperZoomApp {
                     s.my_participant_id,
                     peers,
                     s.elapsed_ms() / 1000,
+                    s.role,
+                    s.public_addr,
+                    s.hidden_video.clone(),
+                    s.resuming(),
                 )
             } else {
                 return;
@@ -380,15 +507,35 @@ impl HyperZoomApp {
             ui.label(format!("Duration: {duration_s}s"));
             ui.separator();
 
-            // Camera toggle
+            if role == Role::Host {
+                self.show_share_address(ui, public_addr);
+                self.show_room_lock(ui);
+                ui.separator();
+            }
+            if resuming {
+                ui.label("Reconnecting to host...");
+                ui.separator();
+            }
+            if let Some(notice) = self.host_notice {
+                ui.label(notice);
+                ui.separator();
+            }
+
+            // Mic and camera toggles
+            let mic_label = if self.mic_on { "Mic ON" } else { "Mic OFF" };
+            if ui.button(mic_label).clicked() {
+                self.set_mic(!self.mic_on);
+                self.host_notice = None;
+            }
             let cam_label = if self.camera_on { "Camera ON" } else { "Camera OFF" };
             if ui.button(cam_label).clicked() {
-                self.camera_on = !self.camera_on;
-                if let Some(ref vp) = self.video_pipeline {
-                    vp.set_camera_enabled(self.camera_on);
-                }
+                self.set_camera(!self.camera_on);
+                self.host_notice = None;
             }
 
+            ui.toggle_value(&mut self.show_stats, "Stats");
+            ui.toggle_value(&mut self.show_chat, "Chat");
+
             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                 if ui.button("End Call").clicked() {
                     self.handle_end_call();
@@ -396,8 +543,25 @@ impl HyperZoomApp {
             });
         });
 
+        if role == Role::Host {
+            self.show_waiting_room(ui);
+        }
+        self.show_peer_audio_controls(ui, &peers_info);
+
+        if self.show_stats {
+            self.show_network_stats(ui);
+        }
+
         ui.separator();
 
+        self.save_chat_attachments();
+        if self.show_chat {
+            egui::SidePanel::right("chat")
+                .resizable(true)
+                .default_width(280.0)
+                .show_inside(ui, |ui| self.show_chat_panel(ui));
+        }
+
         // Update textures from latest video frames
         if let Some(ref mut vp) = self.video_pipeline {
             // Local preview
@@ -407,8 +571,12 @@ impl HyperZoomApp {
                 }
             }
 
-            // Remote peers
-            if let Ok(rf) = vp.remote_frames.lock() {
+            // Remote peers; drop the last frame of any whose video we've hidden
+            if let Ok(mut rf) = vp.remote_frames.lock() {
+                for pid in &hidden_video {
+                    rf.remove(pid);
+                    vp.display.remove_remote(*pid);
+                }
                 for (&pid, frame) in rf.iter() {
                     vp.display.update_remote(ctx, pid, frame);
                 }
@@ -421,8 +589,13 @@ impl HyperZoomApp {
                 .map(|(id, name, _)| (*id, name.clone()))
                 .collect();
 
-            // Render video grid
-            vp.display.show_grid(ui, &my_name, &grid_peers);
+            // Render video grid, then ask for the smallest simulcast layer
+            // that fills a tile at its current size
+            let cell_w = vp.display.show_grid(ui, &my_name, &grid_peers);
+            if let Some(state) = &self.session_state {
+                state.lock().unwrap().preferred_layer =
+                    ladder::layer_for_width(cell_w * ctx.pixels_per_point());
+            }
         } else {
             // No video pipeline — show text-only peer list
             ui.label("Peers:");
@@ -443,6 +616,439 @@ impl HyperZoomApp {
         // Repaint at ~30fps for smooth video
         ctx.request_repaint_after(std::time::Duration::from_millis(33));
     }
+
+    /// The IP:port guests should join on. STUN gives the public IP; when UPnP
+    /// has mapped a port, that mapping is what actually admits inbound traffic.
+    fn show_share_address(&self, ui: &mut egui::Ui, public_addr: Option<SocketAddr>) {
+        let share = public_addr.map(|addr| match &self.manager_upnp {
+            Some(mapping) => SocketAddr::new(addr.ip(), mapping.external_port()),
+            None => addr,
+        });
+        match share {
+            Some(addr) => {
+                ui.label(format!("Share: {addr}"));
+                if ui.small_button("Copy").clicked() {
+                    ui.ctx().copy_text(addr.to_string());
+                }
+            }
+            None => {
+                ui.label("Public address unknown");
+            }
+        }
+    }
+
+    fn set_mic(&mut self, on: bool) {
+        self.mic_on = on;
+        if let Some(ref ap) = self.audio_pipeline {
+            ap.set_mic_enabled(on);
+        }
+    }
+
+    fn set_camera(&mut self, on: bool) {
+        self.camera_on = on;
+        if let Some(ref vp) = self.video_pipeline {
+            vp.set_camera_enabled(on);
+        }
+    }
+
+    /// Guest: carry out what the host asked of us.
+    fn apply_host_requests(&mut self) {
+        let Some(state) = &self.session_state else {
+            return;
+        };
+        let requests = std::mem::take(&mut state.lock().unwrap().host_requests);
+        for action in requests {
+            match action {
+                ModerationAction::Mute => {
+                    self.set_mic(false);
+                    self.host_notice = Some("The host muted your microphone");
+                }
+                ModerationAction::CameraOff => {
+                    self.set_camera(false);
+                    self.host_notice = Some("The host turned your camera off");
+                }
+                ModerationAction::Kick => {
+                    self.handle_end_call();
+                    self.end_reason = "Removed from the call by the host".into();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Chat messages and attachments, a box to write in, and the file
+    /// dropped on the window, if any.
+    fn show_chat_panel(&mut self, ui: &mut egui::Ui) {
+        let Some(state) = self.session_state.clone() else {
+            return;
+        };
+        for file in ui.ctx().input(|i| i.raw.dropped_files.clone()) {
+            let Some(path) = file.path else { continue };
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "attachment".into());
+            match std::fs::read(&path) {
+                Ok(data) if data.len() > chat::MAX_ATTACHMENT_BYTES => {
+                    self.chat_notice = Some(format!(
+                        "{name} is too large to attach (limit {} KB)",
+                        chat::MAX_ATTACHMENT_BYTES / 1024
+                    ));
+                }
+                Ok(data) => {
+                    self.chat_attachment = Some((name, data));
+                    self.chat_notice = None;
+                }
+                Err(e) => self.chat_notice = Some(format!("Couldn't read {name}: {e}")),
+            }
+        }
+
+        ui.strong("Chat");
+        ui.separator();
+
+        // Leave room for the attachment line and the message box
+        let log_height = (ui.available_height() - 70.0).max(0.0);
+        egui::ScrollArea::vertical()
+            .max_height(log_height)
+            .auto_shrink([false, false])
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                let s = state.lock().unwrap();
+                for entry in &s.chat.entries {
+                    ui.horizontal(|ui| {
+                        ui.strong(&entry.author_name);
+                        ui.weak(entry.time.with_timezone(&chrono::Local).format("%H:%M").to_string());
+                    });
+                    if !entry.text.is_empty() {
+                        ui.label(&entry.text);
+                    }
+                    if let Some(attachment) = &entry.attachment {
+                        let status = match &attachment.saved {
+                            Some(Ok(path)) => format!("saved to {}", path.display()),
+                            Some(Err(e)) => e.clone(),
+                            None if attachment.is_complete() => "received".to_string(),
+                            None => format!("receiving, {:.0}%", attachment.progress() * 100.0),
+                        };
+                        ui.weak(format!(
+                            "File: {} ({} KB), {status}",
+                            attachment.name,
+                            attachment.size.div_ceil(1024)
+                        ));
+                    }
+                    ui.add_space(4.0);
+                }
+            });
+
+        ui.separator();
+        match &self.chat_attachment {
+            Some((name, data)) => {
+                let label = format!("Attached: {name} ({} KB)", data.len().div_ceil(1024));
+                ui.horizontal(|ui| {
+                    ui.label(label);
+                    if ui.small_button("Remove").clicked() {
+                        self.chat_attachment = None;
+                    }
+                });
+            }
+            None => {
+                ui.weak("Drop a file on the window to attach it");
+            }
+        }
+        if let Some(notice) = &self.chat_notice {
+            ui.colored_label(ui.visuals().warn_fg_color, notice);
+        }
+        ui.horizontal(|ui| {
+            let input = ui.add(
+                egui::TextEdit::singleline(&mut self.chat_input)
+                    .hint_text("Message")
+                    .desired_width(ui.available_width() - 50.0),
+            );
+            let entered = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if ui.button("Send").clicked() || entered {
+                self.send_chat();
+                input.request_focus();
+            }
+        });
+    }
+
+    fn send_chat(&mut self) {
+        let (Some(state), Some(transport)) = (&self.session_state, &self.manager_transport) else {
+            return;
+        };
+        let text = self.chat_input.trim();
+        if text.is_empty() && self.chat_attachment.is_none() {
+            return;
+        }
+        NetworkManager::send_chat(
+            state,
+            transport,
+            text,
+            self.chat_attachment.take(),
+        );
+        self.chat_input.clear();
+        self.chat_notice = None;
+    }
+
+    /// Save attachments to the session directory as they complete, ours
+    /// included, so the recording keeps everything shared in the call.
+    /// Without one they still go to disk, in a scratch directory.
+    fn save_chat_attachments(&self) {
+        let Some(state) = &self.session_state else {
+            return;
+        };
+        let dir = self.session_dir.clone().unwrap_or_else(recording::scratch_dir);
+        let complete: Vec<(usize, String, Vec<u8>)> = state
+            .lock()
+            .unwrap()
+            .chat
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| {
+                let a = entry.attachment.as_ref()?;
+                (a.saved.is_none() && a.is_complete()).then(|| (i, a.name.clone(), a.data().to_vec()))
+            })
+            .collect();
+        for (i, name, data) in complete {
+            let saved = recording::save_attachment(&dir, &name, &data);
+            if let Err(e) = &saved {
+                log::warn!("{e}");
+            }
+            if let Some(a) = state.lock().unwrap().chat.entries[i].attachment.as_mut() {
+                a.saved = Some(saved);
+            }
+        }
+    }
+
+    /// Host: refuse anyone new while the room is locked.
+    fn show_room_lock(&self, ui: &mut egui::Ui) {
+        let Some(state) = &self.session_state else {
+            return;
+        };
+        let mut locked = state.lock().unwrap().room_locked;
+        if ui
+            .toggle_value(&mut locked, "Lock room")
+            .on_hover_text("Turn away new guests; dropped ones can still reconnect")
+            .changed()
+        {
+            state.lock().unwrap().room_locked = locked;
+        }
+    }
+
+    /// Host: guests in the waiting room, to admit or deny.
+    fn show_waiting_room(&self, ui: &mut egui::Ui) {
+        let Some(state) = &self.session_state else {
+            return;
+        };
+        let waiting: Vec<(SocketAddr, String)> = state
+            .lock()
+            .unwrap()
+            .waiting
+            .iter()
+            .filter(|guest| guest.decision.is_none())
+            .map(|guest| (guest.addr, guest.name.clone()))
+            .collect();
+        for (addr, name) in waiting {
+            ui.horizontal(|ui| {
+                ui.label(format!("{name} ({addr}) is waiting to join"));
+                if ui.button("Admit").clicked() {
+                    state.lock().unwrap().decide(addr, true);
+                }
+                if ui.button("Deny").clicked() {
+                    state.lock().unwrap().decide(addr, false);
+                }
+            });
+        }
+    }
+
+    /// Per-peer playback volume and mute, applied in the audio mixer. SFU
+    /// guests can also stop receiving a peer's video.
+    fn show_peer_audio_controls(&self, ui: &mut egui::Ui, peers_info: &[(u8, String, PeerState)]) {
+        let (Some(mixer), Some(state)) = (&self.manager_mixer, &self.session_state) else {
+            return;
+        };
+        let (video_selectable, hidden_video, is_host) = {
+            let s = state.lock().unwrap();
+            (
+                s.role == Role::Guest && s.topology == Topology::Sfu,
+                s.hidden_video.clone(),
+                s.role == Role::Host,
+            )
+        };
+        let mut video_toggle = None;
+        let mut moderation = None;
+        let mut m = mixer.lock().unwrap();
+
+        ui.horizontal_wrapped(|ui| {
+            for (pid, name, state) in peers_info {
+                if *state == PeerState::Disconnected {
+                    continue;
+                }
+                let Some((mut gain, mut muted)) = m.controls(*pid) else {
+                    continue;
+                };
+                ui.label(name);
+                if ui.checkbox(&mut muted, "Mute").changed() {
+                    m.set_muted(*pid, muted);
+                }
+                if ui
+                    .add(egui::Slider::new(&mut gain, 0.0..=MAX_GAIN).show_value(false))
+                    .changed()
+                {
+                    m.set_gain(*pid, gain);
+                }
+                if video_selectable {
+                    let mut shown = !hidden_video.contains(pid);
+                    if ui.checkbox(&mut shown, "Video").changed() {
+                        video_toggle = Some((*pid, !shown));
+                    }
+                }
+                if is_host {
+                    ui.menu_button("Moderate", |ui| {
+                        let actions = [
+                            (ModerationAction::Mute, "Mute their mic"),
+                            (ModerationAction::CameraOff, "Turn their camera off"),
+                            (ModerationAction::Kick, "Remove from call"),
+                        ];
+                        for (action, label) in actions {
+                            if ui.button(label).clicked() {
+                                moderation = Some((*pid, action));
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                }
+                ui.separator();
+            }
+        });
+        drop(m);
+
+        if let Some((pid, hidden)) = video_toggle {
+            self.set_video_hidden(pid, hidden);
+        }
+        if let (Some((pid, action)), Some(transport)) = (moderation, &self.manager_transport) {
+            NetworkManager::moderate(state, transport, mixer, pid, action);
+        }
+    }
+
+    /// SFU guest: ask the host to stop (or resume) forwarding a peer's video.
+    fn set_video_hidden(&self, participant_id: u8, hidden: bool) {
+        let (Some(state), Some(transport)) = (&self.session_state, &self.manager_transport) else {
+            return;
+        };
+        {
+            let mut s = state.lock().unwrap();
+            if hidden {
+                s.hidden_video.insert(participant_id);
+            } else {
+                s.hidden_video.remove(&participant_id);
+            }
+        }
+        NetworkManager::send_video_subscription(state, transport);
+    }
+
+    /// Network stats overlay: per-peer RTT, loss, jitter and traffic, and on
+    /// the host what it relays or forwards between guests.
+    fn show_network_stats(&self, ui: &mut egui::Ui) {
+        let Some(state) = &self.session_state else {
+            return;
+        };
+        let s = state.lock().unwrap();
+        let mut peers: Vec<_> = s
+            .peers
+            .values()
+            .filter(|p| p.state != PeerState::Disconnected)
+            .collect();
+        peers.sort_by_key(|p| p.participant_id);
+
+        egui::Grid::new("network_stats").striped(true).show(ui, |ui| {
+            ui.label("Peer");
+            ui.label("Path");
+            ui.label("RTT");
+            ui.label("Audio loss / jitter");
+            ui.label("Video loss / jitter");
+            ui.label("Layer");
+            ui.label("In");
+            ui.label("Out");
+            ui.label("Clock offset");
+            ui.end_row();
+
+            for peer in peers {
+                let stats = &peer.stats;
+                ui.label(&peer.name);
+                ui.label(match peer.path {
+                    PeerPath::Direct => "direct",
+                    PeerPath::Relay => "via host",
+                });
+                ui.label(match stats.rtt_ms {
+                    Some(rtt) => format!("{rtt:.1} ms"),
+                    None => "--".into(),
+                });
+                ui.label(format!(
+                    "{:.1}% / {:.1} ms",
+                    stats.audio.loss_percent(),
+                    stats.audio.jitter_ms()
+                ));
+                ui.label(format!(
+                    "{:.1}% / {:.1} ms",
+                    stats.video.loss_percent(),
+                    stats.video.jitter_ms()
+                ));
+                ui.label(match peer.video_layer_in {
+                    Some(layer) => format!("{layer}"),
+                    None => "--".into(),
+                });
+                ui.label(format!("{} pkts, {} KB", stats.packets_in, stats.bytes_in / 1024));
+                ui.label(format!("{} pkts, {} KB", stats.packets_out, stats.bytes_out / 1024));
+                ui.label(match peer.clock {
+                    Some(clock) => format!(
+                        "{:+.1} ms ± {:.1}",
+                        clock.offset_ms(),
+                        clock.uncertainty_ms()
+                    ),
+                    None => "--".into(),
+                });
+                ui.end_row();
+            }
+        });
+
+        if s.role == Role::Host && s.topology == Topology::Sfu {
+            let mut peers: Vec<_> = s.peers.values().collect();
+            peers.sort_by_key(|p| p.participant_id);
+
+            ui.add_space(5.0);
+            ui.label("Forwarded by this host:");
+            egui::Grid::new("forward_stats").striped(true).show(ui, |ui| {
+                for peer in peers {
+                    ui.label(format!("→ {}", peer.name));
+                    ui.label(format!(
+                        "{} pkts, {} KB",
+                        peer.forwarded.packets,
+                        peer.forwarded.bytes / 1024
+                    ));
+                    ui.end_row();
+                }
+            });
+            return;
+        }
+
+        if s.relay_pairs.is_empty() {
+            return;
+        }
+        let name = |id: &u8| s.peers.get(id).map_or_else(|| format!("#{id}"), |p| p.name.clone());
+        let mut pairs: Vec<_> = s.relay_pairs.iter().collect();
+        pairs.sort_by_key(|(pair, _)| **pair);
+
+        ui.add_space(5.0);
+        ui.label("Relayed by this host:");
+        egui::Grid::new("relay_stats").striped(true).show(ui, |ui| {
+            for ((a, b), stats) in pairs {
+                ui.label(format!("{} ↔ {}", name(a), name(b)));
+                ui.label(format!("{} pkts, {} KB", stats.packets, stats.bytes / 1024));
+                ui.end_row();
+            }
+        });
+    }
 }
 
 impl eframe::App for HyperZoomApp {
@@ -468,16 +1074,31 @@ impl eframe::App for HyperZoomApp {
                         ui.text_edit_singleline(&mut self.port_input);
                     });
 
+                    ui.horizontal(|ui| {
+                        ui.label("Room password:");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.passphrase_input)
+                                .password(true)
+                                .hint_text("optional"),
+                        );
+                    });
+
                     ui.add_space(10.0);
 
                     let connecting = self.connect_rx.is_some();
 
-                    if ui
-                        .add_enabled(!connecting, egui::Button::new("Host"))
-                        .clicked()
-                    {
-                        self.handle_host();
-                    }
+                    ui.horizontal(|ui| {
+                        if ui
+                            .add_enabled(!connecting, egui::Button::new("Host"))
+                            .clicked()
+                        {
+                            self.handle_host();
+                        }
+                        ui.checkbox(&mut self.sfu_mode, "Forward all media through me (SFU)")
+                            .on_hover_text("Guests send one copy to you instead of one per peer; up to 16 people");
+                        ui.checkbox(&mut self.waiting_room, "Waiting room")
+                            .on_hover_text("Guests wait until you admit them");
+                    });
 
                     ui.add_space(5.0);
                     ui.separator();
@@ -4,8 +4,12 @@ pub const OPUS_FRAME_SAMPLES: usize = 240; // 5ms at 48kHz
 pub const MAX_ENCODED_SIZE: usize = 256;
 pub const SAMPLE_RATE: u32 = 48_000;
 
+/// Packet loss the encoder plans its in-band FEC for.
+const EXPECTED_LOSS_PERC: i32 = 10;
+
 /// Create an Opus encoder configured for low-delay voice.
 /// 48kHz, mono, LowDelay application, 32kbps CBR, FEC enabled.
+/// The expected loss rate must be nonzero or the encoder never emits FEC data.
 pub fn create_encoder() -> Result<Encoder, String> {
     let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Mono, Application::LowDelay)
         .map_err(|e| format!("Opus encoder creation failed: {e}"))?;
@@ -22,7 +26,11 @@ pub fn create_encoder() -> Result<Encoder, String> {
         .set_inband_fec(true)
         .map_err(|e| format!("Failed to enable FEC: {e}"))?;
 
-    log::info!("Opus encoder created: 48kHz mono, 32kbps CBR, FEC on");
+    encoder
+        .set_packet_loss_perc(EXPECTED_LOSS_PERC)
+        .map_err(|e| format!("Failed to set expected packet loss: {e}"))?;
+
+    log::info!("Opus encoder created: 48kHz mono, 32kbps CBR, FEC on ({EXPECTED_LOSS_PERC}% expected loss)");
     Ok(encoder)
 }
 
@@ -65,3 +73,15 @@ pub fn decode_frame(decoder: &mut Decoder, data: Option<&[u8]>) -> [f32; OPUS_FR
     }
     output
 }
+
+/// Recover a lost frame from the in-band FEC data carried by the packet after it.
+/// `next` is decoded again normally on the following pull. Packets without FEC
+/// data (e.g. CELT-only frames) fall back to PLC inside libopus.
+pub fn decode_fec_frame(decoder: &mut Decoder, next: &[u8]) -> [f32; OPUS_FRAME_SAMPLES] {
+    let mut output = [0.0f32; OPUS_FRAME_SAMPLES];
+    if let Err(e) = decoder.decode_float(next, &mut output, true) {
+        log::debug!("Opus FEC decode failed, using PLC: {e}");
+        return decode_frame(decoder, None);
+    }
+    output
+}
@@ -1,64 +1,91 @@
 use std::collections::BTreeMap;
 
-use super::codec::OPUS_FRAME_SAMPLES;
-
 /// Maximum number of frames the jitter buffer can hold before eviction.
 const MAX_BUFFER_FRAMES: usize = 60; // 300ms at 5ms/frame
 
-/// Adaptive jitter buffer for incoming audio frames.
+/// Consecutive misses with an empty buffer before playout stops concealing
+/// and goes back to buffering (100ms at 5ms/frame).
+const MAX_CONCEALED_FRAMES: usize = 20;
+
+/// What the playout side should do for the next 5ms frame.
+pub enum Playout {
+    /// The expected packet arrived — decode it normally.
+    Packet(Vec<u8>),
+    /// The expected packet is missing but the one after it is buffered.
+    /// Decode that packet with FEC to recover the lost frame from its
+    /// in-band redundancy; it stays in the buffer for the next pull.
+    Recover(Vec<u8>),
+    /// The expected packet is missing with nothing to recover it from — run PLC.
+    Lost,
+    /// Not playing (still buffering, or the stream went quiet) — output silence.
+    Silence,
+}
+
+/// Adaptive jitter buffer for incoming encoded audio packets.
 ///
-/// Frames are keyed by sequence number (u16). The buffer adapts its target
-/// depth between 1 and 6 frames (5–30ms) based on underrun/overrun patterns.
+/// Packets are keyed by sequence number (u16) and kept encoded until playout,
+/// so gaps can be concealed with Opus FEC/PLC by the decoder. The buffer adapts
+/// its target depth between 1 and 6 frames (5–30ms) based on underrun/overrun
+/// patterns.
 pub struct JitterBuffer {
-    frames: BTreeMap<u16, [f32; OPUS_FRAME_SAMPLES]>,
+    packets: BTreeMap<u16, Vec<u8>>,
     next_seq: Option<u16>,
     target_depth: usize,
     consecutive_ok: usize,
     consecutive_underruns: usize,
+    concealed: usize,
 }
 
 impl JitterBuffer {
     pub fn new() -> Self {
         Self {
-            frames: BTreeMap::new(),
+            packets: BTreeMap::new(),
             next_seq: None,
             target_depth: 2, // start at 10ms (2 frames × 5ms)
             consecutive_ok: 0,
             consecutive_underruns: 0,
+            concealed: 0,
         }
     }
 
-    /// Insert a decoded audio frame keyed by sequence number.
-    pub fn push(&mut self, seq: u16, samples: [f32; OPUS_FRAME_SAMPLES]) {
-        self.frames.insert(seq, samples);
+    /// Insert an encoded audio packet keyed by sequence number.
+    /// Packets that arrive after their playout slot has passed are dropped.
+    pub fn push(&mut self, seq: u16, packet: Vec<u8>) {
+        if let Some(next) = self.next_seq {
+            if (seq.wrapping_sub(next) as i16) < 0 {
+                return;
+            }
+        }
+
+        self.packets.insert(seq, packet);
 
         // Evict stale entries if buffer is too large
-        while self.frames.len() > MAX_BUFFER_FRAMES {
-            self.frames.pop_first();
+        while self.packets.len() > MAX_BUFFER_FRAMES {
+            self.packets.pop_first();
         }
     }
 
-    /// Pull the next frame in sequence order.
-    /// Returns the audio samples, or silence if the buffer is empty/starved.
-    pub fn pull(&mut self) -> [f32; OPUS_FRAME_SAMPLES] {
-        // Wait until we have at least target_depth frames before starting
+    /// Pull the next packet in sequence order, or tell the caller how to conceal it.
+    pub fn pull(&mut self) -> Playout {
+        // Wait until we have at least target_depth packets before starting
         if self.next_seq.is_none() {
-            if self.frames.len() >= self.target_depth {
-                // Start playback from the earliest frame
-                if let Some(&first_seq) = self.frames.keys().next() {
+            if self.packets.len() >= self.target_depth {
+                // Start playback from the earliest packet
+                if let Some(&first_seq) = self.packets.keys().next() {
                     self.next_seq = Some(first_seq);
                 }
             }
             if self.next_seq.is_none() {
-                return [0.0; OPUS_FRAME_SAMPLES];
+                return Playout::Silence;
             }
         }
 
         let seq = self.next_seq.unwrap();
 
-        if let Some(frame) = self.frames.remove(&seq) {
+        if let Some(packet) = self.packets.remove(&seq) {
             self.next_seq = Some(seq.wrapping_add(1));
             self.consecutive_underruns = 0;
+            self.concealed = 0;
             self.consecutive_ok += 1;
 
             // Shrink depth after 200 consecutive OK pulls (~1 second)
@@ -68,9 +95,9 @@ impl JitterBuffer {
                 log::debug!("Jitter buffer: shrink depth to {} frames", self.target_depth);
             }
 
-            frame
+            Playout::Packet(packet)
         } else {
-            // Underrun: frame not available yet
+            // Underrun: packet not available (late or lost)
             self.next_seq = Some(seq.wrapping_add(1));
             self.consecutive_ok = 0;
             self.consecutive_underruns += 1;
@@ -82,13 +109,26 @@ impl JitterBuffer {
                 log::debug!("Jitter buffer: grow depth to {} frames", self.target_depth);
             }
 
-            [0.0; OPUS_FRAME_SAMPLES]
+            if let Some(next) = self.packets.get(&seq.wrapping_add(1)) {
+                self.concealed = 0;
+                return Playout::Recover(next.clone());
+            }
+
+            // The stream has stopped: stop concealing and re-buffer
+            self.concealed += 1;
+            if self.packets.is_empty() && self.concealed > MAX_CONCEALED_FRAMES {
+                self.next_seq = None;
+                self.concealed = 0;
+                return Playout::Silence;
+            }
+
+            Playout::Lost
         }
     }
 
-    /// Current number of buffered frames.
+    /// Current number of buffered packets.
     pub fn len(&self) -> usize {
-        self.frames.len()
+        self.packets.len()
     }
 
     /// Current target depth in frames.
@@ -98,10 +138,61 @@ impl JitterBuffer {
 
     /// Reset the buffer state (e.g., on peer reconnect).
     pub fn reset(&mut self) {
-        self.frames.clear();
+        self.packets.clear();
         self.next_seq = None;
         self.target_depth = 2;
         self.consecutive_ok = 0;
         self.consecutive_underruns = 0;
+        self.concealed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq: u16) -> Vec<u8> {
+        vec![seq as u8]
+    }
+
+    #[test]
+    fn single_gap_is_recovered_from_the_next_packet() {
+        let mut buffer = JitterBuffer::new();
+        for seq in [0, 1, 3, 4] {
+            buffer.push(seq, packet(seq));
+        }
+        assert!(matches!(buffer.pull(), Playout::Packet(p) if p == packet(0)));
+        assert!(matches!(buffer.pull(), Playout::Packet(p) if p == packet(1)));
+        assert!(matches!(buffer.pull(), Playout::Recover(p) if p == packet(3)));
+        // The packet used for FEC still plays in its own slot
+        assert!(matches!(buffer.pull(), Playout::Packet(p) if p == packet(3)));
+        assert!(matches!(buffer.pull(), Playout::Packet(p) if p == packet(4)));
+
+        // Arriving after its slot has passed, the lost packet is dropped
+        buffer.push(2, packet(2));
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn a_stream_gone_quiet_is_concealed_then_silenced() {
+        let mut buffer = JitterBuffer::new();
+        for seq in [0, 1] {
+            buffer.push(seq, packet(seq));
+        }
+        assert!(matches!(buffer.pull(), Playout::Packet(_)));
+        assert!(matches!(buffer.pull(), Playout::Packet(_)));
+        for _ in 0..MAX_CONCEALED_FRAMES {
+            assert!(matches!(buffer.pull(), Playout::Lost));
+        }
+        assert!(matches!(buffer.pull(), Playout::Silence));
+
+        // Back to buffering: nothing plays until the target depth is reached
+        buffer.push(100, packet(100));
+        assert!(buffer.target_depth() > 1);
+        assert!(matches!(buffer.pull(), Playout::Silence));
+        for seq in 101..buffer.target_depth() as u16 + 100 {
+            buffer.push(seq, packet(seq));
+        }
+        assert!(matches!(buffer.pull(), Playout::Packet(p) if p == packet(100)));
     }
 }
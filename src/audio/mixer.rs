@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use opus::Decoder;
+
+use super::codec::{self, OPUS_FRAME_SAMPLES};
+use super::jitter::{JitterBuffer, Playout};
+
+/// Shared mixer: the network inbound task pushes packets, the refill thread mixes them.
+pub type SharedMixer = Arc<Mutex<AudioMixer>>;
+
+/// Level above which the limiter starts compressing the mixed signal.
+const LIMITER_THRESHOLD: f32 = 0.8;
+
+/// Maximum per-participant gain (+6 dB).
+pub const MAX_GAIN: f32 = 2.0;
+
+/// One remote participant's playback source.
+struct MixerSource {
+    /// Each speaker gets its own decoder so PLC/FEC state never mixes across streams.
+    decoder: Decoder,
+    jitter: JitterBuffer,
+    gain: f32,
+    muted: bool,
+}
+
+impl MixerSource {
+    /// Decode the next 5ms frame, concealing a missing packet with FEC or PLC.
+    fn next_frame(&mut self) -> [f32; OPUS_FRAME_SAMPLES] {
+        match self.jitter.pull() {
+            Playout::Packet(packet) => codec::decode_frame(&mut self.decoder, Some(&packet)),
+            Playout::Recover(next) => codec::decode_fec_frame(&mut self.decoder, &next),
+            Playout::Lost => codec::decode_frame(&mut self.decoder, None),
+            Playout::Silence => [0.0; OPUS_FRAME_SAMPLES],
+        }
+    }
+}
+
+/// Mixes one jitter buffer per remote participant into a single mono stream.
+///
+/// Each participant's sequence numbers and codec state are independent, so every
+/// source gets its own Opus decoder and `JitterBuffer`. Packets are decoded at
+/// playout time so a gap can be filled from the next packet's FEC data, or by
+/// PLC when nothing arrived. Sources are added when a peer joins and removed
+/// when it leaves.
+pub struct AudioMixer {
+    sources: HashMap<u8, MixerSource>,
+}
+
+impl AudioMixer {
+    pub fn new() -> Self {
+        Self {
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Start playing audio from a participant. Keeps gain/mute if the source
+    /// already exists, but starts from a fresh decoder and empty buffer.
+    pub fn add_source(&mut self, participant_id: u8) {
+        let decoder = match codec::create_decoder() {
+            Ok(d) => d,
+            Err(e) => {
+                log::error!("No audio for participant {participant_id}: {e}");
+                return;
+            }
+        };
+        match self.sources.get_mut(&participant_id) {
+            Some(source) => {
+                source.decoder = decoder;
+                source.jitter.reset();
+            }
+            None => {
+                self.sources.insert(
+                    participant_id,
+                    MixerSource {
+                        decoder,
+                        jitter: JitterBuffer::new(),
+                        gain: 1.0,
+                        muted: false,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Stop playing audio from a participant, dropping its decoder and buffered frames.
+    pub fn remove_source(&mut self, participant_id: u8) {
+        self.sources.remove(&participant_id);
+    }
+
+    /// Buffer an encoded Opus packet for the participant; it is decoded at playout.
+    /// Packets from unknown participants are dropped.
+    pub fn push_packet(&mut self, participant_id: u8, seq: u16, payload: Vec<u8>) {
+        if let Some(source) = self.sources.get_mut(&participant_id) {
+            source.jitter.push(seq, payload);
+        }
+    }
+
+    /// Set a participant's playback gain (clamped to 0.0..=MAX_GAIN).
+    pub fn set_gain(&mut self, participant_id: u8, gain: f32) {
+        if let Some(source) = self.sources.get_mut(&participant_id) {
+            source.gain = gain.clamp(0.0, MAX_GAIN);
+        }
+    }
+
+    pub fn set_muted(&mut self, participant_id: u8, muted: bool) {
+        if let Some(source) = self.sources.get_mut(&participant_id) {
+            source.muted = muted;
+        }
+    }
+
+    /// Current (gain, muted) for a participant, if it has a source.
+    pub fn controls(&self, participant_id: u8) -> Option<(f32, bool)> {
+        self.sources
+            .get(&participant_id)
+            .map(|s| (s.gain, s.muted))
+    }
+
+    /// Decode one frame from every source and mix them.
+    /// Muted sources are still decoded so their buffers and decoder state keep
+    /// pace with playback.
+    pub fn mix(&mut self) -> [f32; OPUS_FRAME_SAMPLES] {
+        let mut mixed = [0.0f32; OPUS_FRAME_SAMPLES];
+        for source in self.sources.values_mut() {
+            let frame = source.next_frame();
+            if source.muted || source.gain == 0.0 {
+                continue;
+            }
+            for (out, sample) in mixed.iter_mut().zip(frame.iter()) {
+                *out += sample * source.gain;
+            }
+        }
+        for sample in mixed.iter_mut() {
+            *sample = soft_clip(*sample);
+        }
+        mixed
+    }
+}
+
+/// Soft limiter: linear below the threshold, smoothly approaches ±1.0 above it,
+/// so several loud speakers summing together never hard-clip.
+#[inline]
+fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= LIMITER_THRESHOLD {
+        return sample;
+    }
+    let headroom = 1.0 - LIMITER_THRESHOLD;
+    let compressed = LIMITER_THRESHOLD + headroom * ((magnitude - LIMITER_THRESHOLD) / headroom).tanh();
+    compressed.copysign(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soft_clip_is_identity_below_the_threshold_and_bounded_above() {
+        for i in -80..=80 {
+            let sample = i as f32 / 100.0;
+            assert_eq!(soft_clip(sample), sample);
+        }
+        let mut previous = LIMITER_THRESHOLD;
+        for i in 81..=1000 {
+            let sample = i as f32 / 100.0;
+            let clipped = soft_clip(sample);
+            assert!(clipped >= previous && clipped <= 1.0, "{sample} -> {clipped}");
+            assert_eq!(soft_clip(-sample), -clipped);
+            previous = clipped;
+        }
+        assert!(soft_clip(f32::MAX) <= 1.0);
+    }
+}
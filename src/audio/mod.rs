@@ -2,40 +2,42 @@ pub mod device;
 pub mod rt_priority;
 pub mod codec;
 pub mod jitter;
+pub mod mixer;
 pub mod aac;
 pub mod fmp4;
 pub mod recorder;
 
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
 use cpal::traits::{DeviceTrait, StreamTrait};
 use cpal::{SampleRate, Stream, StreamConfig};
 use ringbuf::traits::{Consumer, Producer, Split};
 use ringbuf::HeapRb;
-use tokio::net::UdpSocket;
-use tokio::runtime::Handle;
 
-use crate::net::protocol::{Packet, PacketHeader, PacketType};
+use crate::net::protocol::{Packet, PacketHeader, PacketType, HEADER_SIZE};
 use crate::net::session::SharedSessionState;
+use crate::net::transport::Transport;
 use codec::OPUS_FRAME_SAMPLES;
-use jitter::JitterBuffer;
+use mixer::SharedMixer;
 use recorder::AudioRecorder;
 
 const SAMPLE_RATE: u32 = 48_000;
 
 /// The networked audio pipeline with optional local recording.
 ///
-/// Input: cpal callback → Ring A → encode thread → Opus encode → UDP send
+/// Input: cpal callback → Ring A → encode thread → Opus encode → send queue
 ///                       → Ring B → recorder thread → AAC encode → fMP4 file  [when recording]
-/// Output: JitterBuffer → refill thread → playback ring → cpal callback
+/// Output: per-peer JitterBuffers → AudioMixer (Opus decode/FEC/PLC) → refill thread → playback ring → cpal callback
 pub struct AudioPipeline {
     _input_stream: Stream,
     _output_stream: Stream,
     encode_thread: Option<JoinHandle<()>>,
     encode_stop: Arc<AtomicBool>,
+    /// Whether our microphone is sent; muting leaves the local recording running.
+    mic_enabled: Arc<AtomicBool>,
     refill_thread: Option<JoinHandle<()>>,
     refill_stop: Arc<AtomicBool>,
     recorder: Option<AudioRecorder>,
@@ -44,9 +46,8 @@ pub struct AudioPipeline {
 impl AudioPipeline {
     pub fn new(
         state: SharedSessionState,
-        socket: Arc<UdpSocket>,
-        handle: Handle,
-        jitter: Arc<Mutex<JitterBuffer>>,
+        transport: Arc<dyn Transport>,
+        mixer: SharedMixer,
         recording_path: Option<PathBuf>,
     ) -> Result<Self, String> {
         device::log_all_devices();
@@ -165,9 +166,10 @@ impl AudioPipeline {
         // --- Encode thread: raw OS thread with RT priority ---
         let encode_stop = Arc::new(AtomicBool::new(false));
         let stop_flag = encode_stop.clone();
+        let mic_enabled = Arc::new(AtomicBool::new(true));
+        let mic_flag = mic_enabled.clone();
         let encode_state = state.clone();
-        let encode_socket = socket.clone();
-        let encode_handle = handle.clone();
+        let encode_transport = transport.clone();
 
         let encode_thread = thread::Builder::new()
             .name("audio-encode".into())
@@ -199,6 +201,9 @@ impl AudioPipeline {
                                 let mut frame = [0.0f32; OPUS_FRAME_SAMPLES];
                                 frame.copy_from_slice(&accumulator);
                                 accumulator.clear();
+                                if !mic_flag.load(Ordering::Relaxed) {
+                                    continue;
+                                }
 
                                 let encoded = match codec::encode_frame(&mut encoder, &frame) {
                                     Ok(data) => data,
@@ -214,6 +219,7 @@ impl AudioPipeline {
                                     let seq = s.next_seq();
                                     let ts = s.elapsed_ms();
                                     let addrs = s.connected_peer_addrs();
+                                    s.record_sent(&addrs, HEADER_SIZE + encoded.len());
                                     (s.my_participant_id, seq, ts, addrs)
                                 };
 
@@ -227,14 +233,11 @@ impl AudioPipeline {
                                     );
                                     let packet_bytes = Packet::new(header, encoded).to_bytes();
 
+                                    // Queued ahead of any video; never waits on the network
                                     for addr in &peer_addrs {
-                                        let bytes = packet_bytes.clone();
-                                        let sock = encode_socket.clone();
-                                        let target = *addr;
-                                        // Use block_on for quick UDP send
-                                        let _ = encode_handle.block_on(async move {
-                                            sock.send_to(&bytes, target).await
-                                        });
+                                        if let Err(e) = encode_transport.send_audio(packet_bytes.clone(), *addr) {
+                                            log::debug!("Audio send to {addr} failed: {e}");
+                                        }
                                     }
                                 }
                             }
@@ -249,7 +252,7 @@ impl AudioPipeline {
             })
             .map_err(|e| format!("Failed to spawn encode thread: {e}"))?;
 
-        // --- Refill thread: jitter buffer → playback ring ---
+        // --- Refill thread: mixer → playback ring ---
         let refill_stop = Arc::new(AtomicBool::new(false));
         let refill_stop_flag = refill_stop.clone();
 
@@ -258,11 +261,11 @@ impl AudioPipeline {
             .spawn(move || {
                 log::info!("Refill thread started");
                 while !refill_stop_flag.load(Ordering::Relaxed) {
-                    // Check if playback ring needs more data
-                    // Pull a frame from jitter buffer and push samples to playback ring
+                    // Mix one frame from every participant's jitter buffer
+                    // and push the result to the playback ring
                     let frame = {
-                        match jitter.lock() {
-                            Ok(mut jb) => jb.pull(),
+                        match mixer.lock() {
+                            Ok(mut m) => m.mix(),
                             Err(_) => [0.0; OPUS_FRAME_SAMPLES],
                         }
                     };
@@ -293,11 +296,16 @@ impl AudioPipeline {
             _output_stream: output_stream,
             encode_thread: Some(encode_thread),
             encode_stop,
+            mic_enabled,
             refill_thread: Some(refill_thread),
             refill_stop,
             recorder,
         })
     }
+
+    pub fn set_mic_enabled(&self, enabled: bool) {
+        self.mic_enabled.store(enabled, Ordering::Relaxed);
+    }
 }
 
 impl Drop for AudioPipeline {
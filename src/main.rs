@@ -6,10 +6,21 @@ mod video;
 
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    log::info!("HyperZoom starting");
 
     let runtime = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
 
+    // `hyperzoom simulate ...`: a whole call over a simulated network, headless
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|a| a == "simulate") {
+        if let Err(e) = runtime.block_on(net::simulate::run(&args[1..])) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    log::info!("HyperZoom starting");
+
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(
         "HyperZoom",
@@ -0,0 +1,254 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+
+use super::control::{Chat, ChatChunk};
+use super::crypto;
+
+/// Longest message text, and attachment file name, in bytes. Both go in one
+/// Chat, which has to fit a single packet.
+pub const MAX_TEXT_BYTES: usize = 1000;
+const MAX_NAME_BYTES: usize = 100;
+/// Attachments are for links' worth of notes, not media: kept small, as they
+/// share the call's bandwidth.
+pub const MAX_ATTACHMENT_BYTES: usize = 512 * 1024;
+/// Attachment bytes per ChatChunk.
+pub const CHUNK_BYTES: usize = 1024;
+
+/// A file sent along with a chat message.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub size: usize,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    /// Where the app saved it once complete, or why it couldn't.
+    pub saved: Option<Result<PathBuf, String>>,
+}
+
+impl Attachment {
+    fn expecting(name: String, size: usize) -> Self {
+        Self {
+            name,
+            size,
+            data: vec![0; size],
+            received: vec![false; size.div_ceil(CHUNK_BYTES)],
+            saved: None,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|&r| r)
+    }
+
+    /// Fraction of the chunks received so far.
+    pub fn progress(&self) -> f32 {
+        if self.received.is_empty() {
+            return 1.0;
+        }
+        self.received.iter().filter(|&&r| r).count() as f32 / self.received.len() as f32
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatEntry {
+    pub author: u8,
+    pub author_name: String,
+    /// When it was sent or arrived here.
+    pub time: DateTime<Utc>,
+    pub text: String,
+    pub attachment: Option<Attachment>,
+    message_id: u32,
+}
+
+/// The call's chat so far, ours and everyone else's, in the order we saw it.
+#[derive(Debug, Clone, Default)]
+pub struct ChatLog {
+    pub entries: Vec<ChatEntry>,
+}
+
+impl ChatLog {
+    /// Add a message of our own. Returns the Chat and ChatChunks to send.
+    pub fn post(
+        &mut self,
+        author: u8,
+        author_name: String,
+        text: &str,
+        attachment: Option<(String, Vec<u8>)>,
+    ) -> (Chat, Vec<ChatChunk>) {
+        let message_id = crypto::random_u32();
+        let attachment = attachment.map(|(name, data)| Attachment {
+            name: truncate(&name, MAX_NAME_BYTES).to_string(),
+            size: data.len(),
+            received: vec![true; data.len().div_ceil(CHUNK_BYTES)],
+            data,
+            saved: None,
+        });
+        let chat = Chat {
+            author,
+            message_id,
+            text: truncate(text, MAX_TEXT_BYTES).to_string(),
+            attachment: attachment.as_ref().map(|a| (a.name.clone(), a.size as u32)),
+        };
+        let chunks = attachment
+            .as_ref()
+            .map(|a| {
+                a.data
+                    .chunks(CHUNK_BYTES)
+                    .enumerate()
+                    .map(|(index, piece)| ChatChunk {
+                        author,
+                        message_id,
+                        index: index as u16,
+                        data: piece.to_vec(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.entries.push(ChatEntry {
+            author,
+            author_name,
+            time: Utc::now(),
+            text: chat.text.clone(),
+            attachment,
+            message_id,
+        });
+        (chat, chunks)
+    }
+
+    /// Add someone else's message. False if we already have it, or its
+    /// attachment is over the size limit.
+    pub fn receive(&mut self, author_name: String, chat: &Chat) -> bool {
+        if self.find(chat.author, chat.message_id).is_some() {
+            return false;
+        }
+        if chat.attachment.as_ref().is_some_and(|(_, size)| *size as usize > MAX_ATTACHMENT_BYTES) {
+            return false;
+        }
+        self.entries.push(ChatEntry {
+            author: chat.author,
+            author_name,
+            time: Utc::now(),
+            text: chat.text.clone(),
+            attachment: chat
+                .attachment
+                .as_ref()
+                .map(|(name, size)| Attachment::expecting(name.clone(), *size as usize)),
+            message_id: chat.message_id,
+        });
+        true
+    }
+
+    /// Store a piece of an attachment. False if it belongs to no message we
+    /// have, doesn't fit it, or was stored already.
+    pub fn receive_chunk(&mut self, chunk: &ChatChunk) -> bool {
+        let Some(entry) = self.find(chunk.author, chunk.message_id) else { return false };
+        let Some(attachment) = entry.attachment.as_mut() else { return false };
+        let index = chunk.index as usize;
+        let start = index * CHUNK_BYTES;
+        let end = (start + CHUNK_BYTES).min(attachment.size);
+        if attachment.received.get(index) != Some(&false) || chunk.data.len() != end - start {
+            return false;
+        }
+        attachment.data[start..end].copy_from_slice(&chunk.data);
+        attachment.received[index] = true;
+        true
+    }
+
+    fn find(&mut self, author: u8, message_id: u32) -> Option<&mut ChatEntry> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.author == author && e.message_id == message_id)
+    }
+}
+
+/// The longest prefix of `s` within `max` bytes that ends on a character boundary.
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment_bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 253) as u8).collect()
+    }
+
+    #[test]
+    fn attachment_reassembles_through_the_wire_format() {
+        let data = attachment_bytes(2 * CHUNK_BYTES + 10);
+        let mut sender = ChatLog::default();
+        let (chat, chunks) = sender.post(1, "Ann".into(), "notes", Some(("notes.txt".into(), data.clone())));
+        assert_eq!(chunks.len(), 3);
+        assert!(sender.entries[0].attachment.as_ref().unwrap().is_complete());
+
+        let mut receiver = ChatLog::default();
+        assert!(receiver.receive("Ann".into(), &Chat::from_bytes(&chat.to_bytes()).unwrap()));
+        for chunk in chunks.iter().rev() {
+            let attachment = receiver.entries[0].attachment.as_ref().unwrap();
+            assert!(!attachment.is_complete());
+            assert!(receiver.receive_chunk(&ChatChunk::from_bytes(&chunk.to_bytes()).unwrap()));
+        }
+        let entry = &receiver.entries[0];
+        assert_eq!(entry.text, "notes");
+        let attachment = entry.attachment.as_ref().unwrap();
+        assert_eq!((attachment.name.as_str(), attachment.size), ("notes.txt", data.len()));
+        assert!(attachment.is_complete());
+        assert_eq!(attachment.progress(), 1.0);
+        assert_eq!(attachment.data(), &data[..]);
+    }
+
+    #[test]
+    fn duplicates_and_strays_are_refused() {
+        let mut sender = ChatLog::default();
+        let (chat, chunks) = sender.post(1, "Ann".into(), "", Some(("a.bin".into(), attachment_bytes(CHUNK_BYTES + 1))));
+        let mut receiver = ChatLog::default();
+        // A chunk ahead of its message has nowhere to go
+        assert!(!receiver.receive_chunk(&chunks[0]));
+        assert!(receiver.receive("Ann".into(), &chat));
+        assert!(!receiver.receive("Ann".into(), &chat));
+        assert!(receiver.receive_chunk(&chunks[0]));
+        assert!(!receiver.receive_chunk(&chunks[0]));
+        assert_eq!(receiver.entries[0].attachment.as_ref().unwrap().progress(), 0.5);
+
+        let mut wrong_size = chunks[1].clone();
+        wrong_size.data.push(0);
+        assert!(!receiver.receive_chunk(&wrong_size));
+        let mut out_of_range = chunks[1].clone();
+        out_of_range.index = 2;
+        assert!(!receiver.receive_chunk(&out_of_range));
+        let mut other_message = chunks[1].clone();
+        other_message.message_id = other_message.message_id.wrapping_add(1);
+        assert!(!receiver.receive_chunk(&other_message));
+        assert_eq!(receiver.entries.len(), 1);
+    }
+
+    #[test]
+    fn oversized_attachments_are_refused() {
+        let chat = Chat {
+            author: 2,
+            message_id: 7,
+            text: String::new(),
+            attachment: Some(("big.bin".into(), MAX_ATTACHMENT_BYTES as u32 + 1)),
+        };
+        assert!(!ChatLog::default().receive("Bo".into(), &chat));
+    }
+
+    #[test]
+    fn text_is_truncated_on_a_character_boundary() {
+        let text = "é".repeat(MAX_TEXT_BYTES);
+        let (chat, chunks) = ChatLog::default().post(1, "Ann".into(), &text, None);
+        assert!(chunks.is_empty());
+        assert_eq!(chat.text.len(), MAX_TEXT_BYTES);
+        assert_eq!(truncate("aé", 2), "a");
+    }
+}
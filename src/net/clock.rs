@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+/// Number of timestamp round trips in a sync exchange.
+pub const SYNC_ROUNDS: usize = 8;
+
+/// Spacing between sync requests, so one delayed reply doesn't skew the next.
+pub const SYNC_SPACING: Duration = Duration::from_millis(50);
+
+/// A peer's session clock relative to ours, estimated NTP-style.
+///
+/// Every participant's session clock counts from its own `start_time`, so the
+/// offset is typically seconds, not milliseconds. Of all exchanges the one with
+/// the smallest round-trip delay wins (NTP's minimum-delay filter): queueing
+/// only ever adds delay, and the less delay the less room for asymmetry.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockOffset {
+    /// Peer clock minus our clock, in microseconds.
+    pub offset_us: i64,
+    /// Half the round-trip delay of the sample used; the true offset lies within ± this.
+    pub uncertainty_us: u64,
+    /// Exchanges completed so far.
+    pub samples: u32,
+}
+
+impl ClockOffset {
+    /// Estimate from one exchange: `t1` our send, `t2` peer receive,
+    /// `t3` peer send, `t4` our receive (µs on the respective session clocks).
+    pub fn from_exchange(t1: u64, t2: u64, t3: u64, t4: u64) -> Self {
+        let (t1, t2, t3, t4) = (t1 as i64, t2 as i64, t3 as i64, t4 as i64);
+        let offset_us = ((t2 - t1) + (t3 - t4)) / 2;
+        let delay_us = ((t4 - t1) - (t3 - t2)).max(0) as u64;
+        Self {
+            offset_us,
+            uncertainty_us: delay_us / 2,
+            samples: 1,
+        }
+    }
+
+    /// Fold in a new sample, keeping whichever has the smaller delay.
+    pub fn merge(&mut self, sample: ClockOffset) {
+        let samples = self.samples + sample.samples;
+        if sample.uncertainty_us < self.uncertainty_us {
+            *self = sample;
+        }
+        self.samples = samples;
+    }
+
+    pub fn offset_ms(&self) -> f64 {
+        self.offset_us as f64 / 1000.0
+    }
+
+    pub fn uncertainty_ms(&self) -> f64 {
+        self.uncertainty_us as f64 / 1000.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An exchange with a peer whose clock reads `offset` µs ahead of ours,
+    /// `out` and `back` µs on the wire each way and `hold` µs spent at the peer.
+    fn exchange(t1: u64, offset: u64, out: u64, hold: u64, back: u64) -> ClockOffset {
+        let t2 = t1 + out + offset;
+        let t3 = t2 + hold;
+        let t4 = t3 - offset + back;
+        ClockOffset::from_exchange(t1, t2, t3, t4)
+    }
+
+    #[test]
+    fn symmetric_exchange_gives_the_exact_offset() {
+        let sample = exchange(1_000_000, 5_000_000, 10_000, 3_000, 10_000);
+        assert_eq!(sample.offset_us, 5_000_000);
+        assert_eq!(sample.uncertainty_us, 10_000, "time held at the peer is not delay");
+        assert_eq!(sample.offset_ms(), 5000.0);
+    }
+
+    #[test]
+    fn the_least_delayed_sample_wins_in_any_order() {
+        // The reply queued 50 ms behind other traffic: skewed by half of that
+        let queued = exchange(1_000_000, 5_000_000, 10_000, 1_000, 60_000);
+        assert_eq!(queued.offset_us, 5_000_000 - 25_000);
+        assert_eq!(queued.uncertainty_us, 35_000);
+        let clean = exchange(2_000_000, 5_000_000, 12_000, 1_000, 10_000);
+
+        let mut estimate = queued;
+        estimate.merge(clean);
+        estimate.merge(queued);
+        assert_eq!(estimate.offset_us, 5_000_000 + 1_000);
+        assert_eq!(estimate.uncertainty_us, 11_000);
+        assert_eq!(estimate.samples, 3);
+        // The true offset lies within the reported uncertainty
+        assert!(estimate.offset_us.abs_diff(5_000_000) <= estimate.uncertainty_us);
+    }
+}
@@ -0,0 +1,134 @@
+/// Video bitrate bounds in kbps, for all simulcast layers together. The spec's
+/// live video budget is 300–500 kbps per stream; as the budget shrinks the
+/// encoder drops its higher layers, then stops (see `video::ladder`).
+pub const MIN_VIDEO_KBPS: u32 = 40;
+pub const MAX_VIDEO_KBPS: u32 = 800;
+const START_VIDEO_KBPS: u32 = 400;
+
+/// Reported loss above this means the link is congested: back off hard.
+const LOSS_HIGH_PERCENT: f32 = 10.0;
+/// Reported loss below this (and no queueing delay) means there is room to grow.
+const LOSS_LOW_PERCENT: f32 = 2.0;
+
+/// RTT this far above the best seen means queues are building.
+const QUEUEING_DELAY_MS: f32 = 50.0;
+/// Multiplicative back-off when queueing delay is detected.
+const DELAY_BACKOFF: f32 = 0.85;
+/// How far the baseline RTT may creep up per update, so a route change
+/// doesn't look like permanent queueing.
+const MIN_RTT_DRIFT_MS: f32 = 0.5;
+
+/// Growth per update while the link is clean: 5% plus a little, so the
+/// estimate can climb back from the floor.
+const INCREASE_FACTOR: f32 = 1.05;
+const INCREASE_STEP_KBPS: f32 = 5.0;
+
+/// Loss- and delay-based send rate estimator for live video.
+///
+/// Fed once per heartbeat with the worst receiver feedback across peers. Each
+/// receiver picks a simulcast layer and steps it down on its own loss first,
+/// so the estimate mostly tracks our uplink rather than one weak downlink.
+/// Audio is not governed by this estimate — it always goes out at full rate.
+#[derive(Debug, Clone)]
+pub struct BandwidthEstimator {
+    target_kbps: f32,
+    min_rtt_ms: Option<f32>,
+}
+
+impl BandwidthEstimator {
+    pub fn new() -> Self {
+        Self {
+            target_kbps: START_VIDEO_KBPS as f32,
+            min_rtt_ms: None,
+        }
+    }
+
+    /// Update from one feedback interval: loss in percent and smoothed RTT, if known.
+    pub fn update(&mut self, loss_percent: f32, rtt_ms: Option<f32>) {
+        let queueing = match rtt_ms {
+            Some(rtt) => {
+                let baseline = match self.min_rtt_ms {
+                    Some(min) => (min + MIN_RTT_DRIFT_MS).min(rtt),
+                    None => rtt,
+                };
+                self.min_rtt_ms = Some(baseline);
+                rtt - baseline > QUEUEING_DELAY_MS
+            }
+            None => false,
+        };
+
+        let previous = self.target_kbps;
+        if loss_percent > LOSS_HIGH_PERCENT {
+            self.target_kbps *= 1.0 - 0.5 * (loss_percent / 100.0).min(1.0);
+        } else if queueing {
+            self.target_kbps *= DELAY_BACKOFF;
+        } else if loss_percent < LOSS_LOW_PERCENT {
+            self.target_kbps = self.target_kbps * INCREASE_FACTOR + INCREASE_STEP_KBPS;
+        }
+        self.target_kbps = self
+            .target_kbps
+            .clamp(MIN_VIDEO_KBPS as f32, MAX_VIDEO_KBPS as f32);
+
+        if (self.target_kbps - previous).abs() >= 1.0 {
+            log::debug!(
+                "Video rate {:.0} -> {:.0} kbps (loss {loss_percent:.1}%, rtt {rtt_ms:?}, queueing {queueing})",
+                previous,
+                self.target_kbps
+            );
+        }
+    }
+
+    /// Current video send rate target in kbps.
+    pub fn target_kbps(&self) -> u32 {
+        self.target_kbps as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_link_grows_to_the_cap() {
+        let mut bwe = BandwidthEstimator::new();
+        bwe.update(0.0, Some(40.0));
+        assert!((424..=425).contains(&bwe.target_kbps())); // 400 * 1.05 + 5
+        for _ in 0..50 {
+            bwe.update(0.0, Some(40.0));
+        }
+        assert_eq!(bwe.target_kbps(), MAX_VIDEO_KBPS);
+    }
+
+    #[test]
+    fn heavy_loss_backs_off_in_proportion_and_moderate_loss_holds() {
+        let mut bwe = BandwidthEstimator::new();
+        bwe.update(20.0, None);
+        assert_eq!(bwe.target_kbps(), 360); // 400 * (1 - 0.5 * 0.2)
+        bwe.update(5.0, None);
+        assert_eq!(bwe.target_kbps(), 360);
+        for _ in 0..50 {
+            bwe.update(100.0, None);
+        }
+        assert_eq!(bwe.target_kbps(), MIN_VIDEO_KBPS);
+    }
+
+    #[test]
+    fn rtt_above_the_best_seen_backs_off_until_the_baseline_catches_up() {
+        let mut bwe = BandwidthEstimator::new();
+        bwe.update(5.0, Some(50.0));
+        bwe.update(5.0, Some(120.0));
+        assert_eq!(bwe.target_kbps(), 340); // 400 * 0.85
+        bwe.update(5.0, Some(80.0));
+        assert_eq!(bwe.target_kbps(), 340, "30 ms over the baseline is not queueing");
+
+        // A route that stays slower stops counting as queueing once the
+        // baseline has crept up to within QUEUEING_DELAY_MS of it
+        for _ in 0..40 {
+            bwe.update(5.0, Some(120.0));
+        }
+        let settled = bwe.target_kbps();
+        bwe.update(5.0, Some(120.0));
+        assert_eq!(bwe.target_kbps(), settled);
+        assert!(settled < 340);
+    }
+}
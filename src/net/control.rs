@@ -1,4 +1,8 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::crypto::{
+    PassphraseProof, ResumeToken, PASSPHRASE_PROOF_SIZE, PUBLIC_KEY_SIZE, RESUME_TOKEN_SIZE, WRAPPED_KEY_SIZE,
+};
 
 /// Control message sub-types carried inside a Control packet's payload.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -9,6 +13,28 @@ pub enum ControlType {
     PeerJoined = 0x03,
     Heartbeat = 0x04,
     Nack = 0x05,
+    KeyframeRequest = 0x06,
+    Ping = 0x07,
+    Pong = 0x08,
+    SyncRequest = 0x09,
+    SyncReply = 0x0A,
+    ReceiverReport = 0x0B,
+    PunchCheck = 0x0C,
+    PunchAck = 0x0D,
+    RelayRequest = 0x0E,
+    RelayStart = 0x0F,
+    RelayForward = 0x10,
+    VideoSubscription = 0x11,
+    LayerSelection = 0x12,
+    Reject = 0x13,
+    Waiting = 0x14,
+    Moderate = 0x15,
+    HostHandover = 0x16,
+    Reliable = 0x17,
+    Ack = 0x18,
+    Chat = 0x19,
+    ChatChunk = 0x1A,
+    ResumeTokens = 0x1B,
 }
 
 impl ControlType {
@@ -19,117 +45,310 @@ impl ControlType {
             0x03 => Some(Self::PeerJoined),
             0x04 => Some(Self::Heartbeat),
             0x05 => Some(Self::Nack),
+            0x06 => Some(Self::KeyframeRequest),
+            0x07 => Some(Self::Ping),
+            0x08 => Some(Self::Pong),
+            0x09 => Some(Self::SyncRequest),
+            0x0A => Some(Self::SyncReply),
+            0x0B => Some(Self::ReceiverReport),
+            0x0C => Some(Self::PunchCheck),
+            0x0D => Some(Self::PunchAck),
+            0x0E => Some(Self::RelayRequest),
+            0x0F => Some(Self::RelayStart),
+            0x10 => Some(Self::RelayForward),
+            0x11 => Some(Self::VideoSubscription),
+            0x12 => Some(Self::LayerSelection),
+            0x13 => Some(Self::Reject),
+            0x14 => Some(Self::Waiting),
+            0x15 => Some(Self::Moderate),
+            0x16 => Some(Self::HostHandover),
+            0x17 => Some(Self::Reliable),
+            0x18 => Some(Self::Ack),
+            0x19 => Some(Self::Chat),
+            0x1A => Some(Self::ChatChunk),
+            0x1B => Some(Self::ResumeTokens),
             _ => None,
         }
     }
 }
 
-// --- Wire helpers for SocketAddrV4 (4 + 2 = 6 bytes) ---
+// --- Wire helpers for SocketAddr ---
+// Wire: [family: u8 = 4 | 6] [ip: 4 or 16 bytes] [port: u16 BE]  (7 or 19 bytes)
+// The family byte versions the encoding; unknown families fail to parse.
+
+const ADDR_FAMILY_V4: u8 = 4;
+const ADDR_FAMILY_V6: u8 = 6;
 
-fn write_addr(addr: &SocketAddrV4, buf: &mut Vec<u8>) {
-    buf.extend_from_slice(&addr.ip().octets());
+fn write_addr(addr: &SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ADDR_FAMILY_V4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ADDR_FAMILY_V6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
     buf.extend_from_slice(&addr.port().to_be_bytes());
 }
 
-fn read_addr(buf: &[u8], offset: usize) -> Option<(SocketAddrV4, usize)> {
-    if buf.len() < offset + 6 {
-        return None;
+fn read_addr(buf: &[u8], offset: usize) -> Option<(SocketAddr, usize)> {
+    let family = *buf.get(offset)?;
+    let ip_start = offset + 1;
+    let (ip, port_start) = match family {
+        ADDR_FAMILY_V4 => {
+            let octets: [u8; 4] = buf.get(ip_start..ip_start + 4)?.try_into().ok()?;
+            (IpAddr::V4(Ipv4Addr::from(octets)), ip_start + 4)
+        }
+        ADDR_FAMILY_V6 => {
+            let octets: [u8; 16] = buf.get(ip_start..ip_start + 16)?.try_into().ok()?;
+            (IpAddr::V6(Ipv6Addr::from(octets)), ip_start + 16)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(buf.get(port_start..port_start + 2)?.try_into().ok()?);
+    Some((SocketAddr::new(ip, port), port_start + 2))
+}
+
+// --- Capabilities: inside Hello and Welcome ---
+// Wire: [opus_count: u8] [opus_count × frame_samples: u16 BE] [video_codecs: u8]
+//       [max_width: u16 BE] [max_height: u16 BE]
+
+/// Bit of `Capabilities::video_codecs`. 0x02 is VP9, which no build encodes yet.
+pub const VIDEO_CODEC_VP8: u8 = 0x01;
+
+/// The media formats a participant can send and receive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Opus frame sizes it can send and play out, in samples at 48 kHz.
+    pub opus_frame_sizes: Vec<u16>,
+    /// `VIDEO_CODEC_*` bits it can encode and decode.
+    pub video_codecs: u8,
+    /// Largest picture it wants to receive.
+    pub max_width: u16,
+    pub max_height: u16,
+}
+
+impl Capabilities {
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.push(self.opus_frame_sizes.len() as u8);
+        for samples in &self.opus_frame_sizes {
+            buf.extend_from_slice(&samples.to_be_bytes());
+        }
+        buf.push(self.video_codecs);
+        buf.extend_from_slice(&self.max_width.to_be_bytes());
+        buf.extend_from_slice(&self.max_height.to_be_bytes());
+    }
+
+    /// Read capabilities starting at `offset`. Returns them and the offset past them.
+    fn read(buf: &[u8], offset: usize) -> Option<(Self, usize)> {
+        let opus_count = *buf.get(offset)? as usize;
+        let sizes_end = offset + 1 + 2 * opus_count;
+        let opus_frame_sizes = buf
+            .get(offset + 1..sizes_end)?
+            .chunks_exact(2)
+            .map(|s| u16::from_be_bytes([s[0], s[1]]))
+            .collect();
+        let rest = buf.get(sizes_end..sizes_end + 5)?;
+        Some((
+            Self {
+                opus_frame_sizes,
+                video_codecs: rest[0],
+                max_width: u16::from_be_bytes([rest[1], rest[2]]),
+                max_height: u16::from_be_bytes([rest[3], rest[4]]),
+            },
+            sizes_end + 5,
+        ))
     }
-    let ip = Ipv4Addr::new(buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]);
-    let port = u16::from_be_bytes([buf[offset + 4], buf[offset + 5]]);
-    Some((SocketAddrV4::new(ip, port), offset + 6))
 }
 
 // --- Hello: guest → host ---
-// Wire: [ctrl_type=0x01] [name_len: u8] [name: utf8...]
+// Wire: [ctrl_type=0x01] [protocol_version: u8] [name_len: u8] [name: utf8...]
+//       [capabilities] [public_key: 32 bytes, optional]
+//       [passphrase_proof: 32 bytes, with a public_key] [resume_token: 16 bytes, optional]
+// The first two bytes never move, so any build can read the version and
+// answer a mismatch with a Reject.
 
 #[derive(Debug, Clone)]
 pub struct Hello {
+    pub protocol_version: u8,
     pub name: String,
+    pub capabilities: Capabilities,
+    /// X25519 key for the session key exchange. Only sent to the host; the
+    /// Hellos guests send each other are already encrypted.
+    pub public_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+    /// Sent with the public key: see `crypto::passphrase_proof`. The host
+    /// turns the guest away if it doesn't check out.
+    pub passphrase_proof: Option<PassphraseProof>,
+    /// A dropped guest resuming: the token the host last gave it. Sent in the
+    /// clear, so it only picks the participant ID — the key exchange still
+    /// needs the passphrase.
+    pub resume_token: Option<ResumeToken>,
 }
 
 impl Hello {
     pub fn to_bytes(&self) -> Vec<u8> {
         let name_bytes = self.name.as_bytes();
-        let mut buf = Vec::with_capacity(2 + name_bytes.len());
+        let mut buf =
+            Vec::with_capacity(16 + name_bytes.len() + PUBLIC_KEY_SIZE + PASSPHRASE_PROOF_SIZE + RESUME_TOKEN_SIZE);
         buf.push(ControlType::Hello as u8);
+        buf.push(self.protocol_version);
         buf.push(name_bytes.len() as u8);
         buf.extend_from_slice(name_bytes);
+        self.capabilities.write(&mut buf);
+        if let (Some(key), Some(proof)) = (&self.public_key, &self.passphrase_proof) {
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(proof);
+            if let Some(token) = &self.resume_token {
+                buf.extend_from_slice(token);
+            }
+        }
         buf
     }
 
+    /// The sender's protocol version, readable whatever the rest looks like.
+    pub fn version_of(buf: &[u8]) -> Option<u8> {
+        buf.get(1).copied()
+    }
+
     pub fn from_bytes(buf: &[u8]) -> Option<Self> {
-        if buf.len() < 2 {
-            return None;
-        }
-        let name_len = buf[1] as usize;
-        if buf.len() < 2 + name_len {
-            return None;
-        }
-        let name = String::from_utf8(buf[2..2 + name_len].to_vec()).ok()?;
-        Some(Self { name })
+        let protocol_version = Self::version_of(buf)?;
+        let name_len = *buf.get(2)? as usize;
+        let name = String::from_utf8(buf.get(3..3 + name_len)?.to_vec()).ok()?;
+        let (capabilities, key_start) = Capabilities::read(buf, 3 + name_len)?;
+        let proof_start = key_start + PUBLIC_KEY_SIZE;
+        let token_start = proof_start + PASSPHRASE_PROOF_SIZE;
+        let public_key = buf
+            .get(key_start..proof_start)
+            .and_then(|k| k.try_into().ok());
+        let passphrase_proof = buf
+            .get(proof_start..token_start)
+            .and_then(|p| p.try_into().ok());
+        let resume_token = buf
+            .get(token_start..token_start + RESUME_TOKEN_SIZE)
+            .and_then(|t| t.try_into().ok());
+        Some(Self {
+            protocol_version,
+            name,
+            capabilities,
+            public_key,
+            passphrase_proof,
+            resume_token,
+        })
     }
 }
 
 // --- Welcome: host → guest ---
 // Wire: [ctrl_type=0x02] [session_id: u32 BE] [assigned_participant_id: u8]
+//       [host_public_key: 32 bytes] [wrapped_session_key: 48 bytes] [sfu: u8]
+//       [capabilities] [peer_count: u8] [peer_count × (participant_id: u8, addr: 7 or 19 bytes, name_len: u8, name: utf8...)]
 
 #[derive(Debug, Clone)]
 pub struct Welcome {
     pub session_id: u32,
     pub assigned_participant_id: u8,
+    /// Host's half of the key exchange started by the guest's Hello.
+    pub host_public_key: [u8; PUBLIC_KEY_SIZE],
+    /// Session key sealed for this guest (see `crypto::KeyExchange`).
+    pub wrapped_session_key: [u8; WRAPPED_KEY_SIZE],
+    /// The host forwards all media between guests (`Topology::Sfu`).
+    pub sfu: bool,
+    /// What the host sends and receives.
+    pub capabilities: Capabilities,
+    /// Other guests already in the session, so the new guest can reach them directly.
+    pub peers: Vec<PeerJoined>,
 }
 
 impl Welcome {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(6);
+        let mut buf = Vec::with_capacity(
+            8 + PUBLIC_KEY_SIZE + WRAPPED_KEY_SIZE + self.peers.len() * 29,
+        );
         buf.push(ControlType::Welcome as u8);
         buf.extend_from_slice(&self.session_id.to_be_bytes());
         buf.push(self.assigned_participant_id);
+        buf.extend_from_slice(&self.host_public_key);
+        buf.extend_from_slice(&self.wrapped_session_key);
+        buf.push(self.sfu as u8);
+        self.capabilities.write(&mut buf);
+        buf.push(self.peers.len() as u8);
+        for peer in &self.peers {
+            peer.write_entry(&mut buf);
+        }
         buf
     }
 
     pub fn from_bytes(buf: &[u8]) -> Option<Self> {
-        if buf.len() < 6 {
+        const KEYS_END: usize = 6 + PUBLIC_KEY_SIZE + WRAPPED_KEY_SIZE;
+        if buf.len() < KEYS_END + 1 {
             return None;
         }
         let session_id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
         let assigned_participant_id = buf[5];
+        let host_public_key = buf[6..6 + PUBLIC_KEY_SIZE].try_into().ok()?;
+        let wrapped_session_key = buf[6 + PUBLIC_KEY_SIZE..KEYS_END].try_into().ok()?;
+        let sfu = buf[KEYS_END] != 0;
+        let (capabilities, count_at) = Capabilities::read(buf, KEYS_END + 1)?;
+        let peer_count = *buf.get(count_at)? as usize;
+        let mut peers = Vec::with_capacity(peer_count);
+        let mut offset = count_at + 1;
+        for _ in 0..peer_count {
+            let (peer, next) = PeerJoined::read_entry(buf, offset)?;
+            peers.push(peer);
+            offset = next;
+        }
         Some(Self {
             session_id,
             assigned_participant_id,
+            host_public_key,
+            wrapped_session_key,
+            sfu,
+            capabilities,
+            peers,
         })
     }
 }
 
 // --- PeerJoined: host → existing peers ---
-// Wire: [ctrl_type=0x03] [participant_id: u8] [addr: 6 bytes] [name_len: u8] [name: utf8...]
+// Wire: [ctrl_type=0x03] [participant_id: u8] [addr: 7 or 19 bytes] [name_len: u8] [name: utf8...]
 
 #[derive(Debug, Clone)]
 pub struct PeerJoined {
     pub participant_id: u8,
-    pub addr: SocketAddrV4,
+    pub addr: SocketAddr,
     pub name: String,
 }
 
 impl PeerJoined {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let name_bytes = self.name.as_bytes();
-        let mut buf = Vec::with_capacity(8 + name_bytes.len());
+        let mut buf = Vec::with_capacity(22 + self.name.len());
         buf.push(ControlType::PeerJoined as u8);
+        self.write_entry(&mut buf);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        Self::read_entry(buf, 1).map(|(peer, _)| peer)
+    }
+
+    /// Write the peer fields without the control type byte.
+    /// Shared with the roster carried in Welcome.
+    fn write_entry(&self, buf: &mut Vec<u8>) {
+        let name_bytes = self.name.as_bytes();
         buf.push(self.participant_id);
-        write_addr(&self.addr, &mut buf);
+        write_addr(&self.addr, buf);
         buf.push(name_bytes.len() as u8);
         buf.extend_from_slice(name_bytes);
-        buf
     }
 
-    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
-        if buf.len() < 3 {
+    /// Read peer fields starting at `offset`. Returns the entry and the offset past it.
+    fn read_entry(buf: &[u8], offset: usize) -> Option<(Self, usize)> {
+        if buf.len() < offset + 1 {
             return None;
         }
-        let participant_id = buf[1];
-        let (addr, offset) = read_addr(buf, 2)?;
+        let participant_id = buf[offset];
+        let (addr, offset) = read_addr(buf, offset + 1)?;
         if buf.len() < offset + 1 {
             return None;
         }
@@ -138,11 +357,14 @@ impl PeerJoined {
             return None;
         }
         let name = String::from_utf8(buf[offset + 1..offset + 1 + name_len].to_vec()).ok()?;
-        Some(Self {
-            participant_id,
-            addr,
-            name,
-        })
+        Some((
+            Self {
+                participant_id,
+                addr,
+                name,
+            },
+            offset + 1 + name_len,
+        ))
     }
 }
 
@@ -166,30 +388,721 @@ impl Heartbeat {
 }
 
 // --- Nack: request retransmission ---
-// Wire: [ctrl_type=0x05] [seq_start: u16 BE] [count: u8]
+// Wire: [ctrl_type=0x05] [layer: u8] [seq_start: u16 BE] [count: u8]
+// Each simulcast layer has its own sequence numbers.
 
 #[derive(Debug, Clone)]
 pub struct Nack {
+    pub layer: u8,
     pub seq_start: u16,
     pub count: u8,
 }
 
 impl Nack {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(4);
+        let mut buf = Vec::with_capacity(5);
         buf.push(ControlType::Nack as u8);
+        buf.push(self.layer);
         buf.extend_from_slice(&self.seq_start.to_be_bytes());
         buf.push(self.count);
         buf
     }
 
     pub fn from_bytes(buf: &[u8]) -> Option<Self> {
-        if buf.len() < 4 {
+        if buf.len() < 5 {
+            return None;
+        }
+        let layer = buf[1];
+        let seq_start = u16::from_be_bytes([buf[2], buf[3]]);
+        let count = buf[4];
+        Some(Self {
+            layer,
+            seq_start,
+            count,
+        })
+    }
+}
+
+// --- KeyframeRequest: receiver lost decoder sync (PLI) ---
+// Wire: [ctrl_type=0x06]
+
+#[derive(Debug, Clone)]
+pub struct KeyframeRequest;
+
+impl KeyframeRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![ControlType::KeyframeRequest as u8]
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.is_empty() {
+            return None;
+        }
+        Some(Self)
+    }
+}
+
+// --- Ping / Pong: round-trip time measurement ---
+// Wire: [ctrl_type=0x07] [timestamp_us: u32 BE]
+// Pong echoes the Ping's timestamp back: [ctrl_type=0x08] [timestamp_us: u32 BE]
+
+#[derive(Debug, Clone)]
+pub struct Ping {
+    /// Sender's clock (µs since its session start), echoed back in the Pong.
+    pub timestamp_us: u32,
+}
+
+impl Ping {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5);
+        buf.push(ControlType::Ping as u8);
+        buf.extend_from_slice(&self.timestamp_us.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 5 {
+            return None;
+        }
+        let timestamp_us = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        Some(Self { timestamp_us })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pong {
+    pub timestamp_us: u32,
+}
+
+impl Pong {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5);
+        buf.push(ControlType::Pong as u8);
+        buf.extend_from_slice(&self.timestamp_us.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 5 {
+            return None;
+        }
+        let timestamp_us = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        Some(Self { timestamp_us })
+    }
+}
+
+// --- SyncRequest / SyncReply: NTP-style clock offset estimation ---
+// Times are µs on the sender's / replier's own session clock.
+// Wire: [ctrl_type=0x09] [t1: u64 BE]
+// Wire: [ctrl_type=0x0A] [t1: u64 BE] [t2: u64 BE] [t3: u64 BE]
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    let bytes = buf.get(offset..offset + 8)?;
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncRequest {
+    /// Requester's send time.
+    pub t1: u64,
+}
+
+impl SyncRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(9);
+        buf.push(ControlType::SyncRequest as u8);
+        buf.extend_from_slice(&self.t1.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        Some(Self {
+            t1: read_u64(buf, 1)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncReply {
+    /// Echo of the request's send time.
+    pub t1: u64,
+    /// Replier's receive time.
+    pub t2: u64,
+    /// Replier's send time.
+    pub t3: u64,
+}
+
+impl SyncReply {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(25);
+        buf.push(ControlType::SyncReply as u8);
+        buf.extend_from_slice(&self.t1.to_be_bytes());
+        buf.extend_from_slice(&self.t2.to_be_bytes());
+        buf.extend_from_slice(&self.t3.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        Some(Self {
+            t1: read_u64(buf, 1)?,
+            t2: read_u64(buf, 9)?,
+            t3: read_u64(buf, 17)?,
+        })
+    }
+}
+
+// --- ReceiverReport: how the sender's streams reached us over the last interval ---
+// Wire: [ctrl_type=0x0B] [audio_loss_pct: u8] [video_loss_pct: u8] [video_jitter_ms: u16 BE]
+
+#[derive(Debug, Clone)]
+pub struct ReceiverReport {
+    pub audio_loss_percent: u8,
+    pub video_loss_percent: u8,
+    pub video_jitter_ms: u16,
+}
+
+impl ReceiverReport {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5);
+        buf.push(ControlType::ReceiverReport as u8);
+        buf.push(self.audio_loss_percent);
+        buf.push(self.video_loss_percent);
+        buf.extend_from_slice(&self.video_jitter_ms.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 5 {
+            return None;
+        }
+        Some(Self {
+            audio_loss_percent: buf[1].min(100),
+            video_loss_percent: buf[2].min(100),
+            video_jitter_ms: u16::from_be_bytes([buf[3], buf[4]]),
+        })
+    }
+}
+
+// --- PunchCheck / PunchAck: guest-to-guest connectivity check during hole punching ---
+// Wire: [ctrl_type=0x0C] [token: u32 BE]
+// PunchAck echoes the check's token back: [ctrl_type=0x0D] [token: u32 BE]
+
+#[derive(Debug, Clone)]
+pub struct PunchCheck {
+    /// Random per-attempt value the sender matches against the ack.
+    pub token: u32,
+}
+
+impl PunchCheck {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5);
+        buf.push(ControlType::PunchCheck as u8);
+        buf.extend_from_slice(&self.token.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 5 {
+            return None;
+        }
+        let token = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        Some(Self { token })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PunchAck {
+    pub token: u32,
+}
+
+impl PunchAck {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5);
+        buf.push(ControlType::PunchAck as u8);
+        buf.extend_from_slice(&self.token.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 5 {
             return None;
         }
-        let seq_start = u16::from_be_bytes([buf[1], buf[2]]);
-        let count = buf[3];
-        Some(Self { seq_start, count })
+        let token = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        Some(Self { token })
+    }
+}
+
+// --- RelayRequest: guest → host, hole punching to a peer failed ---
+// Wire: [ctrl_type=0x0E] [peer_id: u8]
+
+#[derive(Debug, Clone)]
+pub struct RelayRequest {
+    pub peer_id: u8,
+}
+
+impl RelayRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![ControlType::RelayRequest as u8, self.peer_id]
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 2 {
+            return None;
+        }
+        Some(Self { peer_id: buf[1] })
+    }
+}
+
+// --- RelayStart: host → both guests of a pair, traffic between them now goes through the host ---
+// Wire: [ctrl_type=0x0F] [peer_id: u8]
+
+#[derive(Debug, Clone)]
+pub struct RelayStart {
+    pub peer_id: u8,
+}
+
+impl RelayStart {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![ControlType::RelayStart as u8, self.peer_id]
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 2 {
+            return None;
+        }
+        Some(Self { peer_id: buf[1] })
+    }
+}
+
+// --- RelayForward: guest → host, a packet for a relayed peer ---
+// Wire: [ctrl_type=0x10] [target: u8] [packet: sealed packet bytes...]
+// The host sends `packet` on to `target` unchanged, so it keeps the sender's header and seal.
+
+#[derive(Debug, Clone)]
+pub struct RelayForward {
+    pub target: u8,
+    pub packet: Vec<u8>,
+}
+
+impl RelayForward {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(2 + self.packet.len());
+        buf.push(ControlType::RelayForward as u8);
+        buf.push(self.target);
+        buf.extend_from_slice(&self.packet);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 2 {
+            return None;
+        }
+        Some(Self {
+            target: buf[1],
+            packet: buf[2..].to_vec(),
+        })
+    }
+}
+
+// --- VideoSubscription: guest → host in SFU mode, whose video not to forward ---
+// Wire: [ctrl_type=0x11] [count: u8] [count × participant_id: u8]
+// Sent when the selection changes and repeated with every heartbeat.
+
+#[derive(Debug, Clone)]
+pub struct VideoSubscription {
+    pub hidden: Vec<u8>,
+}
+
+impl VideoSubscription {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(2 + self.hidden.len());
+        buf.push(ControlType::VideoSubscription as u8);
+        buf.push(self.hidden.len() as u8);
+        buf.extend_from_slice(&self.hidden);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let count = *buf.get(1)? as usize;
+        let hidden = buf.get(2..2 + count)?.to_vec();
+        Some(Self { hidden })
+    }
+}
+
+// --- LayerSelection: receiver → the peers it gets video from directly ---
+// Wire: [ctrl_type=0x12] [count: u8] [count × (participant_id: u8, layer: u8)]
+// The simulcast layer wanted from each sender. A sender applies its own entry;
+// the host also applies the others when it forwards. Sent every heartbeat.
+
+#[derive(Debug, Clone)]
+pub struct LayerSelection {
+    pub layers: Vec<(u8, u8)>,
+}
+
+impl LayerSelection {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(2 + 2 * self.layers.len());
+        buf.push(ControlType::LayerSelection as u8);
+        buf.push(self.layers.len() as u8);
+        for &(participant_id, layer) in &self.layers {
+            buf.push(participant_id);
+            buf.push(layer);
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let count = *buf.get(1)? as usize;
+        let entries = buf.get(2..2 + 2 * count)?;
+        let layers = entries.chunks_exact(2).map(|e| (e[0], e[1])).collect();
+        Some(Self { layers })
+    }
+}
+
+// --- Reject: host → guest, in place of a Welcome ---
+// Wire: [ctrl_type=0x13] [reason: u8] [protocol_version: u8]
+// Sent in the clear. Like Hello's prefix, this layout must never change.
+
+/// Why the host turned a guest away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RejectReason {
+    VersionMismatch = 0x01,
+    AudioUnsupported = 0x02,
+    VideoUnsupported = 0x03,
+    SessionFull = 0x04,
+    WrongPassword = 0x05,
+    Denied = 0x06,
+    RoomLocked = 0x07,
+}
+
+impl RejectReason {
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0x01 => Some(Self::VersionMismatch),
+            0x02 => Some(Self::AudioUnsupported),
+            0x03 => Some(Self::VideoUnsupported),
+            0x04 => Some(Self::SessionFull),
+            0x05 => Some(Self::WrongPassword),
+            0x06 => Some(Self::Denied),
+            0x07 => Some(Self::RoomLocked),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reject {
+    /// A `RejectReason`, kept raw so codes from newer hosts still reach the user.
+    pub reason: u8,
+    /// The host's protocol version.
+    pub protocol_version: u8,
+}
+
+impl Reject {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![ControlType::Reject as u8, self.reason, self.protocol_version]
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        Some(Self {
+            reason: *buf.get(1)?,
+            protocol_version: *buf.get(2)?,
+        })
+    }
+
+    /// Why the join failed, in words for the status line.
+    pub fn message(&self, our_version: u8) -> String {
+        match RejectReason::from_u8(self.reason) {
+            Some(RejectReason::VersionMismatch) => format!(
+                "Host runs protocol v{}, this build v{} — both need the same HyperZoom version",
+                self.protocol_version, our_version
+            ),
+            Some(RejectReason::AudioUnsupported) => {
+                "Host rejected us: no Opus frame size in common".to_string()
+            }
+            Some(RejectReason::VideoUnsupported) => {
+                "Host rejected us: no video codec in common".to_string()
+            }
+            Some(RejectReason::SessionFull) => "Session is full".to_string(),
+            Some(RejectReason::WrongPassword) => "Wrong room password".to_string(),
+            Some(RejectReason::Denied) => "The host didn't let you in".to_string(),
+            Some(RejectReason::RoomLocked) => "The host has locked the room".to_string(),
+            None => format!("Host rejected us (error code {})", self.reason),
+        }
+    }
+}
+
+// --- Waiting: host → guest, in answer to a Hello ---
+// Wire: [ctrl_type=0x14]
+// The host holds new guests in a waiting room until it admits or denies them.
+// Sent in the clear; the guest keeps repeating its Hello until it hears back.
+
+#[derive(Debug, Clone)]
+pub struct Waiting;
+
+impl Waiting {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![ControlType::Waiting as u8]
+    }
+}
+
+// --- Moderate: host → guest ---
+// Wire: [ctrl_type=0x15] [action: u8]
+
+/// What the host asks of a guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ModerationAction {
+    /// Leave the call.
+    Kick = 0x01,
+    /// Stop sending our microphone.
+    Mute = 0x02,
+    /// Turn our camera off.
+    CameraOff = 0x03,
+}
+
+impl ModerationAction {
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0x01 => Some(Self::Kick),
+            0x02 => Some(Self::Mute),
+            0x03 => Some(Self::CameraOff),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Moderate {
+    pub action: ModerationAction,
+}
+
+impl Moderate {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![ControlType::Moderate as u8, self.action as u8]
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let action = ModerationAction::from_u8(*buf.get(1)?)?;
+        Some(Self { action })
+    }
+}
+
+// --- HostHandover: old host → guests, then new host → guests ---
+// Wire: [ctrl_type=0x16] [new_host_id: u8]
+// A leaving host names its successor before its BYE. The new host repeats it
+// for a while, also after taking over from a host that vanished.
+
+#[derive(Debug, Clone)]
+pub struct HostHandover {
+    pub new_host_id: u8,
+}
+
+impl HostHandover {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![ControlType::HostHandover as u8, self.new_host_id]
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        Some(Self {
+            new_host_id: *buf.get(1)?,
+        })
+    }
+}
+
+// --- Reliable: any direction, a control message that must arrive ---
+// Wire: [ctrl_type=0x17] [stream: u16 BE] [sequence: u16 BE] [message: control payload...]
+// Sequence numbers count from 0 within a stream, one stream per sender and
+// receiver address; a sender that gives up on a message starts a new one.
+// See `reliable` for acks, retransmission and ordering.
+
+#[derive(Debug, Clone)]
+pub struct Reliable {
+    pub stream: u16,
+    pub sequence: u16,
+    pub message: Vec<u8>,
+}
+
+impl Reliable {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5 + self.message.len());
+        buf.push(ControlType::Reliable as u8);
+        buf.extend_from_slice(&self.stream.to_be_bytes());
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf.extend_from_slice(&self.message);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 6 {
+            return None;
+        }
+        Some(Self {
+            stream: u16::from_be_bytes([buf[1], buf[2]]),
+            sequence: u16::from_be_bytes([buf[3], buf[4]]),
+            message: buf[5..].to_vec(),
+        })
+    }
+}
+
+// --- Ack: receiver → sender of a Reliable ---
+// Wire: [ctrl_type=0x18] [stream: u16 BE] [sequence: u16 BE]
+// Sent for every copy received, duplicates included, in case an Ack was lost.
+
+#[derive(Debug, Clone)]
+pub struct Ack {
+    pub stream: u16,
+    pub sequence: u16,
+}
+
+impl Ack {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(5);
+        buf.push(ControlType::Ack as u8);
+        buf.extend_from_slice(&self.stream.to_be_bytes());
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 5 {
+            return None;
+        }
+        Some(Self {
+            stream: u16::from_be_bytes([buf[1], buf[2]]),
+            sequence: u16::from_be_bytes([buf[3], buf[4]]),
+        })
+    }
+}
+
+// --- Chat: any direction, a text message and maybe a file ---
+// Wire: [ctrl_type=0x19] [author: u8] [message_id: u32 BE] [text_len: u16 BE] [text: UTF-8]
+//       [has_attachment: u8] [if 1: size: u32 BE] [name_len: u8] [name: UTF-8]
+// Sent reliably. The host passes it on to guests the author can't reach
+// directly, hence the author field. The file follows in ChatChunks.
+
+#[derive(Debug, Clone)]
+pub struct Chat {
+    pub author: u8,
+    pub message_id: u32,
+    pub text: String,
+    /// File name and size in bytes.
+    pub attachment: Option<(String, u32)>,
+}
+
+impl Chat {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let text_bytes = self.text.as_bytes();
+        let mut buf = Vec::with_capacity(16 + text_bytes.len());
+        buf.push(ControlType::Chat as u8);
+        buf.push(self.author);
+        buf.extend_from_slice(&self.message_id.to_be_bytes());
+        buf.extend_from_slice(&(text_bytes.len() as u16).to_be_bytes());
+        buf.extend_from_slice(text_bytes);
+        match &self.attachment {
+            Some((name, size)) => {
+                buf.push(1);
+                buf.extend_from_slice(&size.to_be_bytes());
+                buf.push(name.len() as u8);
+                buf.extend_from_slice(name.as_bytes());
+            }
+            None => buf.push(0),
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let author = *buf.get(1)?;
+        let message_id = u32::from_be_bytes(buf.get(2..6)?.try_into().ok()?);
+        let text_len = u16::from_be_bytes(buf.get(6..8)?.try_into().ok()?) as usize;
+        let text = String::from_utf8(buf.get(8..8 + text_len)?.to_vec()).ok()?;
+        let offset = 8 + text_len;
+        let attachment = match *buf.get(offset)? {
+            0 => None,
+            _ => {
+                let size = u32::from_be_bytes(buf.get(offset + 1..offset + 5)?.try_into().ok()?);
+                let name_len = *buf.get(offset + 5)? as usize;
+                let name_start = offset + 6;
+                let name = String::from_utf8(buf.get(name_start..name_start + name_len)?.to_vec()).ok()?;
+                Some((name, size))
+            }
+        };
+        Some(Self {
+            author,
+            message_id,
+            text,
+            attachment,
+        })
+    }
+}
+
+// --- ChatChunk: any direction, one piece of a Chat's attachment ---
+// Wire: [ctrl_type=0x1A] [author: u8] [message_id: u32 BE] [index: u16 BE] [data...]
+// Sent reliably after its Chat; every chunk but the last is `chat::CHUNK_BYTES` long.
+
+#[derive(Debug, Clone)]
+pub struct ChatChunk {
+    pub author: u8,
+    pub message_id: u32,
+    pub index: u16,
+    pub data: Vec<u8>,
+}
+
+impl ChatChunk {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(8 + self.data.len());
+        buf.push(ControlType::ChatChunk as u8);
+        buf.push(self.author);
+        buf.extend_from_slice(&self.message_id.to_be_bytes());
+        buf.extend_from_slice(&self.index.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 8 {
+            return None;
+        }
+        Some(Self {
+            author: buf[1],
+            message_id: u32::from_be_bytes([buf[2], buf[3], buf[4], buf[5]]),
+            index: u16::from_be_bytes([buf[6], buf[7]]),
+            data: buf[8..].to_vec(),
+        })
+    }
+}
+
+// --- ResumeTokens: host → guest ---
+// Wire: [ctrl_type=0x1B] [count: u8] [count × (participant_id: u8, token: 16 bytes)]
+// Always sent sealed and reliably. A newly admitted guest gets its own token;
+// the host's successor gets every guest's, to honour after a handover.
+
+#[derive(Debug, Clone)]
+pub struct ResumeTokens {
+    pub tokens: Vec<(u8, ResumeToken)>,
+}
+
+impl ResumeTokens {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(2 + self.tokens.len() * (1 + RESUME_TOKEN_SIZE));
+        buf.push(ControlType::ResumeTokens as u8);
+        buf.push(self.tokens.len() as u8);
+        for (participant_id, token) in &self.tokens {
+            buf.push(*participant_id);
+            buf.extend_from_slice(token);
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let count = *buf.get(1)? as usize;
+        let tokens = buf
+            .get(2..2 + count * (1 + RESUME_TOKEN_SIZE))?
+            .chunks_exact(1 + RESUME_TOKEN_SIZE)
+            .map(|entry| Some((entry[0], entry[1..].try_into().ok()?)))
+            .collect::<Option<_>>()?;
+        Some(Self { tokens })
     }
 }
 
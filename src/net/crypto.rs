@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::protocol::{PacketHeader, HEADER_SIZE};
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const RESUME_TOKEN_SIZE: usize = 16;
+pub const PASSPHRASE_PROOF_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+const COUNTER_SIZE: usize = 8;
+/// Session key sealed for one guest: 32-byte key + Poly1305 tag.
+pub const WRAPPED_KEY_SIZE: usize = 32 + TAG_SIZE;
+/// Bytes a sealed packet carries beyond its plaintext payload.
+pub const SEAL_OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+
+/// Packets this far behind the newest one from the same sender are dropped
+/// as replays even if they were never seen (as in IPsec / WireGuard). Wide,
+/// as a sender's one counter is spread over all its destinations: each sees
+/// only its share, and priority queueing and relaying reorder those.
+const REPLAY_WINDOW: u64 = 1024;
+const WINDOW_WORDS: usize = (REPLAY_WINDOW / 64) as usize;
+
+const WRAP_INFO: &[u8] = b"hyperzoom key wrap";
+const PROOF_INFO: &[u8] = b"hyperzoom passphrase proof";
+
+/// Symmetric key shared by everyone in the session, generated by the host.
+pub type SessionKey = Key;
+
+pub fn generate_session_key() -> SessionKey {
+    ChaCha20Poly1305::generate_key(&mut OsRng)
+}
+
+/// Unpredictable value for protocol tokens.
+pub fn random_u32() -> u32 {
+    OsRng.next_u32()
+}
+
+/// Handed to each guest once it is admitted; presenting it in a later Hello
+/// gets the guest its participant ID back after a drop (see
+/// `Hello::resume_token`). Random, so only the host, and the successor it
+/// shares them with (see `control::ResumeTokens`), knows whose is whose.
+pub type ResumeToken = [u8; RESUME_TOKEN_SIZE];
+
+pub fn generate_resume_token() -> ResumeToken {
+    let mut token = [0u8; RESUME_TOKEN_SIZE];
+    OsRng.fill_bytes(&mut token);
+    token
+}
+
+/// Shows the host a guest knows the passphrase before it is admitted.
+/// Bound to the guest's public key, so replaying someone else's Hello gets
+/// a Welcome that only they can open.
+pub type PassphraseProof = [u8; PASSPHRASE_PROOF_SIZE];
+
+pub fn passphrase_proof(passphrase: &str, public_key: &[u8; PUBLIC_KEY_SIZE]) -> PassphraseProof {
+    let hk = Hkdf::<Sha256>::new(Some(passphrase.as_bytes()), public_key);
+    let mut proof = [0u8; PASSPHRASE_PROOF_SIZE];
+    hk.expand(PROOF_INFO, &mut proof)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    proof
+}
+
+/// Check a guest's proof without revealing through timing how much of it matched.
+pub fn verify_passphrase_proof(
+    passphrase: &str,
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    proof: &PassphraseProof,
+) -> bool {
+    let expected = passphrase_proof(passphrase, public_key);
+    expected
+        .iter()
+        .zip(proof)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// One side of the X25519 exchange carried in Hello/Welcome.
+///
+/// The guest sends its public key in Hello; the host answers in Welcome with
+/// its own public key and the session key sealed under a key derived from the
+/// shared secret and the session passphrase. Without a passphrase this keeps
+/// out passive eavesdroppers and off-path injection but not an active
+/// man-in-the-middle. With one, a wrong passphrase makes the Welcome undecryptable.
+/// A weak passphrase can still be guessed offline by someone who intercepted a
+/// handshake, so it should be more than a word.
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.public.to_bytes()
+    }
+
+    /// Host side: seal `session_key` for the guest that sent `guest_public`.
+    pub fn wrap(
+        self,
+        guest_public: &[u8; PUBLIC_KEY_SIZE],
+        passphrase: &str,
+        session_key: &SessionKey,
+    ) -> Result<[u8; WRAPPED_KEY_SIZE], String> {
+        let host_public = self.public_key();
+        let cipher = self.wrapping_cipher(guest_public, guest_public, &host_public, passphrase)?;
+        let mut buf = [0u8; WRAPPED_KEY_SIZE];
+        buf[..32].copy_from_slice(session_key);
+        // The wrapping key is used exactly once, so a zero nonce is safe
+        let tag = cipher
+            .encrypt_in_place_detached(&Nonce::default(), &[], &mut buf[..32])
+            .map_err(|_| "Session key wrap failed".to_string())?;
+        buf[32..].copy_from_slice(&tag);
+        Ok(buf)
+    }
+
+    /// Guest side: recover the session key from the host's Welcome.
+    pub fn unwrap(
+        self,
+        host_public: &[u8; PUBLIC_KEY_SIZE],
+        wrapped: &[u8; WRAPPED_KEY_SIZE],
+        passphrase: &str,
+    ) -> Result<SessionKey, String> {
+        let guest_public = self.public_key();
+        let cipher = self.wrapping_cipher(host_public, &guest_public, host_public, passphrase)?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&wrapped[..32]);
+        cipher
+            .decrypt_in_place_detached(
+                &Nonce::default(),
+                &[],
+                &mut key,
+                Tag::from_slice(&wrapped[32..]),
+            )
+            .map_err(|_| "Could not decrypt the session key (wrong passphrase?)".to_string())?;
+        Ok(*Key::from_slice(&key))
+    }
+
+    fn wrapping_cipher(
+        self,
+        peer_public: &[u8; PUBLIC_KEY_SIZE],
+        guest_public: &[u8; PUBLIC_KEY_SIZE],
+        host_public: &[u8; PUBLIC_KEY_SIZE],
+        passphrase: &str,
+    ) -> Result<ChaCha20Poly1305, String> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer_public));
+        if !shared.was_contributory() {
+            return Err("Peer sent a degenerate public key".into());
+        }
+        // Bind both public keys so the wrapped key only opens for this exchange
+        let mut info = Vec::with_capacity(WRAP_INFO.len() + 2 * PUBLIC_KEY_SIZE);
+        info.extend_from_slice(WRAP_INFO);
+        info.extend_from_slice(guest_public);
+        info.extend_from_slice(host_public);
+
+        let hk = Hkdf::<Sha256>::new(Some(passphrase.as_bytes()), shared.as_bytes());
+        let mut okm = [0u8; 32];
+        hk.expand(&info, &mut okm)
+            .map_err(|_| "Key derivation failed".to_string())?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&okm)))
+    }
+}
+
+/// Sliding window of counters already accepted from one sender.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    newest: Option<u64>,
+    /// Bit `counter % REPLAY_WINDOW` set = that counter has been seen, for
+    /// counters within the window.
+    seen: [u64; WINDOW_WORDS],
+}
+
+impl ReplayWindow {
+    fn bit(counter: u64) -> (usize, u64) {
+        let index = counter % REPLAY_WINDOW;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn is_fresh(&self, counter: u64) -> bool {
+        match self.newest {
+            None => true,
+            Some(newest) if counter > newest => true,
+            Some(newest) => {
+                let (word, mask) = Self::bit(counter);
+                newest - counter < REPLAY_WINDOW && self.seen[word] & mask == 0
+            }
+        }
+    }
+
+    fn accept(&mut self, counter: u64) {
+        match self.newest {
+            Some(newest) if counter <= newest => {}
+            Some(newest) if counter - newest < REPLAY_WINDOW => {
+                // The slots of the counters skipped over now stand for them
+                for skipped in newest + 1..counter {
+                    let (word, mask) = Self::bit(skipped);
+                    self.seen[word] &= !mask;
+                }
+                self.newest = Some(counter);
+            }
+            _ => {
+                self.seen = [0; WINDOW_WORDS];
+                self.newest = Some(counter);
+            }
+        }
+        let (word, mask) = Self::bit(counter);
+        self.seen[word] |= mask;
+    }
+}
+
+/// Seals outgoing and opens incoming packets with the session key.
+///
+/// Sealed wire format: the 12-byte header (encrypted flag set, payload_length
+/// covering everything after it, authenticated as associated data), then
+/// [counter: u64 BE] [ciphertext] [tag: 16 bytes].
+///
+/// The nonce is the sender's participant ID followed by the counter. Counters
+/// start at the wall clock in microseconds, so a participant ID handed out again
+/// (e.g. after a rejoin) continues above anything sent under it before, which
+/// keeps nonces unique under the shared key and the replay windows monotonic.
+pub struct PacketCipher {
+    cipher: ChaCha20Poly1305,
+    next_counter: u64,
+    windows: HashMap<u8, ReplayWindow>,
+}
+
+impl PacketCipher {
+    pub fn new(key: &SessionKey) -> Self {
+        let now_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        Self {
+            cipher: ChaCha20Poly1305::new(key),
+            next_counter: now_us,
+            windows: HashMap::new(),
+        }
+    }
+
+    fn nonce(participant_id: u8, counter: u64) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[0] = participant_id;
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Seal a serialized plaintext packet.
+    pub fn seal(&mut self, packet: &[u8]) -> Result<Vec<u8>, String> {
+        let mut header = PacketHeader::from_bytes(packet).ok_or("Cannot seal malformed packet")?;
+        let payload_end = HEADER_SIZE + header.payload_length as usize;
+        let payload = packet
+            .get(HEADER_SIZE..payload_end)
+            .ok_or("Cannot seal truncated packet")?;
+        header.encrypted = true;
+        header.payload_length = u16::try_from(payload.len() + SEAL_OVERHEAD)
+            .map_err(|_| "Packet too large to seal".to_string())?;
+
+        let counter = self.next_counter;
+        self.next_counter += 1;
+
+        let header_bytes = header.to_bytes();
+        let mut out = Vec::with_capacity(HEADER_SIZE + payload.len() + SEAL_OVERHEAD);
+        out.extend_from_slice(&header_bytes);
+        out.extend_from_slice(&counter.to_be_bytes());
+        out.extend_from_slice(payload);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                &Self::nonce(header.participant_id, counter),
+                &header_bytes,
+                &mut out[HEADER_SIZE + COUNTER_SIZE..],
+            )
+            .map_err(|_| "Packet encryption failed".to_string())?;
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    /// Authenticate and decrypt a sealed packet, returning its header (with the
+    /// plaintext payload length) and payload. `None` for forgeries and replays.
+    pub fn open(&mut self, packet: &[u8]) -> Option<(PacketHeader, Vec<u8>)> {
+        let mut header = PacketHeader::from_bytes(packet)?;
+        let body = packet.get(HEADER_SIZE..HEADER_SIZE + header.payload_length as usize)?;
+        if !header.encrypted || body.len() < SEAL_OVERHEAD {
+            return None;
+        }
+        let counter = u64::from_be_bytes(body[..COUNTER_SIZE].try_into().ok()?);
+        let window = self.windows.entry(header.participant_id).or_default();
+        if !window.is_fresh(counter) {
+            return None;
+        }
+
+        let tag_start = body.len() - TAG_SIZE;
+        let mut payload = body[COUNTER_SIZE..tag_start].to_vec();
+        self.cipher
+            .decrypt_in_place_detached(
+                &Self::nonce(header.participant_id, counter),
+                &packet[..HEADER_SIZE],
+                &mut payload,
+                Tag::from_slice(&body[tag_start..]),
+            )
+            .ok()?;
+
+        // Only authenticated packets may advance the window
+        window.accept(counter);
+        header.payload_length = payload.len() as u16;
+        Some((header, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::protocol::{Packet, PacketType};
+
+    fn packet(participant_id: u8, payload: &[u8]) -> Vec<u8> {
+        let header = PacketHeader::new(PacketType::Control, participant_id, 0, 0, payload.len() as u16);
+        Packet::new(header, payload.to_vec()).to_bytes()
+    }
+
+    #[test]
+    fn replay_window_accepts_each_counter_once() {
+        let mut window = ReplayWindow::default();
+        for counter in [10, 12, 11] {
+            assert!(window.is_fresh(counter));
+            window.accept(counter);
+            assert!(!window.is_fresh(counter));
+        }
+        // Never seen, but too far behind the newest to tell
+        window.accept(10 + REPLAY_WINDOW + 5);
+        assert!(!window.is_fresh(13));
+        assert!(window.is_fresh(10 + REPLAY_WINDOW + 4));
+        assert!(!window.is_fresh(10 + REPLAY_WINDOW + 5));
+    }
+
+    #[test]
+    fn replay_window_jump_clears_history() {
+        let mut window = ReplayWindow::default();
+        window.accept(1);
+        window.accept(1 + 10 * REPLAY_WINDOW);
+        assert!(!window.is_fresh(1));
+        assert!(window.is_fresh(10 * REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn seal_open_round_trip_rejects_replays_and_tampering() {
+        let key = generate_session_key();
+        let mut sender = PacketCipher::new(&key);
+        let mut receiver = PacketCipher::new(&key);
+        let sealed = sender.seal(&packet(3, b"hello")).unwrap();
+        assert_eq!(sealed.len(), HEADER_SIZE + 5 + SEAL_OVERHEAD);
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(receiver.open(&tampered).is_none());
+        let mut relabelled = sealed.clone();
+        relabelled[1] = 4;
+        assert!(receiver.open(&relabelled).is_none());
+
+        let (header, payload) = receiver.open(&sealed).unwrap();
+        assert_eq!(header.participant_id, 3);
+        assert_eq!(header.payload_length, 5);
+        assert_eq!(payload, b"hello");
+        assert!(receiver.open(&sealed).is_none());
+    }
+
+    #[test]
+    fn senders_have_separate_windows() {
+        let key = generate_session_key();
+        let mut early = PacketCipher::new(&key);
+        let first = early.seal(&packet(1, b"a")).unwrap();
+        let later = PacketCipher::new(&key).seal(&packet(2, b"b")).unwrap();
+        let mut receiver = PacketCipher::new(&key);
+        for _ in 0..REPLAY_WINDOW {
+            early.seal(&packet(1, b"a")).unwrap();
+        }
+        let newest = early.seal(&packet(1, b"a")).unwrap();
+
+        // Each sender's counters only move its own window
+        assert!(receiver.open(&later).is_some());
+        assert!(receiver.open(&newest).is_some());
+        assert!(receiver.open(&first).is_none());
+    }
+
+    #[test]
+    fn reordering_across_destinations_is_not_replay() {
+        const DESTINATIONS: usize = 8;
+        let key = generate_session_key();
+        let mut sender = PacketCipher::new(&key);
+        let mut receiver = PacketCipher::new(&key);
+        // Round robin over the destinations; this receiver gets every 8th
+        let ours: Vec<Vec<u8>> = (0..DESTINATIONS * 100)
+            .map(|_| sender.seal(&packet(5, b"media")).unwrap())
+            .step_by(DESTINATIONS)
+            .collect();
+        // Its first 50 were held back behind the 50 after them
+        let (held, overtook) = ours.split_at(50);
+        for sealed in overtook.iter().chain(held) {
+            assert!(receiver.open(sealed).is_some());
+        }
+        for sealed in &ours {
+            assert!(receiver.open(sealed).is_none());
+        }
+    }
+
+    #[test]
+    fn key_wrap_needs_the_passphrase() {
+        let session_key = generate_session_key();
+        let host = KeyExchange::new();
+        let host_public = host.public_key();
+        let guest = KeyExchange::new();
+        let guest_public = guest.public_key();
+        let wrapped = host.wrap(&guest_public, "open sesame", &session_key).unwrap();
+        assert_eq!(guest.unwrap(&host_public, &wrapped, "open sesame").unwrap(), session_key);
+
+        let host = KeyExchange::new();
+        let host_public = host.public_key();
+        let guest = KeyExchange::new();
+        let wrapped = host.wrap(&guest.public_key(), "open sesame", &session_key).unwrap();
+        assert!(guest.unwrap(&host_public, &wrapped, "wrong").is_err());
+    }
+
+    #[test]
+    fn passphrase_proof_is_bound_to_the_key() {
+        let key = KeyExchange::new().public_key();
+        let proof = passphrase_proof("open sesame", &key);
+        assert!(verify_passphrase_proof("open sesame", &key, &proof));
+        assert!(!verify_passphrase_proof("wrong", &key, &proof));
+        assert!(!verify_passphrase_proof("open sesame", &KeyExchange::new().public_key(), &proof));
+    }
+}
@@ -1,18 +1,41 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio::net::UdpSocket;
 use tokio::runtime::Handle;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 
-use super::control::{self, Hello, Heartbeat, Welcome};
-use super::protocol::{Packet, PacketHeader, PacketType};
-use super::session::{PeerState, SharedSessionState, SessionState};
-use super::socket::{InboundEvent, UdpTransport};
+use super::clock::{ClockOffset, SYNC_ROUNDS, SYNC_SPACING};
+use super::control::{
+    self, Capabilities, Chat, ChatChunk, Hello, Heartbeat, HostHandover, LayerSelection, Moderate, ModerationAction, PeerJoined, Ping,
+    Pong, PunchAck, PunchCheck, ReceiverReport, Reject, RejectReason, RelayForward, RelayRequest,
+    RelayStart, ResumeTokens, SyncReply, SyncRequest, VideoSubscription, Welcome, VIDEO_CODEC_VP8,
+};
+use super::crypto::{self, KeyExchange, SEAL_OVERHEAD};
+use super::protocol::{Packet, PacketHeader, PacketType, HEADER_SIZE, PROTOCOL_VERSION};
+use super::session::{
+    relay_pair, PeerPath, PeerState, Role, SharedSessionState, SessionState, Topology,
+};
+use super::socket::UdpTransport;
+use super::transport::{InboundEvent, Transport};
 use super::upnp::PortMapping;
-use crate::audio::codec;
-use crate::audio::jitter::JitterBuffer;
+use crate::audio::codec::OPUS_FRAME_SAMPLES;
+use crate::audio::mixer::{AudioMixer, SharedMixer};
+use crate::video::ladder;
+
+/// Hole punching: connectivity checks to a newly introduced guest, this far
+/// apart, before giving up on a direct path. Stays under the 5 s peer timeout.
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+const PUNCH_ATTEMPTS: u32 = 20;
+
+/// How long a joining guest waits for the host to answer its Hello.
+const WELCOME_TIMEOUT: Duration = Duration::from_secs(5);
+/// In a waiting room: how often the guest repeats its Hello, and how long it
+/// waits for the host to let it in.
+const WAITING_HELLO_INTERVAL: Duration = Duration::from_secs(1);
+const WAITING_ROOM_TIMEOUT: Duration = Duration::from_secs(600);
 
 /// Result of a host or join attempt, sent back to the UI via oneshot.
 /// Does NOT contain AudioPipeline (cpal::Stream is !Send).
@@ -20,9 +43,8 @@ use crate::audio::jitter::JitterBuffer;
 pub enum ConnectResult {
     Ready {
         state: SharedSessionState,
-        socket: Arc<UdpSocket>,
-        transport: Arc<UdpTransport>,
-        jitter: Arc<Mutex<JitterBuffer>>,
+        transport: Arc<dyn Transport>,
+        mixer: SharedMixer,
         heartbeat_stop: tokio::sync::watch::Sender<bool>,
         inbound_stop: tokio::sync::watch::Sender<bool>,
         upnp: Option<PortMapping>,
@@ -45,97 +67,80 @@ impl NetworkManager {
     pub fn host(
         handle: Handle,
         name: String,
+        passphrase: String,
         port: u16,
+        topology: Topology,
+        waiting_room: bool,
         result_tx: oneshot::Sender<ConnectResult>,
     ) {
         let h = handle.clone();
         handle.spawn(async move {
-            let result = Self::do_host(h, name, port).await;
+            let result = Self::do_host(h, name, passphrase, port, topology, waiting_room).await;
             let _ = result_tx.send(result);
         });
     }
 
-    async fn do_host(_handle: Handle, name: String, port: u16) -> ConnectResult {
+    async fn do_host(
+        _handle: Handle,
+        name: String,
+        passphrase: String,
+        port: u16,
+        topology: Topology,
+        waiting_room: bool,
+    ) -> ConnectResult {
         let transport = match UdpTransport::bind(port).await {
             Ok(t) => Arc::new(t),
             Err(e) => return ConnectResult::Error(e),
         };
-
         let upnp = PortMapping::create(port).await;
+        Self::host_on(transport, upnp, port, name, passphrase, topology, waiting_room)
+    }
 
-        let state = Arc::new(Mutex::new(SessionState::new_host(name)));
-        let jitter = Arc::new(Mutex::new(JitterBuffer::new()));
-
-        let mut inbound_rx = transport.spawn_recv_loop();
+    /// Host a new session on an already-bound transport: a UDP socket, or a
+    /// node on a simulated network.
+    pub fn host_on(
+        transport: Arc<dyn Transport>,
+        upnp: Option<PortMapping>,
+        port: u16,
+        name: String,
+        passphrase: String,
+        topology: Topology,
+        waiting_room: bool,
+    ) -> ConnectResult {
+        let mut session = SessionState::new_host(name, passphrase, topology);
+        session.waiting_room = waiting_room;
+        if let Some(key) = &session.session_key {
+            transport.set_session_key(key);
+        }
+        transport.set_participant_id(session.my_participant_id);
+        let state = Arc::new(Mutex::new(session));
+        let mixer = Arc::new(Mutex::new(AudioMixer::new()));
 
-        let mut decoder = match codec::create_decoder() {
-            Ok(d) => d,
-            Err(e) => return ConnectResult::Error(e),
-        };
+        let inbound_rx = transport.spawn_recv_loop();
 
         // Video event channel
         let (video_tx, video_rx) = mpsc::unbounded_channel();
 
         // Spawn inbound processing task
-        let (stop_tx, mut stop_rx) = tokio::sync::watch::channel(false);
-        let state_clone = state.clone();
-        let jitter_clone = jitter.clone();
-        let transport_clone = transport.clone();
+        let stop_tx = Self::spawn_inbound_task(
+            state.clone(),
+            transport.clone(),
+            mixer.clone(),
+            inbound_rx,
+            video_tx,
+        );
 
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    _ = stop_rx.changed() => break,
-                    event = inbound_rx.recv() => {
-                        match event {
-                            Some(InboundEvent::Audio { participant_id, sequence, payload, .. }) => {
-                                let samples = codec::decode_frame(&mut decoder, Some(&payload));
-                                if let Ok(mut jb) = jitter_clone.lock() {
-                                    jb.push(sequence, samples);
-                                }
-                                if let Ok(mut s) = state_clone.lock() {
-                                    s.touch_peer(participant_id);
-                                }
-                            }
-                            Some(ref ev @ InboundEvent::Video { participant_id, .. }) => {
-                                if let Ok(mut s) = state_clone.lock() {
-                                    s.touch_peer(participant_id);
-                                }
-                                // Forward to video pipeline
-                                let _ = video_tx.send(ev.clone());
-                            }
-                            Some(InboundEvent::Control { from, payload, .. }) => {
-                                Self::handle_control_host(
-                                    &state_clone,
-                                    &transport_clone,
-                                    from,
-                                    &payload,
-                                ).await;
-                            }
-                            Some(InboundEvent::Bye { participant_id }) => {
-                                if let Ok(mut s) = state_clone.lock() {
-                                    if let Some(peer) = s.peers.get_mut(&participant_id) {
-                                        log::info!("Peer {} sent BYE", peer.name);
-                                        peer.state = PeerState::Disconnected;
-                                    }
-                                }
-                            }
-                            None => break,
-                        }
-                    }
-                }
-            }
-        });
+        // Learn the address guests should join on, in the background
+        Self::spawn_public_addr_discovery(state.clone(), transport.clone());
 
         // Start heartbeat
         let (hb_stop_tx, hb_stop_rx) = tokio::sync::watch::channel(false);
-        Self::start_heartbeat(state.clone(), transport.clone(), hb_stop_rx);
+        Self::start_heartbeat(state.clone(), transport.clone(), mixer.clone(), hb_stop_rx);
 
         ConnectResult::Ready {
             state,
-            socket: transport.socket.clone(),
             transport,
-            jitter,
+            mixer,
             heartbeat_stop: hb_stop_tx,
             inbound_stop: stop_tx,
             upnp,
@@ -144,17 +149,20 @@ impl NetworkManager {
         }
     }
 
-    /// Join an existing session.
+    /// Join an existing session. `waiting_tx` turns true if the host puts us
+    /// in its waiting room.
     pub fn join(
         handle: Handle,
         name: String,
+        passphrase: String,
         host_addr: SocketAddr,
         local_port: u16,
+        waiting_tx: watch::Sender<bool>,
         result_tx: oneshot::Sender<ConnectResult>,
     ) {
         let h = handle.clone();
         handle.spawn(async move {
-            let result = Self::do_join(h, name, host_addr, local_port).await;
+            let result = Self::do_join(h, name, passphrase, host_addr, local_port, waiting_tx).await;
             let _ = result_tx.send(result);
         });
     }
@@ -162,52 +170,100 @@ impl NetworkManager {
     async fn do_join(
         _handle: Handle,
         name: String,
+        passphrase: String,
         host_addr: SocketAddr,
         local_port: u16,
+        waiting_tx: watch::Sender<bool>,
     ) -> ConnectResult {
         let transport = match UdpTransport::bind(local_port).await {
             Ok(t) => Arc::new(t),
             Err(e) => return ConnectResult::Error(e),
         };
+        Self::join_on(transport, name, passphrase, host_addr, local_port, waiting_tx).await
+    }
 
-        let state = Arc::new(Mutex::new(SessionState::new_guest(name.clone())));
-        let jitter = Arc::new(Mutex::new(JitterBuffer::new()));
+    /// Join an existing session over an already-bound transport, as `host_on`.
+    pub async fn join_on(
+        transport: Arc<dyn Transport>,
+        name: String,
+        passphrase: String,
+        host_addr: SocketAddr,
+        local_port: u16,
+        waiting_tx: watch::Sender<bool>,
+    ) -> ConnectResult {
+        let state = Arc::new(Mutex::new(SessionState::new_guest(
+            name.clone(),
+            passphrase.clone(),
+        )));
+        let mixer = Arc::new(Mutex::new(AudioMixer::new()));
 
         let mut inbound_rx = transport.spawn_recv_loop();
 
-        // Send Hello to host
-        let hello_payload = Hello { name: name.clone() }.to_bytes();
+        // Send Hello to host, opening the session key exchange
+        let key_exchange = KeyExchange::new();
+        let public_key = key_exchange.public_key();
+        let hello_payload = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            name: name.clone(),
+            capabilities: Self::local_capabilities(),
+            public_key: Some(public_key),
+            passphrase_proof: Some(crypto::passphrase_proof(&passphrase, &public_key)),
+            resume_token: None,
+        }
+        .to_bytes();
         let header = PacketHeader::new(PacketType::Control, 0, 0, 0, hello_payload.len() as u16);
         let packet = Packet::new(header, hello_payload).to_bytes();
-        if let Err(e) = transport.send_to(&packet, host_addr).await {
+        if let Err(e) = transport.send_handshake_to(&packet, host_addr) {
             return ConnectResult::Error(format!("Failed to send Hello: {e}"));
         }
         log::info!("Sent Hello to {host_addr}");
 
-        // Wait for Welcome (with timeout)
-        let welcome = tokio::time::timeout(Duration::from_secs(5), async {
-            loop {
-                match inbound_rx.recv().await {
-                    Some(InboundEvent::Control { payload, from, .. }) => {
-                        if let Some(ctrl_type) = control::parse_control_type(&payload) {
-                            if ctrl_type == control::ControlType::Welcome {
-                                if let Some(welcome) = Welcome::from_bytes(&payload) {
-                                    return Ok((welcome, from));
-                                }
-                            }
+        // Wait for Welcome, or a Reject saying why not. A host with a waiting
+        // room answers Waiting instead; then keep knocking until it decides.
+        let mut deadline = tokio::time::Instant::now() + WELCOME_TIMEOUT;
+        let mut waiting = false;
+        let (welcome, host_id) = loop {
+            if tokio::time::Instant::now() >= deadline {
+                return ConnectResult::Error(if waiting {
+                    "The host didn't let you in".into()
+                } else {
+                    "Timeout waiting for Welcome from host".into()
+                });
+            }
+            let (payload, sender) = match tokio::time::timeout(WAITING_HELLO_INTERVAL, inbound_rx.recv()).await {
+                Ok(Some(InboundEvent::Control { payload, participant_id, .. })) => (payload, participant_id),
+                Ok(Some(_)) => continue,
+                Ok(None) => {
+                    return ConnectResult::Error("Channel closed while waiting for Welcome".into())
+                }
+                Err(_) => {
+                    if waiting {
+                        if let Err(e) = transport.send_handshake_to(&packet, host_addr) {
+                            log::debug!("Failed to repeat Hello: {e}");
                         }
                     }
-                    None => return Err("Channel closed while waiting for Welcome".to_string()),
-                    _ => continue,
+                    continue;
                 }
+            };
+            match control::parse_control_type(&payload) {
+                Some(control::ControlType::Welcome) => {
+                    if let Some(welcome) = Welcome::from_bytes(&payload) {
+                        break (welcome, sender);
+                    }
+                }
+                Some(control::ControlType::Reject) => {
+                    if let Some(reject) = Reject::from_bytes(&payload) {
+                        return ConnectResult::Error(reject.message(PROTOCOL_VERSION));
+                    }
+                }
+                Some(control::ControlType::Waiting) if !waiting => {
+                    log::info!("Host put us in its waiting room");
+                    waiting = true;
+                    deadline = tokio::time::Instant::now() + WAITING_ROOM_TIMEOUT;
+                    let _ = waiting_tx.send(true);
+                }
+                _ => {}
             }
-        })
-        .await;
-
-        let (welcome, _) = match welcome {
-            Ok(Ok(w)) => w,
-            Ok(Err(e)) => return ConnectResult::Error(e),
-            Err(_) => return ConnectResult::Error("Timeout waiting for Welcome from host".into()),
         };
 
         log::info!(
@@ -215,25 +271,79 @@ impl NetworkManager {
             welcome.session_id, welcome.assigned_participant_id
         );
 
-        {
+        let session_key = match key_exchange.unwrap(
+            &welcome.host_public_key,
+            &welcome.wrapped_session_key,
+            &passphrase,
+        ) {
+            Ok(key) => key,
+            Err(e) => return ConnectResult::Error(e),
+        };
+        // Everything from here on is encrypted
+        transport.set_session_key(&session_key);
+        transport.set_participant_id(welcome.assigned_participant_id);
+
+        let added = {
             let mut s = state.lock().unwrap();
+            s.session_key = Some(session_key);
             s.session_id = welcome.session_id;
             s.my_participant_id = welcome.assigned_participant_id;
-            s.add_peer(1, "Host".into(), host_addr);
-            s.touch_peer(1);
-        }
-
-        let mut decoder = match codec::create_decoder() {
-            Ok(d) => d,
-            Err(e) => return ConnectResult::Error(e),
+            // Not the original host, if the session has been handed over
+            s.host_id = host_id;
+            Self::admit_welcome(&mut s, &mixer, host_addr, &welcome)
         };
 
+        // Introduce ourselves to the other guests so they can reach us directly
+        // (in SFU mode the host's PeerJoined is all they need)
+        let mesh_peers = if welcome.sfu { Vec::new() } else { added };
+        Self::introduce(&transport, welcome.assigned_participant_id, &name, &mesh_peers);
+
         // Video event channel
         let (video_tx, video_rx) = mpsc::unbounded_channel();
 
+        let stop_tx = Self::spawn_inbound_task(
+            state.clone(),
+            transport.clone(),
+            mixer.clone(),
+            inbound_rx,
+            video_tx,
+        );
+
+        // Punch through to the other guests at the addresses the host observed;
+        // they start punching towards us when the host announces us
+        for peer in &mesh_peers {
+            Self::spawn_hole_punch(state.clone(), transport.clone(), peer.participant_id);
+        }
+
+        // Estimate our clock offset to the host (replies arrive via the inbound task)
+        Self::spawn_clock_sync(state.clone(), transport.clone(), host_id);
+
+        let (hb_stop_tx, hb_stop_rx) = tokio::sync::watch::channel(false);
+        Self::start_heartbeat(state.clone(), transport.clone(), mixer.clone(), hb_stop_rx);
+
+        ConnectResult::Ready {
+            state,
+            transport,
+            mixer,
+            heartbeat_stop: hb_stop_tx,
+            inbound_stop: stop_tx,
+            upnp: None,
+            local_port,
+            video_rx,
+        }
+    }
+
+    /// Spawn the task that consumes inbound events: hands audio to the sender's
+    /// mixer source for decoding, forwards video to the video pipeline, and handles control messages.
+    /// Returns the sender used to stop it.
+    fn spawn_inbound_task(
+        state: SharedSessionState,
+        transport: Arc<dyn Transport>,
+        mixer: SharedMixer,
+        mut inbound_rx: mpsc::UnboundedReceiver<InboundEvent>,
+        video_tx: mpsc::UnboundedSender<InboundEvent>,
+    ) -> tokio::sync::watch::Sender<bool> {
         let (stop_tx, mut stop_rx) = tokio::sync::watch::channel(false);
-        let state_clone = state.clone();
-        let jitter_clone = jitter.clone();
 
         tokio::spawn(async move {
             loop {
@@ -241,28 +351,93 @@ impl NetworkManager {
                     _ = stop_rx.changed() => break,
                     event = inbound_rx.recv() => {
                         match event {
-                            Some(InboundEvent::Audio { participant_id, sequence, payload, .. }) => {
-                                let samples = codec::decode_frame(&mut decoder, Some(&payload));
-                                if let Ok(mut jb) = jitter_clone.lock() {
-                                    jb.push(sequence, samples);
+                            Some(InboundEvent::Audio { participant_id, sequence, timestamp_ms, payload }) => {
+                                let bytes = HEADER_SIZE + payload.len();
+                                if let Ok(mut m) = mixer.lock() {
+                                    m.push_packet(participant_id, sequence, payload);
                                 }
-                                if let Ok(mut s) = state_clone.lock() {
+                                if let Ok(mut s) = state.lock() {
                                     s.touch_peer(participant_id);
+                                    s.record_relayed(participant_id, bytes + SEAL_OVERHEAD, None);
+                                    let now = s.elapsed_ms();
+                                    if let Some(peer) = s.peers.get_mut(&participant_id) {
+                                        peer.stats.record_in(bytes);
+                                        peer.stats.audio.record(sequence, timestamp_ms, now, bytes);
+                                    }
                                 }
                             }
-                            Some(ref ev @ InboundEvent::Video { participant_id, .. }) => {
-                                if let Ok(mut s) = state_clone.lock() {
-                                    s.touch_peer(participant_id);
+                            Some(ref ev @ InboundEvent::Video { participant_id, layer, sequence, timestamp_ms, ref payload, .. }) => {
+                                let bytes = HEADER_SIZE + payload.len();
+                                let shown = match state.lock() {
+                                    Ok(mut s) => {
+                                        s.touch_peer(participant_id);
+                                        if s.mark_layer_live(participant_id, layer) && s.is_relaying() {
+                                            transport.set_relay_routes(s.relay_routes());
+                                        }
+                                        s.record_relayed(participant_id, bytes + SEAL_OVERHEAD, Some(layer));
+                                        let shown = s.display_layer(participant_id) == Some(layer);
+                                        let now = s.elapsed_ms();
+                                        if let Some(peer) = s.peers.get_mut(&participant_id) {
+                                            peer.stats.record_in(bytes);
+                                            if shown {
+                                                // Each layer has its own sequence numbers
+                                                if peer.video_layer_in != Some(layer) {
+                                                    peer.stats.video.restart_sequence();
+                                                    peer.video_layer_in = Some(layer);
+                                                }
+                                                peer.stats.video.record(sequence, timestamp_ms, now, bytes);
+                                            }
+                                        }
+                                        shown
+                                    }
+                                    Err(_) => false,
+                                };
+                                // Forward the layer we show to the video pipeline;
+                                // other layers only pass through the host
+                                if shown {
+                                    let _ = video_tx.send(ev.clone());
                                 }
-                                let _ = video_tx.send(ev.clone());
                             }
-                            Some(InboundEvent::Control { .. }) => {}
+                            Some(InboundEvent::Control { from, participant_id, payload, received_at }) => {
+                                if let Ok(mut s) = state.lock() {
+                                    if let Some(peer) = s.peers.get_mut(&participant_id) {
+                                        peer.stats.record_in(HEADER_SIZE + payload.len());
+                                    }
+                                }
+                                Self::handle_control(
+                                    &state,
+                                    &transport,
+                                    &mixer,
+                                    &video_tx,
+                                    from,
+                                    participant_id,
+                                    &payload,
+                                    received_at,
+                                ).await;
+                            }
                             Some(InboundEvent::Bye { participant_id }) => {
-                                if let Ok(mut s) = state_clone.lock() {
+                                if let Ok(mut s) = state.lock() {
                                     if let Some(peer) = s.peers.get_mut(&participant_id) {
                                         log::info!("Peer {} sent BYE", peer.name);
                                         peer.state = PeerState::Disconnected;
+                                        // Leaving on purpose: nothing to resume
+                                        peer.resumable_until = None;
+                                    }
+                                }
+                                if let Ok(mut m) = mixer.lock() {
+                                    m.remove_source(participant_id);
+                                }
+                                Self::reset_video(&video_tx, participant_id);
+                            }
+                            Some(InboundEvent::Undelivered { to, messages }) => {
+                                let lost: Vec<_> = messages.iter().filter_map(|m| control::parse_control_type(m)).collect();
+                                log::warn!("Control messages to {to} were never acked: {lost:?}");
+                                let dropped = state.lock().ok().and_then(|mut s| s.give_up_on(to));
+                                if let Some(participant_id) = dropped {
+                                    if let Ok(mut m) = mixer.lock() {
+                                        m.remove_source(participant_id);
                                     }
+                                    Self::reset_video(&video_tx, participant_id);
                                 }
                             }
                             None => break,
@@ -272,85 +447,1058 @@ impl NetworkManager {
             }
         });
 
-        let (hb_stop_tx, hb_stop_rx) = tokio::sync::watch::channel(false);
-        Self::start_heartbeat(state.clone(), transport.clone(), hb_stop_rx);
+        stop_tx
+    }
 
-        ConnectResult::Ready {
-            state,
-            socket: transport.socket.clone(),
-            transport,
-            jitter,
-            heartbeat_stop: hb_stop_tx,
-            inbound_stop: stop_tx,
-            upnp: None,
-            local_port,
-            video_rx,
+    /// Dispatch a control message according to our current role.
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_control(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        mixer: &SharedMixer,
+        video_tx: &mpsc::UnboundedSender<InboundEvent>,
+        from: SocketAddr,
+        participant_id: u8,
+        payload: &[u8],
+        received_at: Instant,
+    ) {
+        let ctrl_type = match control::parse_control_type(payload) {
+            Some(t) => t,
+            None => return,
+        };
+
+        if ctrl_type == control::ControlType::Heartbeat {
+            state.lock().unwrap().touch_peer(participant_id);
+            return;
+        }
+
+        if ctrl_type == control::ControlType::Ping {
+            let Some(ping) = Ping::from_bytes(payload) else { return };
+            let my_id = state.lock().unwrap().my_participant_id;
+            let pong_payload = Pong {
+                timestamp_us: ping.timestamp_us,
+            }
+            .to_bytes();
+            let header = PacketHeader::new(
+                PacketType::Control,
+                my_id,
+                0,
+                0,
+                pong_payload.len() as u16,
+            );
+            let packet = Packet::new(header, pong_payload).to_bytes();
+            if let Err(e) = transport.send_to(&packet, from) {
+                log::debug!("Pong send failed to {from}: {e}");
+            }
+            return;
+        }
+
+        if ctrl_type == control::ControlType::SyncRequest {
+            let Some(req) = SyncRequest::from_bytes(payload) else { return };
+            // t2 is when the request came off the socket, not when we got to
+            // it; t3 is read as late as possible before the reply goes out
+            let (my_id, t2, t3) = {
+                let s = state.lock().unwrap();
+                (s.my_participant_id, s.clock_us_at(received_at), s.clock_us())
+            };
+            let reply_payload = SyncReply { t1: req.t1, t2, t3 }.to_bytes();
+            let header = PacketHeader::new(
+                PacketType::Control,
+                my_id,
+                0,
+                0,
+                reply_payload.len() as u16,
+            );
+            let packet = Packet::new(header, reply_payload).to_bytes();
+            if let Err(e) = transport.send_to(&packet, from) {
+                log::debug!("SyncReply send failed to {from}: {e}");
+            }
+            return;
+        }
+
+        if ctrl_type == control::ControlType::SyncReply {
+            let Some(reply) = SyncReply::from_bytes(payload) else { return };
+            let mut s = state.lock().unwrap();
+            let t4 = s.clock_us_at(received_at);
+            let sample = ClockOffset::from_exchange(reply.t1, reply.t2, reply.t3, t4);
+            if let Some(peer) = s.peers.get_mut(&participant_id) {
+                match peer.clock.as_mut() {
+                    Some(clock) => clock.merge(sample),
+                    None => peer.clock = Some(sample),
+                }
+                let clock = peer.clock.unwrap();
+                log::debug!(
+                    "Clock sync with {} ({} samples): offset {:.3} ms ± {:.3} ms",
+                    peer.name,
+                    clock.samples,
+                    clock.offset_ms(),
+                    clock.uncertainty_ms()
+                );
+            }
+            return;
+        }
+
+        if ctrl_type == control::ControlType::ReceiverReport {
+            let Some(report) = ReceiverReport::from_bytes(payload) else { return };
+            let mut s = state.lock().unwrap();
+            if let Some(peer) = s.peers.get_mut(&participant_id) {
+                peer.stats.remote_report = Some(report);
+            }
+            return;
+        }
+
+        if ctrl_type == control::ControlType::LayerSelection {
+            let Some(selection) = LayerSelection::from_bytes(payload) else { return };
+            let layers: HashMap<u8, u8> = selection.layers.into_iter().collect();
+            let mut s = state.lock().unwrap();
+            let Some(peer) = s.peers.get_mut(&participant_id) else { return };
+            if peer.video_layers != layers {
+                log::debug!("{} wants video layers {layers:?}", peer.name);
+                peer.video_layers = layers;
+                if s.is_relaying() {
+                    transport.set_relay_routes(s.relay_routes());
+                }
+            }
+            return;
+        }
+
+        if ctrl_type == control::ControlType::PunchCheck {
+            let Some(check) = PunchCheck::from_bytes(payload) else { return };
+            let (my_id, triggered) = {
+                let mut s = state.lock().unwrap();
+                let my_id = s.my_participant_id;
+                let Some(peer) = s.peers.get_mut(&participant_id) else { return };
+                // The check is authenticated, so `from` is where this peer's
+                // NAT actually maps it towards us
+                if peer.addr != from {
+                    log::info!("Peer {} reachable at {from} (was {})", peer.name, peer.addr);
+                    peer.addr = from;
+                }
+                // Answer with a check of our own so both sides confirm the path
+                let triggered = if peer.state == PeerState::Connecting {
+                    Some(*peer.punch_token.get_or_insert_with(crypto::random_u32))
+                } else {
+                    None
+                };
+                (my_id, triggered)
+            };
+            let ack = PunchAck { token: check.token }.to_bytes();
+            Self::send_control_to(transport, my_id, ack, from);
+            if let Some(token) = triggered {
+                Self::send_control_to(transport, my_id, PunchCheck { token }.to_bytes(), from);
+            }
+            return;
+        }
+
+        if ctrl_type == control::ControlType::PunchAck {
+            let Some(ack) = PunchAck::from_bytes(payload) else { return };
+            let mut s = state.lock().unwrap();
+            let Some(peer) = s.peers.get_mut(&participant_id) else { return };
+            // Once the pair is on the relay, stay there
+            if peer.punch_token != Some(ack.token) || peer.path == PeerPath::Relay {
+                return;
+            }
+            s.set_connected(participant_id);
+            return;
+        }
+
+        if ctrl_type == control::ControlType::Pong {
+            let Some(pong) = Pong::from_bytes(payload) else { return };
+            let mut s = state.lock().unwrap();
+            let rtt_ms = s.elapsed_us().wrapping_sub(pong.timestamp_us) as f32 / 1000.0;
+            if let Some(peer) = s.peers.get_mut(&participant_id) {
+                peer.stats.record_rtt(rtt_ms);
+            }
+            return;
+        }
+
+        // Video feedback is handled by the video pipeline
+        if matches!(
+            ctrl_type,
+            control::ControlType::Nack | control::ControlType::KeyframeRequest
+        ) {
+            let _ = video_tx.send(InboundEvent::Control {
+                from,
+                participant_id,
+                payload: payload.to_vec(),
+                received_at,
+            });
+            return;
+        }
+
+        if matches!(
+            ctrl_type,
+            control::ControlType::Chat | control::ControlType::ChatChunk
+        ) {
+            Self::handle_chat(state, transport, participant_id, ctrl_type, payload);
+            return;
+        }
+
+        // Only a guest resuming after a drop expects a Welcome here; the
+        // first one is consumed by `do_join`
+        if ctrl_type == control::ControlType::Welcome {
+            if let Some(welcome) = Welcome::from_bytes(payload) {
+                Self::resume(state, transport, mixer, video_tx, from, participant_id, welcome);
+            }
+            return;
+        }
+
+        let role = state.lock().unwrap().role;
+        match role {
+            Role::Host => {
+                Self::handle_control_host(
+                    state,
+                    transport,
+                    mixer,
+                    video_tx,
+                    from,
+                    participant_id,
+                    ctrl_type,
+                    payload,
+                )
+                .await
+            }
+            Role::Guest => Self::handle_control_guest(
+                state,
+                transport,
+                mixer,
+                video_tx,
+                from,
+                participant_id,
+                ctrl_type,
+                payload,
+            ),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn handle_control_host(
         state: &SharedSessionState,
-        transport: &Arc<UdpTransport>,
+        transport: &Arc<dyn Transport>,
+        mixer: &SharedMixer,
+        video_tx: &mpsc::UnboundedSender<InboundEvent>,
         from: SocketAddr,
+        participant_id: u8,
+        ctrl_type: control::ControlType,
         payload: &[u8],
     ) {
-        let ctrl_type = match control::parse_control_type(payload) {
-            Some(t) => t,
+        match ctrl_type {
+            control::ControlType::Hello => {}
+            control::ControlType::RelayRequest => {
+                if let Some(req) = RelayRequest::from_bytes(payload) {
+                    Self::start_relay(state, transport, participant_id, req.peer_id).await;
+                }
+                return;
+            }
+            control::ControlType::RelayForward => {
+                if let Some(fwd) = RelayForward::from_bytes(payload) {
+                    Self::forward_relayed(state, transport, participant_id, fwd);
+                }
+                return;
+            }
+            control::ControlType::VideoSubscription => {
+                let Some(sub) = VideoSubscription::from_bytes(payload) else { return };
+                let mut s = state.lock().unwrap();
+                let Some(peer) = s.peers.get_mut(&participant_id) else { return };
+                if peer.hidden_video != sub.hidden {
+                    log::info!("{} now hides video from {:?}", peer.name, sub.hidden);
+                    peer.hidden_video = sub.hidden;
+                    if s.topology == Topology::Sfu {
+                        transport.set_relay_routes(s.relay_routes());
+                    }
+                }
+                return;
+            }
+            _ => return,
+        }
+
+        // Check the version before anything else: past it, another
+        // version's Hello may not even parse
+        let Some(version) = Hello::version_of(payload) else { return };
+        let my_id = state.lock().unwrap().my_participant_id;
+        if version != PROTOCOL_VERSION {
+            log::warn!("Turning away a guest at {from}: protocol v{version}, ours is v{PROTOCOL_VERSION}");
+            Self::reject(transport, my_id, RejectReason::VersionMismatch, from);
+            return;
+        }
+        let hello = match Hello::from_bytes(payload) {
+            Some(h) => h,
             None => return,
         };
+        log::info!("Received Hello from {} at {from}", hello.name);
+        if let Some(reason) = Self::incompatibility(&hello.capabilities) {
+            log::warn!("Turning away {} at {from}: {reason:?} ({:?})", hello.name, hello.capabilities);
+            Self::reject(transport, my_id, reason, from);
+            return;
+        }
+
+        let (Some(guest_key), Some(proof)) = (hello.public_key, hello.passphrase_proof) else {
+            log::warn!("Ignoring Hello from {from} without a key exchange");
+            return;
+        };
+
+        // Password, room lock and waiting room. A guest resuming after a drop
+        // was let in already.
+        let knock = {
+            let mut s = state.lock().unwrap();
+            let resuming = hello
+                .resume_token
+                .is_some_and(|token| s.resumable_peer(&token).is_some());
+            if !crypto::verify_passphrase_proof(&s.passphrase, &guest_key, &proof) {
+                Err(RejectReason::WrongPassword)
+            } else if s.room_locked && !resuming {
+                Err(RejectReason::RoomLocked)
+            } else {
+                Ok((s.waiting_room && !resuming).then(|| s.knock(from, &hello.name)))
+            }
+        };
+        match knock {
+            Err(reason) => {
+                log::warn!("Turning away {} at {from}: {reason:?}", hello.name);
+                Self::reject(transport, my_id, reason, from);
+                return;
+            }
+            Ok(Some(None)) => {
+                Self::send_handshake_control(transport, my_id, control::Waiting.to_bytes(), from);
+                return;
+            }
+            Ok(Some(Some(false))) => {
+                log::info!("Denied {} at {from} from the waiting room", hello.name);
+                Self::reject(transport, my_id, RejectReason::Denied, from);
+                return;
+            }
+            Ok(Some(Some(true)) | None) => {}
+        }
+
+        let (session_id, assigned_id, my_id, existing, host_public_key, wrapped_session_key, sfu, resume_token) = {
+            let mut s = state.lock().unwrap();
+            let Some(session_key) = s.session_key else { return };
+            // A dropped guest coming back keeps its participant ID (and its seat)
+            let resumed = hello.resume_token.and_then(|token| s.resumable_peer(&token));
+            if resumed.is_none() && s.participant_count() >= s.topology.max_participants() {
+                log::warn!(
+                    "Turning away {} at {from}: session is full ({} participants)",
+                    hello.name,
+                    s.topology.max_participants()
+                );
+                Self::reject(transport, my_id, RejectReason::SessionFull, from);
+                return;
+            }
+            let assigned_id = match resumed {
+                Some(id) => id,
+                None => match s.assign_participant_id() {
+                    Some(id) => id,
+                    None => {
+                        log::warn!("Turning away {} at {from}: no participant ID is free", hello.name);
+                        Self::reject(transport, my_id, RejectReason::SessionFull, from);
+                        return;
+                    }
+                },
+            };
+            let key_exchange = KeyExchange::new();
+            let host_public_key = key_exchange.public_key();
+            let wrapped_session_key = match key_exchange.wrap(&guest_key, &s.passphrase, &session_key) {
+                Ok(w) => w,
+                Err(e) => {
+                    log::warn!("Rejecting Hello from {from}: {e}");
+                    return;
+                }
+            };
+            if resumed.is_some() {
+                log::info!("{} resumes as participant {assigned_id}", hello.name);
+                // Its old paths to the other guests are gone; they start over
+                s.relay_pairs.retain(|&(a, b), _| a != assigned_id && b != assigned_id);
+            }
+            // Roster of guests the newcomer should connect to directly
+            let existing: Vec<PeerJoined> = s
+                .peers
+                .values()
+                .filter(|p| p.state != PeerState::Disconnected && p.participant_id != assigned_id)
+                .map(|p| PeerJoined {
+                    participant_id: p.participant_id,
+                    addr: p.addr,
+                    name: p.name.clone(),
+                })
+                .collect();
+            s.add_peer(assigned_id, hello.name.clone(), from);
+            s.set_connected(assigned_id);
+            s.set_capabilities(assigned_id, &hello.capabilities);
+            let resume_token = s.issue_resume_token(assigned_id);
+            mixer.lock().unwrap().add_source(assigned_id);
+            if resumed.is_some() {
+                Self::reset_video(video_tx, assigned_id);
+            }
+            let sfu = s.topology == Topology::Sfu;
+            if s.is_relaying() {
+                transport.set_relay_routes(s.relay_routes());
+            }
+            (
+                s.session_id,
+                assigned_id,
+                s.my_participant_id,
+                existing,
+                host_public_key,
+                wrapped_session_key,
+                sfu,
+                resume_token,
+            )
+        };
+
+        let existing_addrs: Vec<SocketAddr> = existing.iter().map(|p| p.addr).collect();
+        let welcome = Welcome {
+            session_id,
+            assigned_participant_id: assigned_id,
+            host_public_key,
+            wrapped_session_key,
+            sfu,
+            capabilities: Self::local_capabilities(),
+            peers: existing,
+        };
+        // The guest can't decrypt anything until it has unwrapped the key
+        if let Err(e) = transport.send_handshake_reliable(my_id, welcome.to_bytes(), from) {
+            log::warn!("Failed to send Welcome to {from}: {e}");
+        }
+        log::info!("Sent Welcome to {} (id={})", hello.name, assigned_id);
 
+        // Its resume token goes sealed, once the guest has the key: only the
+        // guest, us and our successor may know it
+        if let Some(token) = resume_token {
+            let tokens = ResumeTokens {
+                tokens: vec![(assigned_id, token)],
+            };
+            if let Err(e) = transport.send_reliable(my_id, tokens.to_bytes(), from) {
+                log::warn!("Failed to send resume token to {from}: {e}");
+            }
+        }
+
+        // Estimate the newcomer's clock offset so we can align their recording
+        Self::spawn_clock_sync(state.clone(), transport.clone(), assigned_id);
+
+        // Tell everyone already in the call about the newcomer. Guests on the
+        // other address family can't punch through to it, and fall back to the relay.
+        let joined_payload = PeerJoined {
+            participant_id: assigned_id,
+            addr: from,
+            name: hello.name.clone(),
+        }
+        .to_bytes();
+        for target in existing_addrs {
+            if let Err(e) = transport.send_reliable(my_id, joined_payload.clone(), target) {
+                log::warn!("Failed to send PeerJoined to {target}: {e}");
+            }
+        }
+    }
+
+    /// Host: relay traffic between two guests that couldn't punch through to
+    /// each other, and tell both to route via us.
+    async fn start_relay(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        requester: u8,
+        peer_id: u8,
+    ) {
+        let (my_id, targets) = {
+            let mut s = state.lock().unwrap();
+            let active = |id: u8| {
+                id != s.my_participant_id
+                    && s.peers.get(&id).is_some_and(|p| p.state != PeerState::Disconnected)
+            };
+            if requester == peer_id || !active(requester) || !active(peer_id) {
+                return;
+            }
+            let (a, b) = (&s.peers[&requester], &s.peers[&peer_id]);
+            let targets = [(a.addr, peer_id), (b.addr, requester)];
+            let names = (a.name.clone(), b.name.clone());
+            if let Entry::Vacant(entry) = s.relay_pairs.entry(relay_pair(requester, peer_id)) {
+                log::info!("Relaying between {} and {}", names.0, names.1);
+                entry.insert(Default::default());
+            }
+            transport.set_relay_routes(s.relay_routes());
+            (s.my_participant_id, targets)
+        };
+
+        for (addr, other) in targets {
+            Self::send_reliable_to(transport, my_id, RelayStart { peer_id: other }.to_bytes(), addr);
+        }
+    }
+
+    /// Host: pass a relayed guest's control packet on to its partner. In SFU
+    /// mode any guest may reach any other this way.
+    fn forward_relayed(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        sender: u8,
+        fwd: RelayForward,
+    ) {
+        let target = {
+            let mut s = state.lock().unwrap();
+            let sfu = s.topology == Topology::Sfu;
+            let stats = if sfu {
+                s.peers.get_mut(&fwd.target).map(|p| &mut p.forwarded)
+            } else {
+                s.relay_pairs.get_mut(&relay_pair(sender, fwd.target))
+            };
+            let Some(stats) = stats else {
+                log::debug!("Ignoring RelayForward from {sender} to unrelayed peer {}", fwd.target);
+                return;
+            };
+            stats.packets += 1;
+            stats.bytes += fwd.packet.len() as u64;
+            match s.peers.get(&fwd.target) {
+                Some(peer) if peer.state != PeerState::Disconnected => peer.addr,
+                _ => return,
+            }
+        };
+        if let Err(e) = transport.forward_to(&fwd.packet, target) {
+            log::debug!("Relay forward to {target} failed: {e}");
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_control_guest(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        mixer: &SharedMixer,
+        video_tx: &mpsc::UnboundedSender<InboundEvent>,
+        from: SocketAddr,
+        participant_id: u8,
+        ctrl_type: control::ControlType,
+        payload: &[u8],
+    ) {
         match ctrl_type {
+            control::ControlType::PeerJoined => {
+                let joined = match PeerJoined::from_bytes(payload) {
+                    Some(p) => p,
+                    None => return,
+                };
+                let mut s = state.lock().unwrap();
+                if joined.participant_id == s.my_participant_id {
+                    return;
+                }
+                let known = s
+                    .peers
+                    .get(&joined.participant_id)
+                    .is_some_and(|p| p.state != PeerState::Disconnected);
+                if !known {
+                    log::info!(
+                        "Host announced peer {} (id={}) at {}",
+                        joined.name, joined.participant_id, joined.addr
+                    );
+                    s.add_peer(joined.participant_id, joined.name, joined.addr);
+                    mixer.lock().unwrap().add_source(joined.participant_id);
+                    Self::reset_video(video_tx, joined.participant_id);
+                    Self::reach_guest(&mut s, state, transport, joined.participant_id);
+                }
+            }
+            control::ControlType::RelayStart => {
+                let Some(start) = RelayStart::from_bytes(payload) else { return };
+                let mut s = state.lock().unwrap();
+                if let Some(peer) = s.peers.get_mut(&start.peer_id) {
+                    log::info!("Host is relaying between us and {}", peer.name);
+                    peer.path = PeerPath::Relay;
+                }
+                // The host reaches both of us, so the relayed path works both ways
+                s.set_connected(start.peer_id);
+            }
             control::ControlType::Hello => {
+                // A newly joined guest introducing itself. It may arrive before
+                // the host's PeerJoined, so admit it from the Hello alone.
                 let hello = match Hello::from_bytes(payload) {
                     Some(h) => h,
                     None => return,
                 };
-                log::info!("Received Hello from {} at {from}", hello.name);
+                let mut s = state.lock().unwrap();
+                if participant_id == 0 || participant_id == s.my_participant_id {
+                    return;
+                }
+                let known = s
+                    .peers
+                    .get(&participant_id)
+                    .is_some_and(|p| p.state != PeerState::Disconnected);
+                if !known {
+                    log::info!("Received Hello from peer {} (id={participant_id}) at {from}", hello.name);
+                    s.add_peer(participant_id, hello.name, from);
+                    s.set_capabilities(participant_id, &hello.capabilities);
+                    mixer.lock().unwrap().add_source(participant_id);
+                    Self::reset_video(video_tx, participant_id);
+                    Self::reach_guest(&mut s, state, transport, participant_id);
+                }
+                s.touch_peer(participant_id);
+            }
+            control::ControlType::Moderate => {
+                let Some(moderate) = Moderate::from_bytes(payload) else { return };
+                let mut s = state.lock().unwrap();
+                if participant_id != s.host_id {
+                    return;
+                }
+                log::info!("Host asks us to {:?}", moderate.action);
+                s.host_requests.push(moderate.action);
+            }
+            control::ControlType::ResumeTokens => {
+                let Some(tokens) = ResumeTokens::from_bytes(payload) else { return };
+                let mut s = state.lock().unwrap();
+                if participant_id != s.host_id {
+                    return;
+                }
+                s.accept_resume_tokens(&tokens.tokens);
+            }
+            control::ControlType::HostHandover => {
+                let Some(handover) = HostHandover::from_bytes(payload) else { return };
+                let new_host = handover.new_host_id;
+                let mut s = state.lock().unwrap();
+                // From the leaving host, or from the new one announcing itself
+                if new_host == s.host_id || (participant_id != s.host_id && participant_id != new_host) {
+                    return;
+                }
+                if new_host == s.my_participant_id {
+                    Self::take_over(&mut s, state, transport);
+                } else {
+                    s.follow_host(new_host);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Take in a chat message or a piece of its attachment. The host passes
+    /// each on to the guests its author can't reach directly.
+    fn handle_chat(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        participant_id: u8,
+        ctrl_type: control::ControlType,
+        payload: &[u8],
+    ) {
+        let (my_id, targets) = {
+            let mut s = state.lock().unwrap();
+            // Both carry their author right after the type
+            let Some(&author) = payload.get(1) else { return };
+            // Guests speak for themselves; only the host passes others' messages on
+            let forwarded = participant_id == s.host_id && author != s.my_participant_id;
+            if participant_id != author && !forwarded {
+                return;
+            }
+            let fresh = if ctrl_type == control::ControlType::Chat {
+                let Some(chat) = Chat::from_bytes(payload) else { return };
+                let name = s
+                    .peers
+                    .get(&author)
+                    .map_or_else(|| format!("Participant {author}"), |p| p.name.clone());
+                s.chat.receive(name, &chat)
+            } else {
+                let Some(chunk) = ChatChunk::from_bytes(payload) else { return };
+                s.chat.receive_chunk(&chunk)
+            };
+            if !fresh || s.role != Role::Host {
+                return;
+            }
+            let targets: Vec<SocketAddr> = s
+                .peers
+                .values()
+                .filter(|p| p.participant_id != author && p.state != PeerState::Disconnected)
+                .filter(|p| {
+                    s.topology == Topology::Sfu
+                        || s.relay_pairs.contains_key(&relay_pair(author, p.participant_id))
+                })
+                .map(|p| p.addr)
+                .collect();
+            (s.my_participant_id, targets)
+        };
+        for addr in targets {
+            Self::send_reliable_to(transport, my_id, payload.to_vec(), addr);
+        }
+    }
+
+    /// Guest: take in the host and the other guests from a Welcome. Guests we
+    /// still reach are left as they are; the rest are added afresh and returned.
+    fn admit_welcome(
+        s: &mut SessionState,
+        mixer: &SharedMixer,
+        host_addr: SocketAddr,
+        welcome: &Welcome,
+    ) -> Vec<PeerJoined> {
+        s.topology = if welcome.sfu { Topology::Sfu } else { Topology::Mesh };
+        let host_id = s.host_id;
+        // A guest that took over as host is already known by name
+        let host_name = s.peers.get(&host_id).map_or_else(|| "Host".into(), |p| p.name.clone());
+        s.add_peer(host_id, host_name, host_addr);
+        // The Welcome proves the path to the host both ways
+        s.set_connected(host_id);
+        s.set_capabilities(host_id, &welcome.capabilities);
+        let mut m = mixer.lock().unwrap();
+        m.add_source(host_id);
+        let mut added = Vec::new();
+        for peer in &welcome.peers {
+            let known = s
+                .peers
+                .get(&peer.participant_id)
+                .is_some_and(|p| p.state != PeerState::Disconnected);
+            if known {
+                continue;
+            }
+            s.add_peer(peer.participant_id, peer.name.clone(), peer.addr);
+            // ...and in SFU mode the host is our path to everyone
+            if welcome.sfu {
+                s.set_connected(peer.participant_id);
+            }
+            m.add_source(peer.participant_id);
+            added.push(peer.clone());
+        }
+        added
+    }
+
+    /// What this build sends and receives, announced in Hello and Welcome.
+    fn local_capabilities() -> Capabilities {
+        let (max_width, max_height) = ladder::max_resolution();
+        Capabilities {
+            opus_frame_sizes: vec![OPUS_FRAME_SAMPLES as u16],
+            video_codecs: VIDEO_CODEC_VP8,
+            max_width: max_width as u16,
+            max_height: max_height as u16,
+        }
+    }
 
-                let (session_id, assigned_id, my_id) = {
+    /// Host: why a guest announcing these capabilities can't take part, if
+    /// it can't. Everyone sends our Opus frame size and VP8; a smaller
+    /// picture is fine, it just caps the layers the guest gets.
+    fn incompatibility(theirs: &Capabilities) -> Option<RejectReason> {
+        if !theirs.opus_frame_sizes.contains(&(OPUS_FRAME_SAMPLES as u16)) {
+            Some(RejectReason::AudioUnsupported)
+        } else if theirs.video_codecs & VIDEO_CODEC_VP8 == 0 {
+            Some(RejectReason::VideoUnsupported)
+        } else {
+            None
+        }
+    }
+
+    /// Host: answer a Hello with the reason we won't admit it.
+    fn reject(transport: &Arc<dyn Transport>, my_id: u8, reason: RejectReason, addr: SocketAddr) {
+        let payload = Reject {
+            reason: reason as u8,
+            protocol_version: PROTOCOL_VERSION,
+        }
+        .to_bytes();
+        Self::send_handshake_control(transport, my_id, payload, addr);
+    }
+
+    /// Host: answer a Hello in the clear, as the guest has no session key yet.
+    fn send_handshake_control(transport: &Arc<dyn Transport>, my_id: u8, payload: Vec<u8>, addr: SocketAddr) {
+        let header = PacketHeader::new(
+            PacketType::Control,
+            my_id,
+            0,
+            0,
+            payload.len() as u16,
+        );
+        let packet = Packet::new(header, payload).to_bytes();
+        if let Err(e) = transport.send_handshake_to(&packet, addr) {
+            log::warn!("Handshake reply to {addr} failed: {e}");
+        }
+    }
+
+    /// Guest: send our Hello to other guests, so they can admit us even
+    /// before the host's PeerJoined arrives.
+    fn introduce(transport: &Arc<dyn Transport>, my_id: u8, name: &str, peers: &[PeerJoined]) {
+        let intro_payload = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            name: name.to_string(),
+            capabilities: Self::local_capabilities(),
+            public_key: None,
+            passphrase_proof: None,
+            resume_token: None,
+        }
+        .to_bytes();
+        let header = PacketHeader::new(
+            PacketType::Control,
+            my_id,
+            0,
+            0,
+            intro_payload.len() as u16,
+        );
+        let intro = Packet::new(header, intro_payload).to_bytes();
+        for peer in peers {
+            let addr = peer.addr;
+            if let Err(e) = transport.send_to(&intro, addr) {
+                log::warn!("Failed to send Hello to peer {} at {addr}: {e}", peer.name);
+            }
+        }
+    }
+
+    /// Guest: take our place in the call again from the Welcome answering a
+    /// resume Hello (sent by the heartbeat after the host timed out or
+    /// handed over).
+    #[allow(clippy::too_many_arguments)]
+    fn resume(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        mixer: &SharedMixer,
+        video_tx: &mpsc::UnboundedSender<InboundEvent>,
+        from: SocketAddr,
+        host_id: u8,
+        welcome: Welcome,
+    ) {
+        let (my_id, name, added) = {
+            let mut s = state.lock().unwrap();
+            if host_id != s.host_id {
+                return;
+            }
+            let Some(exchange) = s.resume_exchange.take() else { return };
+            // Only our own session's host can hand us our session key
+            let key = exchange
+                .unwrap(&welcome.host_public_key, &welcome.wrapped_session_key, &s.passphrase)
+                .ok();
+            if welcome.session_id != s.session_id || key.is_none() || key != s.session_key {
+                log::warn!("Ignoring Welcome from {from}: not from our session");
+                return;
+            }
+            if welcome.assigned_participant_id == s.my_participant_id {
+                log::info!("Resumed the session as participant {}", s.my_participant_id);
+            } else {
+                log::warn!(
+                    "Rejoined the session as participant {} (was {})",
+                    welcome.assigned_participant_id,
+                    s.my_participant_id
+                );
+            }
+            s.my_participant_id = welcome.assigned_participant_id;
+            transport.set_participant_id(s.my_participant_id);
+            let added = Self::admit_welcome(&mut s, mixer, from, &welcome);
+            (s.my_participant_id, s.my_name.clone(), added)
+        };
+
+        Self::reset_video(video_tx, host_id);
+        for peer in &added {
+            Self::reset_video(video_tx, peer.participant_id);
+        }
+        if !welcome.sfu {
+            Self::introduce(transport, my_id, &name, &added);
+            for peer in &added {
+                Self::spawn_hole_punch(state.clone(), transport.clone(), peer.participant_id);
+            }
+        }
+        Self::spawn_clock_sync(state.clone(), transport.clone(), host_id);
+    }
+
+    /// Guest: become the host, and find out where new guests should now
+    /// reach us. The heartbeat announces the handover.
+    fn take_over(s: &mut SessionState, state: &SharedSessionState, transport: &Arc<dyn Transport>) {
+        s.become_host();
+        transport.set_relay_routes(s.relay_routes());
+        Self::spawn_public_addr_discovery(state.clone(), transport.clone());
+    }
+
+    /// Tell the video pipeline to drop a peer's decoder state: it left, or is
+    /// being admitted again and must start over from a keyframe.
+    fn reset_video(video_tx: &mpsc::UnboundedSender<InboundEvent>, participant_id: u8) {
+        let _ = video_tx.send(InboundEvent::Bye { participant_id });
+    }
+
+    /// Guest: set up the path to a newly added guest. In SFU mode the host
+    /// already carries everything between us; in a mesh we punch through.
+    fn reach_guest(
+        s: &mut SessionState,
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        participant_id: u8,
+    ) {
+        match s.topology {
+            Topology::Sfu => s.set_connected(participant_id),
+            Topology::Mesh => Self::spawn_hole_punch(state.clone(), transport.clone(), participant_id),
+        }
+    }
+
+    /// Punch a hole through both NATs to another guest: send authenticated
+    /// PunchChecks to its host-observed address until one is acked (see
+    /// `handle_control`), while the peer does the same towards us. If no check
+    /// completes, the pair falls back to the host relay.
+    fn spawn_hole_punch(state: SharedSessionState, transport: Arc<dyn Transport>, participant_id: u8) {
+        tokio::spawn(async move {
+            let token = crypto::random_u32();
+            for _ in 0..PUNCH_ATTEMPTS {
+                let (my_id, addr, token) = {
                     let mut s = state.lock().unwrap();
-                    let assigned_id = s.assign_participant_id();
-                    s.add_peer(assigned_id, hello.name.clone(), from);
-                    s.touch_peer(assigned_id);
-                    (s.session_id, assigned_id, s.my_participant_id)
+                    let my_id = s.my_participant_id;
+                    match s.peers.get_mut(&participant_id) {
+                        Some(peer) if peer.state == PeerState::Connecting => {
+                            let token = *peer.punch_token.get_or_insert(token);
+                            (my_id, peer.addr, token)
+                        }
+                        _ => return,
+                    }
                 };
+                Self::send_control_to(&transport, my_id, PunchCheck { token }.to_bytes(), addr);
+                tokio::time::sleep(PUNCH_INTERVAL).await;
+            }
+
+            let (my_id, host_addr) = {
+                let mut s = state.lock().unwrap();
+                let Some(peer) = s.peers.get_mut(&participant_id) else { return };
+                if peer.state != PeerState::Connecting {
+                    return;
+                }
+                log::warn!(
+                    "Hole punching to {} ({participant_id}) at {} failed; asking the host to relay",
+                    peer.name,
+                    peer.addr
+                );
+                peer.path = PeerPath::Relay;
+                // Give the relay until the usual timeout to start carrying media
+                peer.last_seen = std::time::Instant::now();
+                let Some(host) = s.peers.get(&s.host_id) else { return };
+                (s.my_participant_id, host.addr)
+            };
+            let req = RelayRequest {
+                peer_id: participant_id,
+            };
+            Self::send_reliable_to(&transport, my_id, req.to_bytes(), host_addr);
+        });
+    }
+
+    /// Guest in SFU mode: tell the host whose video we don't want forwarded.
+    pub fn send_video_subscription(state: &SharedSessionState, transport: &Arc<dyn Transport>) {
+        let (my_id, host_addr, payload) = {
+            let s = state.lock().unwrap();
+            let Some(host) = s.peers.get(&s.host_id) else { return };
+            (s.my_participant_id, host.addr, Self::video_subscription(&s))
+        };
+        Self::send_reliable_to(transport, my_id, payload, host_addr);
+    }
+
+    /// Host: ask a guest to mute or turn its camera off, or remove it from the call.
+    pub fn moderate(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        mixer: &SharedMixer,
+        participant_id: u8,
+        action: ModerationAction,
+    ) {
+        let (my_id, addr) = {
+            let mut s = state.lock().unwrap();
+            let addr = if action == ModerationAction::Kick {
+                let addr = s.kick(participant_id);
+                mixer.lock().unwrap().remove_source(participant_id);
+                // Stop forwarding to and from it, even if it was our last relayed pair
+                transport.set_relay_routes(s.relay_routes());
+                addr
+            } else {
+                s.peers.get(&participant_id).map(|p| p.addr)
+            };
+            let Some(addr) = addr else { return };
+            (s.my_participant_id, addr)
+        };
+        Self::send_reliable_to(transport, my_id, Moderate { action }.to_bytes(), addr);
+    }
+
+    /// Post a chat message, with a file if given, to everyone we reach
+    /// directly; the host passes it on to the rest.
+    pub fn send_chat(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        text: &str,
+        attachment: Option<(String, Vec<u8>)>,
+    ) {
+        let (my_id, targets, chat, chunks) = {
+            let mut s = state.lock().unwrap();
+            let (my_id, name) = (s.my_participant_id, s.my_name.clone());
+            let (chat, chunks) = s.chat.post(my_id, name, text, attachment);
+            let targets: Vec<SocketAddr> = s
+                .peers
+                .values()
+                .filter(|p| p.state != PeerState::Disconnected && p.path == PeerPath::Direct)
+                .map(|p| p.addr)
+                .collect();
+            (my_id, targets, chat.to_bytes(), chunks)
+        };
+        for addr in targets {
+            Self::send_reliable_to(transport, my_id, chat.clone(), addr);
+            for chunk in &chunks {
+                Self::send_reliable_to(transport, my_id, chunk.to_bytes(), addr);
+            }
+        }
+    }
 
-                let welcome = Welcome {
-                    session_id,
-                    assigned_participant_id: assigned_id,
+    fn video_subscription(s: &SessionState) -> Vec<u8> {
+        let mut hidden: Vec<u8> = s.hidden_video.iter().copied().collect();
+        hidden.sort_unstable();
+        VideoSubscription { hidden }.to_bytes()
+    }
+
+    /// Send one control payload to `addr`.
+    fn send_control_to(transport: &Arc<dyn Transport>, my_id: u8, payload: Vec<u8>, addr: SocketAddr) {
+        let header = PacketHeader::new(PacketType::Control, my_id, 0, 0, payload.len() as u16);
+        let packet = Packet::new(header, payload).to_bytes();
+        if let Err(e) = transport.send_to(&packet, addr) {
+            log::debug!("Control send failed to {addr}: {e}");
+        }
+    }
+
+    /// Send one control payload to `addr`, retransmitted until acked.
+    fn send_reliable_to(transport: &Arc<dyn Transport>, my_id: u8, payload: Vec<u8>, addr: SocketAddr) {
+        if let Err(e) = transport.send_reliable(my_id, payload, addr) {
+            log::debug!("Reliable control send failed to {addr}: {e}");
+        }
+    }
+
+    /// Run the NTP-style sync exchange with a peer: SYNC_ROUNDS timestamped
+    /// requests, spaced out. Replies are folded into the peer's `clock` by
+    /// `handle_control` as they arrive.
+    fn spawn_clock_sync(state: SharedSessionState, transport: Arc<dyn Transport>, participant_id: u8) {
+        tokio::spawn(async move {
+            for _ in 0..SYNC_ROUNDS {
+                let (my_id, addr, t1) = {
+                    let s = state.lock().unwrap();
+                    match s.peers.get(&participant_id) {
+                        Some(peer) if peer.state != PeerState::Disconnected => {
+                            (s.my_participant_id, peer.addr, s.clock_us())
+                        }
+                        _ => return,
+                    }
                 };
-                let welcome_payload = welcome.to_bytes();
+
+                let req_payload = SyncRequest { t1 }.to_bytes();
                 let header = PacketHeader::new(
                     PacketType::Control,
                     my_id,
                     0,
                     0,
-                    welcome_payload.len() as u16,
+                    req_payload.len() as u16,
                 );
-                let packet = Packet::new(header, welcome_payload).to_bytes();
-                if let Err(e) = transport.send_to(&packet, from).await {
-                    log::warn!("Failed to send Welcome to {from}: {e}");
+                let packet = Packet::new(header, req_payload).to_bytes();
+                if let Err(e) = transport.send_to(&packet, addr) {
+                    log::debug!("SyncRequest send failed to {addr}: {e}");
                 }
-                log::info!("Sent Welcome to {} (id={})", hello.name, assigned_id);
+
+                tokio::time::sleep(SYNC_SPACING).await;
             }
-            control::ControlType::Heartbeat => {
-                let mut s = state.lock().unwrap();
-                let peer_id = s
-                    .peers
-                    .values()
-                    .find(|p| p.addr == from)
-                    .map(|p| p.participant_id);
-                if let Some(id) = peer_id {
-                    s.touch_peer(id);
-                }
+
+            let s = state.lock().unwrap();
+            match s.peers.get(&participant_id).and_then(|p| p.clock) {
+                Some(clock) => log::info!(
+                    "Clock offset to peer {participant_id}: {:.3} ms ± {:.3} ms ({} of {SYNC_ROUNDS} rounds)",
+                    clock.offset_ms(),
+                    clock.uncertainty_ms(),
+                    clock.samples
+                ),
+                None => log::warn!("Clock sync with peer {participant_id} got no replies"),
             }
-            _ => {}
-        }
+        });
+    }
+
+    /// Find our public address (from the STUN server `stun::STUN_SERVER_ENV`
+    /// names, on a real network) and store it in the session state for the
+    /// host screen.
+    fn spawn_public_addr_discovery(state: SharedSessionState, transport: Arc<dyn Transport>) {
+        tokio::spawn(async move {
+            if let Some(addr) = transport.public_addr().await {
+                state.lock().unwrap().public_addr = Some(addr);
+            }
+        });
     }
 
     fn start_heartbeat(
         state: SharedSessionState,
-        transport: Arc<UdpTransport>,
+        transport: Arc<dyn Transport>,
+        mixer: SharedMixer,
         mut stop_rx: tokio::sync::watch::Receiver<bool>,
     ) {
         tokio::spawn(async move {
@@ -359,13 +1507,130 @@ impl NetworkManager {
                 tokio::select! {
                     _ = stop_rx.changed() => break,
                     _ = interval.tick() => {
-                        let (my_id, ts, peer_addrs) = {
+                        let (my_id, ts, ts_us, peer_addrs, reports, selection, subscription, resume_hello, handover, tokens) = {
                             let mut s = state.lock().unwrap();
-                            let _timed_out = s.check_timeouts();
+                            let timed_out = s.check_timeouts();
+                            if !timed_out.is_empty() {
+                                let mut m = mixer.lock().unwrap();
+                                for id in timed_out {
+                                    m.remove_source(id);
+                                }
+                            }
+                            // The host vanished without handing over: its successor
+                            // takes over, and the rest of us follow
+                            if let Some(new_host) = s.elect_host() {
+                                log::warn!("Lost the host without a handover");
+                                if new_host == s.my_participant_id {
+                                    Self::take_over(&mut s, &state, &transport);
+                                } else {
+                                    s.follow_host(new_host);
+                                }
+                            }
+                            let handover = s.handover_targets();
+                            let tokens = s.resume_tokens_to_share();
+                            // Lost the host: ask it to take us back until it
+                            // answers or the resume window closes
+                            let resume_hello = if s.resuming() {
+                                let public_key = s
+                                    .resume_exchange
+                                    .get_or_insert_with(KeyExchange::new)
+                                    .public_key();
+                                let hello = Hello {
+                                    protocol_version: PROTOCOL_VERSION,
+                                    name: s.my_name.clone(),
+                                    capabilities: Self::local_capabilities(),
+                                    public_key: Some(public_key),
+                                    passphrase_proof: Some(crypto::passphrase_proof(
+                                        &s.passphrase,
+                                        &public_key,
+                                    )),
+                                    resume_token: s.resume_token,
+                                };
+                                s.peers
+                                    .get(&s.host_id)
+                                    .map(|host| (host.addr, hello.to_bytes()))
+                            } else {
+                                None
+                            };
+                            if s.is_relaying() {
+                                transport.set_relay_routes(s.relay_routes());
+                            }
+                            // One stats interval per heartbeat: tell each direct peer
+                            // how its streams arrived, adapt our own video rate, and
+                            // pick the simulcast layers we want from everyone
+                            let reports: Vec<(SocketAddr, ReceiverReport)> = s
+                                .peers
+                                .values_mut()
+                                .filter(|p| p.state != PeerState::Disconnected)
+                                .filter_map(|p| {
+                                    let report = p.stats.roll_interval();
+                                    (p.path == PeerPath::Direct).then_some((p.addr, report))
+                                })
+                                .collect();
+                            s.update_bandwidth_estimate();
+                            s.update_layer_caps();
+                            let selection = s.layer_selection().to_bytes();
                             let addrs = s.connected_peer_addrs();
-                            (s.my_participant_id, s.elapsed_ms(), addrs)
+                            // The estimate budgets one copy of our video; each
+                            // receiver gets at most that
+                            transport.set_video_pacing_kbps(
+                                s.bwe.target_kbps() * addrs.len().max(1) as u32,
+                            );
+                            // SFU guests restate their video selection, since
+                            // control messages can be lost
+                            let subscription = match (s.role, s.topology) {
+                                (Role::Guest, Topology::Sfu) => s
+                                    .peers
+                                    .get(&s.host_id)
+                                    .map(|host| (host.addr, Self::video_subscription(&s))),
+                                _ => None,
+                            };
+                            (
+                                s.my_participant_id,
+                                s.elapsed_ms(),
+                                s.elapsed_us(),
+                                addrs,
+                                reports,
+                                selection,
+                                subscription,
+                                resume_hello,
+                                handover,
+                                tokens,
+                            )
                         };
 
+                        // Just took over as host: tell everyone where to find us
+                        if !handover.is_empty() {
+                            let payload = HostHandover { new_host_id: my_id }.to_bytes();
+                            for addr in handover {
+                                Self::send_control_to(&transport, my_id, payload.clone(), addr);
+                            }
+                        }
+
+                        // Our successor needs the guests' resume tokens
+                        // before we go, in case we vanish without a handover
+                        if let Some((addr, tokens)) = tokens {
+                            if let Err(e) = transport.send_reliable(my_id, tokens.to_bytes(), addr) {
+                                log::debug!("Resume tokens send failed to {addr}: {e}");
+                            }
+                        }
+
+                        if let Some((host_addr, hello_payload)) = resume_hello {
+                            // The handshake goes in the clear, as when joining
+                            let header = PacketHeader::new(
+                                PacketType::Control,
+                                0,
+                                0,
+                                0,
+                                hello_payload.len() as u16,
+                            );
+                            let packet = Packet::new(header, hello_payload).to_bytes();
+                            log::debug!("Asking the host at {host_addr} to resume our session");
+                            if let Err(e) = transport.send_handshake_to(&packet, host_addr) {
+                                log::debug!("Resume Hello send failed to {host_addr}: {e}");
+                            }
+                        }
+
                         let hb_payload = Heartbeat.to_bytes();
                         let header = PacketHeader::new(
                             PacketType::Control,
@@ -376,10 +1641,65 @@ impl NetworkManager {
                         );
                         let packet = Packet::new(header, hb_payload).to_bytes();
 
-                        for addr in peer_addrs {
-                            if let Err(e) = transport.send_to(&packet, addr).await {
+                        // Ping alongside every heartbeat for a running RTT estimate
+                        let ping_payload = Ping { timestamp_us: ts_us }.to_bytes();
+                        let header = PacketHeader::new(
+                            PacketType::Control,
+                            my_id,
+                            0,
+                            ts,
+                            ping_payload.len() as u16,
+                        );
+                        let ping_packet = Packet::new(header, ping_payload).to_bytes();
+
+                        let header = PacketHeader::new(
+                            PacketType::Control,
+                            my_id,
+                            0,
+                            ts,
+                            selection.len() as u16,
+                        );
+                        let selection_packet = Packet::new(header, selection).to_bytes();
+
+                        for addr in &peer_addrs {
+                            if let Err(e) = transport.send_to(&packet, *addr) {
                                 log::debug!("Heartbeat send failed to {addr}: {e}");
                             }
+                            if let Err(e) = transport.send_to(&ping_packet, *addr) {
+                                log::debug!("Ping send failed to {addr}: {e}");
+                            }
+                            if let Err(e) = transport.send_to(&selection_packet, *addr) {
+                                log::debug!("LayerSelection send failed to {addr}: {e}");
+                            }
+                        }
+
+                        if let Some((host_addr, payload)) = subscription {
+                            Self::send_control_to(&transport, my_id, payload, host_addr);
+                        }
+
+                        let mut report_sizes = Vec::with_capacity(reports.len());
+                        for (addr, report) in reports {
+                            let report_payload = report.to_bytes();
+                            let header = PacketHeader::new(
+                                PacketType::Control,
+                                my_id,
+                                0,
+                                ts,
+                                report_payload.len() as u16,
+                            );
+                            let report_packet = Packet::new(header, report_payload).to_bytes();
+                            if let Err(e) = transport.send_to(&report_packet, addr) {
+                                log::debug!("ReceiverReport send failed to {addr}: {e}");
+                            }
+                            report_sizes.push((addr, report_packet.len()));
+                        }
+
+                        let mut s = state.lock().unwrap();
+                        s.record_sent(&peer_addrs, packet.len());
+                        s.record_sent(&peer_addrs, ping_packet.len());
+                        s.record_sent(&peer_addrs, selection_packet.len());
+                        for (addr, size) in report_sizes {
+                            s.record_sent(&[addr], size);
                         }
                     }
                 }
@@ -0,0 +1,273 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+use super::socket::{Datagram, PacketTransport};
+
+/// Packets picked for reordering arrive this much later than they would
+/// have, so the ones sent after them overtake.
+const REORDER_DELAY: Duration = Duration::from_millis(10);
+/// On a link with a bandwidth cap, packets that would wait longer than this
+/// to go out are dropped, as a router's full queue would.
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(200);
+
+/// A transport on a `MemoryNetwork` instead of a UDP socket.
+pub type MemoryTransport = PacketTransport<MemorySocket>;
+
+/// What happens to packets between any two nodes of a `MemoryNetwork`.
+/// Each direction of each pair is a separate link. The default is a perfect
+/// link: no loss, no delay, no cap.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConditions {
+    /// Fraction of packets dropped, 0.0 to 1.0.
+    pub loss: f64,
+    /// One-way delay.
+    pub delay: Duration,
+    /// Extra delay of up to this much, random per packet. Packets still
+    /// arrive in order unless picked for reordering.
+    pub jitter: Duration,
+    /// Fraction of packets held back so later ones overtake them.
+    pub reorder: f64,
+    /// Fraction of packets delivered twice.
+    pub duplicate: f64,
+    /// Link rate; packets queue behind one another to go out.
+    pub bandwidth_kbps: Option<u32>,
+}
+
+/// SplitMix64: small, and the same sequence for a given seed on every platform.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    /// Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+}
+
+/// A packet on its way, ordered by arrival time and then by when it was sent.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct InFlight {
+    arrive_at: Instant,
+    order: u64,
+    to: SocketAddr,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+/// Per-direction link state, by (from, to).
+#[derive(Debug, Default)]
+struct Link {
+    /// When the last queued packet has gone out, with a bandwidth cap.
+    busy_until: Option<Instant>,
+    /// Latest arrival so far, which in-order packets may not beat.
+    last_arrival: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct NetworkState {
+    conditions: LinkConditions,
+    rng: Rng,
+    nodes: HashMap<SocketAddr, mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>>,
+    links: HashMap<(SocketAddr, SocketAddr), Link>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    sent: u64,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<NetworkState>,
+    /// Wakes the delivery task when a packet is sent, or the network is gone.
+    wake: Arc<Notify>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.wake.notify_one();
+    }
+}
+
+/// A network that lives in this process, for running whole sessions (a host
+/// and its guests, each with its own `MemoryTransport`) without real sockets.
+///
+/// Every link loses, delays, reorders, duplicates and rate-limits packets as
+/// its `LinkConditions` say. The random choices come from a seeded generator,
+/// so a run's conditions can be reproduced. Nodes have no NAT between them:
+/// each one's public address is the address it was bound on.
+#[derive(Debug, Clone)]
+pub struct MemoryNetwork {
+    shared: Arc<Shared>,
+}
+
+impl MemoryNetwork {
+    /// A network whose links all have `conditions`. Must be called from
+    /// within the tokio runtime.
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        let wake = Arc::new(Notify::new());
+        let shared = Arc::new(Shared {
+            state: Mutex::new(NetworkState {
+                conditions,
+                rng: Rng(seed),
+                nodes: HashMap::new(),
+                links: HashMap::new(),
+                in_flight: BinaryHeap::new(),
+                sent: 0,
+            }),
+            wake: wake.clone(),
+        });
+        Self::spawn_delivery_task(Arc::downgrade(&shared), wake);
+        Self { shared }
+    }
+
+    /// Add a node at `addr` and return its transport.
+    pub fn bind(&self, addr: SocketAddr) -> Result<MemoryTransport, String> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.nodes.contains_key(&addr) {
+                return Err(format!("{addr} is already in use on the simulated network"));
+            }
+            state.nodes.insert(addr, tx);
+        }
+        log::info!("Simulated socket bound on {addr}");
+        let socket = MemorySocket {
+            addr,
+            network: self.clone(),
+            inbox: tokio::sync::Mutex::new(rx),
+        };
+        Ok(PacketTransport::new(socket, false))
+    }
+
+    /// Change every link's conditions, for packets sent from now on.
+    #[cfg(test)]
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.shared.state.lock().unwrap().conditions = conditions;
+    }
+
+    /// Put a packet on the link from `from` to `to`, or lose it.
+    fn transmit(&self, from: SocketAddr, to: SocketAddr, data: &[u8]) {
+        let now = Instant::now();
+        let mut guard = self.shared.state.lock().unwrap();
+        let state = &mut *guard;
+        if !state.nodes.contains_key(&to) || state.rng.chance(state.conditions.loss) {
+            return;
+        }
+        let link = state.links.entry((from, to)).or_default();
+
+        // Serialise onto a capped link, behind whatever is still queued
+        let mut sent_at = now;
+        if let Some(kbps) = state.conditions.bandwidth_kbps {
+            let start = link.busy_until.map_or(now, |t| t.max(now));
+            if start - now > MAX_QUEUE_DELAY {
+                return;
+            }
+            let on_wire = Duration::from_secs_f64(data.len() as f64 * 8.0 / (kbps.max(1) as f64 * 1000.0));
+            sent_at = start + on_wire;
+            link.busy_until = Some(sent_at);
+        }
+
+        let jitter = state.conditions.jitter.mul_f64(state.rng.next_f64());
+        let mut arrive_at = sent_at + state.conditions.delay + jitter;
+        if state.rng.chance(state.conditions.reorder) {
+            arrive_at += REORDER_DELAY;
+        } else {
+            arrive_at = link.last_arrival.map_or(arrive_at, |t| t.max(arrive_at));
+            link.last_arrival = Some(arrive_at);
+        }
+
+        let copies = if state.rng.chance(state.conditions.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            state.sent += 1;
+            state.in_flight.push(Reverse(InFlight {
+                arrive_at,
+                order: state.sent,
+                to,
+                from,
+                data: data.to_vec(),
+            }));
+        }
+        drop(guard);
+        self.shared.wake.notify_one();
+    }
+
+    /// Hand packets to their receivers as they arrive, until the network and
+    /// every socket on it are dropped.
+    fn spawn_delivery_task(shared: Weak<Shared>, wake: Arc<Notify>) {
+        tokio::spawn(async move {
+            loop {
+                let next = {
+                    let Some(shared) = shared.upgrade() else { break };
+                    let mut state = shared.state.lock().unwrap();
+                    let now = Instant::now();
+                    while state.in_flight.peek().is_some_and(|p| p.0.arrive_at <= now) {
+                        let Some(Reverse(packet)) = state.in_flight.pop() else { break };
+                        if let Some(inbox) = state.nodes.get(&packet.to) {
+                            let _ = inbox.send((packet.data, packet.from));
+                        }
+                    }
+                    state.in_flight.peek().map(|p| p.0.arrive_at)
+                };
+                match next {
+                    Some(at) => {
+                        tokio::select! {
+                            _ = tokio::time::sleep_until(at) => {}
+                            _ = wake.notified() => {}
+                        }
+                    }
+                    None => wake.notified().await,
+                }
+            }
+        });
+    }
+}
+
+/// One node's socket on a `MemoryNetwork`.
+pub struct MemorySocket {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Datagram for MemorySocket {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send {
+        self.network.transmit(self.addr, target, buf);
+        std::future::ready(Ok(buf.len()))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.inbox.lock().await.recv().await {
+            Some((data, from)) => {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok((len, from))
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "Simulated network is gone")),
+        }
+    }
+
+    fn known_public_addr(&self) -> Option<SocketAddr> {
+        Some(self.addr)
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        self.network.shared.state.lock().unwrap().nodes.remove(&self.addr);
+    }
+}
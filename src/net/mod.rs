@@ -1,7 +1,18 @@
 pub mod protocol;
 pub mod control;
+pub mod chat;
+pub mod reliable;
+pub mod clock;
+pub mod crypto;
+pub mod congestion;
+pub mod sender;
 pub mod socket;
+pub mod transport;
+pub mod memory;
+pub mod stun;
 pub mod session;
+pub mod stats;
 pub mod upnp;
 
 pub mod manager;
+pub mod simulate;
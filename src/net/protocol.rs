@@ -1,7 +1,7 @@
 /// 12-byte packet header, big-endian on the wire.
 ///
 /// Wire layout:
-///   byte 0:       version (2 bits) | padding (1 bit) | type (5 bits)
+///   byte 0:       version (2 bits) | encrypted (1 bit) | layer (2 bits) | type (3 bits)
 ///   byte 1:       participant_id (u8)
 ///   bytes 2..4:   sequence number (u16 big-endian)
 ///   bytes 4..8:   timestamp_ms (u32 big-endian)
@@ -10,7 +10,13 @@
 ///   byte 11:      fragment_total (u8)
 
 pub const HEADER_SIZE: usize = 12;
-pub const PROTOCOL_VERSION: u8 = 0; // 2-bit version field
+/// Bumped whenever the wire format changes. Also sent in full in Hello,
+/// since the 2-bit header field wraps.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Simulcast layers a sender encodes, lowest resolution first
+/// (the 2-bit header field has room for four).
+pub const VIDEO_LAYERS: usize = 3;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -38,6 +44,10 @@ impl PacketType {
 #[derive(Debug, Clone)]
 pub struct PacketHeader {
     pub version: u8,
+    /// Payload is sealed with the session key (see `crypto::PacketCipher`).
+    pub encrypted: bool,
+    /// Simulcast layer of a video packet; 0 for everything else.
+    pub layer: u8,
     pub packet_type: PacketType,
     pub participant_id: u8,
     pub sequence: u16,
@@ -51,6 +61,8 @@ impl PacketHeader {
     pub fn new(packet_type: PacketType, participant_id: u8, sequence: u16, timestamp_ms: u32, payload_length: u16) -> Self {
         Self {
             version: PROTOCOL_VERSION,
+            encrypted: false,
+            layer: 0,
             packet_type,
             participant_id,
             sequence,
@@ -63,8 +75,11 @@ impl PacketHeader {
 
     pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
         let mut buf = [0u8; HEADER_SIZE];
-        // byte 0: version(2) | padding(1) | type(5)
-        buf[0] = ((self.version & 0x03) << 6) | (self.packet_type as u8 & 0x1F);
+        // byte 0: version(2) | encrypted(1) | layer(2) | type(3)
+        buf[0] = ((self.version & 0x03) << 6)
+            | ((self.encrypted as u8) << 5)
+            | ((self.layer & 0x03) << 3)
+            | (self.packet_type as u8 & 0x07);
         buf[1] = self.participant_id;
         buf[2..4].copy_from_slice(&self.sequence.to_be_bytes());
         buf[4..8].copy_from_slice(&self.timestamp_ms.to_be_bytes());
@@ -79,7 +94,9 @@ impl PacketHeader {
             return None;
         }
         let version = (buf[0] >> 6) & 0x03;
-        let type_val = buf[0] & 0x1F;
+        let encrypted = buf[0] & 0x20 != 0;
+        let layer = (buf[0] >> 3) & 0x03;
+        let type_val = buf[0] & 0x07;
         let packet_type = PacketType::from_u8(type_val)?;
         let participant_id = buf[1];
         let sequence = u16::from_be_bytes([buf[2], buf[3]]);
@@ -90,6 +107,8 @@ impl PacketHeader {
 
         Some(Self {
             version,
+            encrypted,
+            layer,
             packet_type,
             participant_id,
             sequence,
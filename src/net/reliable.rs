@@ -0,0 +1,426 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::control::{Ack, Reliable};
+use super::crypto;
+use super::protocol::{Packet, PacketHeader, PacketType};
+
+/// First retransmission timeout, doubling per attempt up to MAX_RTO.
+const INITIAL_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(2);
+/// Sends of one message before giving up on its receiver (about 15 s).
+const MAX_ATTEMPTS: u32 = 10;
+/// Messages held per sender while waiting for an earlier one to arrive.
+const MAX_HELD: usize = 256;
+/// Messages per stream sent but not yet acked. Later ones wait their turn,
+/// so a burst (a chat attachment's chunks) goes out a window at a time, only
+/// the window is ever retransmitted, and the receiver never has to hold more
+/// than it can.
+const SEND_WINDOW: usize = 32;
+
+/// How often the transport looks for messages due to be sent again.
+pub const RETRANSMIT_TICK: Duration = Duration::from_millis(50);
+
+/// A message sent but not yet acked.
+#[derive(Debug)]
+struct Pending {
+    sequence: u16,
+    /// The whole packet, unsealed: it is sealed afresh on every send.
+    packet: Vec<u8>,
+    /// Sends so far; 0 while waiting outside the window.
+    attempts: u32,
+    rto: Duration,
+    resend_at: Instant,
+}
+
+#[derive(Debug)]
+struct SendStream {
+    stream: u16,
+    next_sequence: u16,
+    pending: VecDeque<Pending>,
+}
+
+impl SendStream {
+    fn new() -> Self {
+        Self {
+            stream: crypto::random_u32() as u16,
+            next_sequence: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Mark the messages the window has room for as sent, and return them.
+    fn open_window(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        self.pending
+            .iter_mut()
+            .take(SEND_WINDOW)
+            .filter(|p| p.attempts == 0)
+            .map(|p| {
+                p.attempts = 1;
+                p.resend_at = now + INITIAL_RTO;
+                p.packet.clone()
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct RecvStream {
+    stream: u16,
+    next_sequence: u16,
+    /// Arrived ahead of `next_sequence`, by sequence.
+    held: HashMap<u16, Vec<u8>>,
+    /// The stream this one replaced, whose stragglers are ignored.
+    previous: Option<u16>,
+}
+
+impl RecvStream {
+    fn new(stream: u16, previous: Option<u16>) -> Self {
+        Self {
+            stream,
+            next_sequence: 0,
+            held: HashMap::new(),
+            previous,
+        }
+    }
+}
+
+/// A packet to send: again, or for the first time once the window has room.
+#[derive(Debug)]
+pub struct Retransmit {
+    pub addr: SocketAddr,
+    pub packet: Vec<u8>,
+    pub handshake: bool,
+}
+
+/// Messages dropped, sent or still queued, when their receiver was given up on.
+#[derive(Debug)]
+pub struct Abandoned {
+    pub addr: SocketAddr,
+    /// The control payloads, oldest first.
+    pub messages: Vec<Vec<u8>>,
+}
+
+/// Reliable, ordered delivery for control messages that must arrive, under
+/// the otherwise fire-and-forget control channel.
+///
+/// Each message goes out wrapped in a `Reliable` with the next sequence number
+/// of a stream to its receiver's address, and is sent again with exponential
+/// backoff until an `Ack` comes back. At most SEND_WINDOW messages per stream
+/// are out unacked; the rest queue behind them. The receiver acks every copy,
+/// passes each message on once and in order, and holds any that overtake an
+/// earlier one. Streams start at a random ID, so a sender that restarts or gives up on
+/// a message opens a fresh stream the receiver follows from sequence 0.
+///
+/// Handshake messages sent in the clear (the Welcome) have a stream of their
+/// own: anyone can forge those, so receivers ack and pass them on without
+/// letting them touch the sealed stream's state.
+///
+/// Media, and control messages that are repeated anyway (heartbeats, pings,
+/// receiver reports) or are useless late (NACKs), stay unreliable.
+#[derive(Debug, Default)]
+pub struct ReliableChannels {
+    /// By receiver address, and whether the stream goes in the clear.
+    send: HashMap<(SocketAddr, bool), SendStream>,
+    recv: HashMap<SocketAddr, RecvStream>,
+}
+
+impl ReliableChannels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wrap a control message for `addr` and keep it until acked. Returns the
+    /// packet to send now, unless the window is full and it has to wait.
+    pub fn push(&mut self, my_id: u8, message: Vec<u8>, addr: SocketAddr, handshake: bool) -> Option<Vec<u8>> {
+        let stream = self.send.entry((addr, handshake)).or_insert_with(SendStream::new);
+        let sequence = stream.next_sequence;
+        stream.next_sequence = sequence.wrapping_add(1);
+        let payload = Reliable {
+            stream: stream.stream,
+            sequence,
+            message,
+        }
+        .to_bytes();
+        let header = PacketHeader::new(PacketType::Control, my_id, 0, 0, payload.len() as u16);
+        let packet = Packet::new(header, payload).to_bytes();
+        let now = Instant::now();
+        stream.pending.push_back(Pending {
+            sequence,
+            packet,
+            attempts: 0,
+            rto: INITIAL_RTO,
+            resend_at: now,
+        });
+        stream.open_window(now).pop()
+    }
+
+    /// An Ack arrived from `addr`: stop sending that message. Returns the
+    /// messages that now fit in the window, to send. An Ack in the clear (a
+    /// guest's, for the Welcome that brings it the key) only settles the
+    /// handshake stream.
+    pub fn acked(&mut self, addr: SocketAddr, ack: Ack, sealed: bool) -> Vec<Retransmit> {
+        let mut opened = Vec::new();
+        for handshake in [false, true].into_iter().filter(|&handshake| sealed || handshake) {
+            if let Some(stream) = self.send.get_mut(&(addr, handshake)) {
+                if stream.stream == ack.stream {
+                    stream.pending.retain(|p| p.sequence != ack.sequence);
+                    let packets = stream.open_window(Instant::now());
+                    opened.extend(packets.into_iter().map(|packet| Retransmit {
+                        addr,
+                        packet,
+                        handshake,
+                    }));
+                }
+            }
+        }
+        opened
+    }
+
+    /// Messages in the window whose timeout has run out, with their timeouts
+    /// doubled. A receiver that acks nothing for MAX_ATTEMPTS sends is given
+    /// up on: its queue is dropped and returned, and whatever comes next
+    /// starts a new stream.
+    pub fn due(&mut self, now: Instant) -> (Vec<Retransmit>, Vec<Abandoned>) {
+        let mut due = Vec::new();
+        let mut abandoned = Vec::new();
+        self.send.retain(|&(addr, handshake), stream| {
+            let is_due = |p: &Pending| p.attempts > 0 && p.resend_at <= now;
+            if let Some(pending) = stream.pending.iter().find(|p| is_due(p) && p.attempts >= MAX_ATTEMPTS) {
+                log::warn!(
+                    "No ack from {addr} for control message {} after {MAX_ATTEMPTS} sends; giving up on {} message(s)",
+                    pending.sequence,
+                    stream.pending.len()
+                );
+                let messages = stream
+                    .pending
+                    .drain(..)
+                    .filter_map(|p| Some(Reliable::from_bytes(&Packet::from_bytes(&p.packet)?.payload)?.message))
+                    .collect();
+                abandoned.push(Abandoned { addr, messages });
+                return false;
+            }
+            for pending in stream.pending.iter_mut().filter(|p| is_due(p)) {
+                pending.attempts += 1;
+                pending.rto = (pending.rto * 2).min(MAX_RTO);
+                pending.resend_at = now + pending.rto;
+                due.push(Retransmit {
+                    addr,
+                    packet: pending.packet.clone(),
+                    handshake,
+                });
+            }
+            true
+        });
+        (due, abandoned)
+    }
+
+    /// A sealed Reliable arrived from `addr`. Returns the Ack to send back, if any,
+    /// and the messages now deliverable, in order: none for a duplicate or one
+    /// that is still waiting for an earlier message.
+    pub fn receive(&mut self, addr: SocketAddr, reliable: Reliable) -> (Option<Ack>, Vec<Vec<u8>>) {
+        let ack = Some(Ack {
+            stream: reliable.stream,
+            sequence: reliable.sequence,
+        });
+        let stream = self
+            .recv
+            .entry(addr)
+            .or_insert_with(|| RecvStream::new(reliable.stream, None));
+        if stream.stream != reliable.stream {
+            if stream.previous == Some(reliable.stream) {
+                return (ack, Vec::new());
+            }
+            log::debug!("Control stream from {addr} restarted");
+            *stream = RecvStream::new(reliable.stream, Some(stream.stream));
+        }
+
+        // Behind the next expected: seen and delivered already
+        let ahead = reliable.sequence.wrapping_sub(stream.next_sequence);
+        if (ahead as i16) < 0 {
+            return (ack, Vec::new());
+        }
+        if ahead > 0 {
+            // No room: left unacked, so it comes again
+            if stream.held.len() >= MAX_HELD {
+                return (None, Vec::new());
+            }
+            stream.held.insert(reliable.sequence, reliable.message);
+            return (ack, Vec::new());
+        }
+
+        let mut delivered = vec![reliable.message];
+        stream.next_sequence = stream.next_sequence.wrapping_add(1);
+        while let Some(message) = stream.held.remove(&stream.next_sequence) {
+            delivered.push(message);
+            stream.next_sequence = stream.next_sequence.wrapping_add(1);
+        }
+        (ack, delivered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "10.0.0.2:9000".parse().unwrap()
+    }
+
+    /// The Reliable a packet from `push` or `due` carries.
+    fn unwrap(packet: &[u8]) -> Reliable {
+        let packet = Packet::from_bytes(packet).unwrap();
+        Reliable::from_bytes(&packet.payload).unwrap()
+    }
+
+    fn ack_for(reliable: &Reliable) -> Ack {
+        Ack {
+            stream: reliable.stream,
+            sequence: reliable.sequence,
+        }
+    }
+
+    #[test]
+    fn delivers_in_order_and_holds_early_arrivals() {
+        let mut sender = ReliableChannels::new();
+        let mut receiver = ReliableChannels::new();
+        let sent: Vec<Reliable> = (0..3u8)
+            .map(|i| unwrap(&sender.push(1, vec![i], addr(), false).unwrap()))
+            .collect();
+        let [first, second, third] = <[Reliable; 3]>::try_from(sent).unwrap();
+
+        let (ack, delivered) = receiver.receive(addr(), third);
+        assert!(ack.is_some());
+        assert!(delivered.is_empty());
+        let (_, delivered) = receiver.receive(addr(), second);
+        assert!(delivered.is_empty());
+        let (_, delivered) = receiver.receive(addr(), first);
+        assert_eq!(delivered, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn duplicates_are_acked_but_delivered_once() {
+        let mut sender = ReliableChannels::new();
+        let mut receiver = ReliableChannels::new();
+        let packet = sender.push(1, b"hi".to_vec(), addr(), false).unwrap();
+
+        let (_, delivered) = receiver.receive(addr(), unwrap(&packet));
+        assert_eq!(delivered, vec![b"hi".to_vec()]);
+        let (ack, delivered) = receiver.receive(addr(), unwrap(&packet));
+        let ack = ack.unwrap();
+        assert_eq!((ack.stream, ack.sequence), (unwrap(&packet).stream, 0));
+        assert!(delivered.is_empty());
+    }
+
+    #[test]
+    fn retransmits_with_backoff_until_acked() {
+        let mut sender = ReliableChannels::new();
+        let packet = sender.push(1, b"hi".to_vec(), addr(), false).unwrap();
+        let start = Instant::now();
+
+        assert!(sender.due(start).0.is_empty());
+        let (due, _) = sender.due(start + INITIAL_RTO);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].packet, packet);
+        assert!(!due[0].handshake);
+        // The timeout doubled, so not due again one initial timeout later
+        assert!(sender.due(start + INITIAL_RTO * 2).0.is_empty());
+        assert_eq!(sender.due(start + INITIAL_RTO * 3).0.len(), 1);
+
+        sender.acked(addr(), ack_for(&unwrap(&packet)), true);
+        assert!(sender.due(start + MAX_RTO * 10).0.is_empty());
+    }
+
+    #[test]
+    fn gives_up_and_starts_a_new_stream() {
+        let mut sender = ReliableChannels::new();
+        let mut receiver = ReliableChannels::new();
+        let first = unwrap(&sender.push(1, b"lost".to_vec(), addr(), false).unwrap());
+        let mut now = Instant::now();
+        for _ in 1..MAX_ATTEMPTS {
+            now += MAX_RTO;
+            assert_eq!(sender.due(now).0.len(), 1);
+        }
+        now += MAX_RTO;
+        let (due, abandoned) = sender.due(now);
+        assert!(due.is_empty());
+        assert_eq!(abandoned.len(), 1);
+        assert_eq!(abandoned[0].messages, vec![b"lost".to_vec()]);
+
+        let next = unwrap(&sender.push(1, b"next".to_vec(), addr(), false).unwrap());
+        assert_ne!(next.stream, first.stream);
+        assert_eq!(next.sequence, 0);
+        // The receiver never saw the first stream and follows the new one
+        let (_, delivered) = receiver.receive(addr(), next);
+        assert_eq!(delivered, vec![b"next".to_vec()]);
+    }
+
+    #[test]
+    fn window_holds_back_a_burst_until_acks_come() {
+        let mut sender = ReliableChannels::new();
+        let sent: Vec<Reliable> = (0..SEND_WINDOW + 2)
+            .filter_map(|i| sender.push(1, vec![i as u8], addr(), false))
+            .map(|packet| unwrap(&packet))
+            .collect();
+        assert_eq!(sent.len(), SEND_WINDOW);
+        assert_eq!(sender.due(Instant::now() + INITIAL_RTO).0.len(), SEND_WINDOW);
+
+        let opened = sender.acked(addr(), ack_for(&sent[0]), true);
+        assert_eq!(opened.len(), 1);
+        assert_eq!(unwrap(&opened[0].packet).sequence, SEND_WINDOW as u16);
+        // An ack for a stream we aren't sending on changes nothing
+        let stale = Ack {
+            stream: sent[1].stream.wrapping_add(1),
+            sequence: sent[1].sequence,
+        };
+        assert!(sender.acked(addr(), stale, true).is_empty());
+    }
+
+    #[test]
+    fn giving_up_returns_the_backlog_never_sent() {
+        let mut sender = ReliableChannels::new();
+        let other: SocketAddr = "10.0.0.3:9000".parse().unwrap();
+        sender.push(1, b"elsewhere".to_vec(), other, false);
+        for i in 0..SEND_WINDOW + 5 {
+            sender.push(1, vec![i as u8], addr(), false);
+        }
+        let mut now = Instant::now();
+        let abandoned = loop {
+            now += MAX_RTO;
+            // The other receiver keeps acking
+            for retransmit in sender.due(now).0.into_iter().filter(|r| r.addr == other) {
+                sender.acked(other, ack_for(&unwrap(&retransmit.packet)), true);
+            }
+            now += MAX_RTO;
+            let (_, abandoned) = sender.due(now);
+            if !abandoned.is_empty() {
+                break abandoned;
+            }
+        };
+        assert_eq!(abandoned.len(), 1);
+        assert_eq!(abandoned[0].addr, addr());
+        let expected: Vec<Vec<u8>> = (0..SEND_WINDOW + 5).map(|i| vec![i as u8]).collect();
+        assert_eq!(abandoned[0].messages, expected);
+        // Nothing more goes to either
+        assert!(sender.due(now + MAX_RTO * 10).0.is_empty());
+    }
+
+    #[test]
+    fn handshake_stream_is_separate() {
+        let mut sender = ReliableChannels::new();
+        let sealed = unwrap(&sender.push(1, b"sealed".to_vec(), addr(), false).unwrap());
+        let clear = unwrap(&sender.push(1, b"clear".to_vec(), addr(), true).unwrap());
+        assert_ne!(sealed.stream, clear.stream);
+        assert_eq!(clear.sequence, 0);
+
+        let (due, _) = sender.due(Instant::now() + INITIAL_RTO);
+        assert_eq!(due.iter().filter(|r| r.handshake).count(), 1);
+        // An Ack in the clear settles the handshake stream only
+        sender.acked(addr(), ack_for(&sealed), false);
+        sender.acked(addr(), ack_for(&clear), false);
+        let (due, _) = sender.due(Instant::now() + MAX_RTO * 2);
+        assert_eq!(due.len(), 1);
+        assert!(!due[0].handshake);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+/// Video may go out this much faster than the encoder's budget, so a
+/// keyframe is spread over a few frame intervals rather than many.
+const PACING_FACTOR: f64 = 2.5;
+/// Video bytes that may go out back to back before pacing kicks in.
+const PACING_BURST_BYTES: f64 = 12_000.0;
+/// Packets sent per wake-up before looking at the queues again.
+const MAX_BATCH: usize = 16;
+/// Video packets waiting beyond this are dropped, oldest first: the link
+/// can't keep up, and receivers will ask for a keyframe.
+const MAX_VIDEO_QUEUE: usize = 1000;
+
+/// Traffic classes, highest priority first. Nothing of a lower class is sent
+/// while a higher one has packets waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendClass {
+    Audio = 0,
+    Control = 1,
+    Video = 2,
+}
+
+/// A packet waiting to be sent.
+#[derive(Debug)]
+pub struct Outgoing {
+    pub packet: Vec<u8>,
+    pub target: SocketAddr,
+    /// Seal under the session key when sent; false for the handshake and for
+    /// relayed packets, which are sealed already.
+    pub seal: bool,
+    /// Subject to the video token bucket: our own video, not what we relay.
+    pub paced: bool,
+    /// Our ID and the guest the packet is for, when it goes through the host:
+    /// sealed, wrapped in a RelayForward and sealed again as it goes out.
+    pub relay: Option<(u8, u8)>,
+}
+
+/// Token bucket for our video: refilled at PACING_FACTOR × the budget, and
+/// allowed into debt by one packet so none is too big to ever go.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Bytes per second; None until a budget is set.
+    rate: Option<f64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(PACING_BURST_BYTES);
+        }
+        self.last_refill = now;
+    }
+
+    fn take(&mut self, bytes: usize) -> bool {
+        if self.rate.is_none() {
+            return true;
+        }
+        if self.tokens <= 0.0 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+
+    /// How long until the bucket is out of debt.
+    fn wait(&self) -> Duration {
+        match self.rate {
+            Some(rate) if self.tokens <= 0.0 => Duration::from_secs_f64(-self.tokens / rate)
+                .max(Duration::from_millis(1)),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SendQueues {
+    /// Indexed by `SendClass`.
+    queues: [VecDeque<Outgoing>; 3],
+    bucket: TokenBucket,
+    closed: bool,
+}
+
+/// The single queue every packet leaves through, drained by the transport's
+/// send task (see `socket::PacketTransport`).
+///
+/// Audio goes before control, and control before video, whatever order they
+/// were queued in. Our own video is paced by a token bucket, so a keyframe
+/// burst doesn't fill the uplink's queue in front of the next audio frame.
+/// Queueing never blocks on the network, so the audio and video threads can
+/// hand their packets over without waiting on the runtime.
+#[derive(Debug)]
+pub struct SendScheduler {
+    queues: Mutex<SendQueues>,
+    wake: Notify,
+}
+
+impl SendScheduler {
+    pub fn new() -> Self {
+        Self {
+            queues: Mutex::new(SendQueues {
+                queues: Default::default(),
+                bucket: TokenBucket {
+                    rate: None,
+                    tokens: PACING_BURST_BYTES,
+                    last_refill: Instant::now(),
+                },
+                closed: false,
+            }),
+            wake: Notify::new(),
+        }
+    }
+
+    /// Queue a packet behind those of its class.
+    pub fn push(&self, class: SendClass, outgoing: Outgoing) -> Result<(), String> {
+        {
+            let mut q = self.queues.lock().unwrap();
+            if q.closed {
+                return Err("Sender stopped".into());
+            }
+            let queue = &mut q.queues[class as usize];
+            if class == SendClass::Video && queue.len() >= MAX_VIDEO_QUEUE {
+                log::debug!("Send queue full, dropping the oldest video packet");
+                queue.pop_front();
+            }
+            queue.push_back(outgoing);
+        }
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Pace our video for a total send budget, all destinations together.
+    pub fn set_video_budget_kbps(&self, kbps: u32) {
+        let mut q = self.queues.lock().unwrap();
+        q.bucket.refill(Instant::now());
+        q.bucket.rate = Some(kbps as f64 * 1000.0 / 8.0 * PACING_FACTOR);
+    }
+
+    /// Stop the send task; anything still queued is dropped.
+    pub fn close(&self) {
+        self.queues.lock().unwrap().closed = true;
+        self.wake.notify_one();
+    }
+
+    /// The next packets to send, highest class first, and if paced video is
+    /// being held back, how long until it may go. None once closed.
+    pub fn next_batch(&self) -> Option<(Vec<Outgoing>, Option<Duration>)> {
+        let mut q = self.queues.lock().unwrap();
+        if q.closed {
+            return None;
+        }
+        q.bucket.refill(Instant::now());
+        let mut batch = Vec::new();
+        let mut held = None;
+        while batch.len() < MAX_BATCH {
+            let SendQueues { queues, bucket, .. } = &mut *q;
+            let [audio, control, video] = queues;
+            if let Some(outgoing) = audio.pop_front().or_else(|| control.pop_front()) {
+                batch.push(outgoing);
+                continue;
+            }
+            let Some(next) = video.front() else { break };
+            if next.paced && !bucket.take(next.packet.len()) {
+                held = Some(bucket.wait());
+                break;
+            }
+            batch.extend(video.pop_front());
+        }
+        Some((batch, held))
+    }
+
+    /// Wait for more packets, or at most `timeout`.
+    pub async fn wait(&self, timeout: Option<Duration>) {
+        match timeout {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, self.wake.notified()).await;
+            }
+            None => self.wake.notified().await,
+        }
+    }
+}
@@ -1,17 +1,74 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use super::chat::ChatLog;
+use super::clock::ClockOffset;
+use super::congestion::BandwidthEstimator;
+use super::control::{Capabilities, LayerSelection, ModerationAction, ResumeTokens};
+use super::crypto::{self, KeyExchange, ResumeToken, SessionKey};
+use super::protocol::VIDEO_LAYERS;
+use super::stats::{PeerStats, RelayStats};
+use crate::video::ladder;
 
 /// Shared session state accessed by UI, recv task, heartbeat task, and encode thread.
 pub type SharedSessionState = Arc<Mutex<SessionState>>;
 
+/// The host that starts a session has participant ID 1. Its session clock
+/// stays the reference time even after it hands the session over.
+pub const HOST_PARTICIPANT_ID: u8 = 1;
+
+/// Peers are dropped after this long without a packet.
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a dropped peer may resume under its participant ID.
+const RESUME_WINDOW: Duration = Duration::from_secs(120);
+
+/// A host silent this long without handing over is given up on, and its
+/// successor takes over.
+const HOST_TAKEOVER_AFTER: Duration = Duration::from_secs(10);
+/// How long a new host keeps announcing itself to the guests.
+const HANDOVER_ANNOUNCE: Duration = Duration::from_secs(10);
+
+const TOP_LAYER: u8 = VIDEO_LAYERS as u8 - 1;
+
+/// A simulcast layer counts as being sent while its packets are this recent.
+const LAYER_LIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Video loss over a stats interval above which we ask a sender for a lower layer.
+const LAYER_DOWN_LOSS_PERCENT: f32 = 5.0;
+/// Loss below which, after LAYER_UP_HOLD at the current cap, we try a higher one.
+const LAYER_UP_LOSS_PERCENT: f32 = 1.0;
+const LAYER_UP_HOLD: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Role {
     Host,
     Guest,
 }
 
+/// How media travels between guests, chosen by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    /// Everyone sends to everyone; guests punch through to each other.
+    Mesh,
+    /// Selective forwarding: guests send to the host only, and the host
+    /// forwards each stream to the other guests.
+    Sfu,
+}
+
+impl Topology {
+    /// Most participants, including the host, a session admits. A mesh sender
+    /// uploads one copy per peer; through the host each guest uploads one.
+    pub fn max_participants(self) -> usize {
+        match self {
+            Self::Mesh => 4,
+            Self::Sfu => 16,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PeerState {
     Connecting,
@@ -19,6 +76,35 @@ pub enum PeerState {
     Disconnected,
 }
 
+/// How our packets reach a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerPath {
+    /// Straight to `Peer::addr` (the host, or a guest we punched through to).
+    Direct,
+    /// The host forwards traffic for this pair: hole punching failed, or the
+    /// session is in SFU mode.
+    Relay,
+}
+
+/// Host forwarding table: where each sender's media goes, with video keyed
+/// by (sender, simulcast layer).
+#[derive(Debug, Clone, Default)]
+pub struct RelayRoutes {
+    pub audio: HashMap<u8, Vec<SocketAddr>>,
+    pub video: HashMap<(u8, u8), Vec<SocketAddr>>,
+}
+
+/// Host with a waiting room: a guest whose Hello is held until the host decides.
+#[derive(Debug, Clone)]
+pub struct WaitingGuest {
+    pub addr: SocketAddr,
+    pub name: String,
+    /// The guest repeats its Hello while it waits; it has left once these stop.
+    last_hello: Instant,
+    /// Admit (true) or deny (false), applied when the next Hello comes in.
+    pub decision: Option<bool>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Peer {
     pub participant_id: u8,
@@ -26,6 +112,69 @@ pub struct Peer {
     pub addr: SocketAddr,
     pub state: PeerState,
     pub last_seen: Instant,
+    pub stats: PeerStats,
+    /// This peer's session clock relative to ours, once a sync exchange has completed a round.
+    pub clock: Option<ClockOffset>,
+    pub path: PeerPath,
+    /// Token of our outstanding hole-punching check, matched against the peer's PunchAck.
+    pub punch_token: Option<u32>,
+    /// Host in SFU mode: senders whose video this guest asked us not to forward.
+    pub hidden_video: Vec<u8>,
+    /// Host in SFU mode: what we have forwarded to this guest from the others.
+    pub forwarded: RelayStats,
+    /// Simulcast layer of each sender's video this peer asked for (LayerSelection).
+    pub video_layers: HashMap<u8, u8>,
+    /// When each layer of this peer's video last reached us.
+    pub layers_seen: [Option<Instant>; VIDEO_LAYERS],
+    /// The layer of this peer's video we are showing, once any has arrived.
+    pub video_layer_in: Option<u8>,
+    /// Highest layer of this peer's video that reaches us cleanly: stepped
+    /// down on loss, back up after LAYER_UP_HOLD.
+    pub layer_cap: u8,
+    pub layer_cap_changed: Instant,
+    /// Highest layer this peer can take, from the resolution in its capabilities.
+    pub max_layer: u8,
+    /// Host, or the host's successor: the token this guest can resume with
+    /// after a drop.
+    pub resume_token: Option<ResumeToken>,
+    /// Set when the peer times out: until then it may come back (see `RESUME_WINDOW`).
+    pub resumable_until: Option<Instant>,
+}
+
+impl Peer {
+    fn is_active(&self) -> bool {
+        self.state != PeerState::Disconnected
+    }
+
+    /// Dropped, but still within its resume window.
+    fn is_resumable(&self) -> bool {
+        !self.is_active() && self.resumable_until.is_some_and(|t| Instant::now() < t)
+    }
+
+    /// Whether this peer should receive `sender`'s video.
+    pub fn wants_video(&self, sender: u8) -> bool {
+        !self.hidden_video.contains(&sender)
+    }
+
+    /// Layer of `sender`'s video this peer wants; the top one it can take until it says.
+    pub fn wanted_layer(&self, sender: u8) -> u8 {
+        self.video_layers
+            .get(&sender)
+            .copied()
+            .unwrap_or(TOP_LAYER)
+            .min(self.max_layer)
+    }
+
+    /// Of the layers of this peer's video currently arriving, the best at or
+    /// below `wanted`, else the lowest.
+    fn pick_layer(&self, wanted: u8) -> Option<u8> {
+        let live: Vec<u8> = (0..VIDEO_LAYERS as u8)
+            .filter(|&l| {
+                self.layers_seen[l as usize].is_some_and(|t| t.elapsed() < LAYER_LIVE_TIMEOUT)
+            })
+            .collect();
+        live.iter().rev().find(|&&l| l <= wanted).or(live.first()).copied()
+    }
 }
 
 pub struct SessionState {
@@ -34,51 +183,120 @@ pub struct SessionState {
     pub my_participant_id: u8,
     pub my_name: String,
     pub peers: HashMap<u8, Peer>,
-    pub next_participant_id: u8,
+    /// Participant ID of the current host: HOST_PARTICIPANT_ID until a handover.
+    pub host_id: u8,
     pub seq_counter: u16,
     pub start_time: Instant,
     pub ended: bool,
+    /// Send rate target for our live video, driven by peers' receiver reports.
+    pub bwe: BandwidthEstimator,
+    /// Key every packet is sealed with: generated by the host, learned from Welcome by guests.
+    pub session_key: Option<SessionKey>,
+    /// Mixed into the key exchange; empty for no passphrase.
+    pub passphrase: String,
+    /// Our address as seen from outside the NAT (STUN), for guests to join on.
+    pub public_addr: Option<SocketAddr>,
+    /// Host only: guest pairs we relay for, keyed by `relay_pair`, with forwarding totals.
+    pub relay_pairs: HashMap<(u8, u8), RelayStats>,
+    pub topology: Topology,
+    /// Guest in SFU mode: peers whose video we've asked the host to stop forwarding.
+    pub hidden_video: HashSet<u8>,
+    /// Simulcast layer our video tiles are sized for, set by the UI.
+    pub preferred_layer: u8,
+    /// Guest: token the host gave us on admission, for resuming if we lose it.
+    pub resume_token: Option<ResumeToken>,
+    /// Host: the successor that holds our guests' current resume tokens (see
+    /// `resume_tokens_to_share`). Cleared whenever a token is issued.
+    pub tokens_shared_with: Option<u8>,
+    /// Guest: key exchange of the resume Hello we are waiting on an answer to.
+    pub resume_exchange: Option<KeyExchange>,
+    /// Host: hold new guests until we admit them.
+    pub waiting_room: bool,
+    /// Host: guests in the waiting room, in the order they arrived.
+    pub waiting: Vec<WaitingGuest>,
+    /// Host: turn away every new guest; only dropped ones may resume.
+    pub room_locked: bool,
+    /// Guest: what the host has asked of us, for the UI to carry out.
+    pub host_requests: Vec<ModerationAction>,
+    /// Text chat and attachments, ours included.
+    pub chat: ChatLog,
+    /// New host: until when to announce the handover (see `handover_targets`).
+    pub handover_until: Option<Instant>,
 }
 
 impl SessionState {
     /// Create state for a host starting a new session.
-    pub fn new_host(name: String) -> Self {
+    pub fn new_host(name: String, passphrase: String, topology: Topology) -> Self {
         let session_id = rand_session_id();
         log::info!("Created host session {session_id:#010X}");
         Self {
             role: Role::Host,
             session_id,
-            my_participant_id: 1,
+            my_participant_id: HOST_PARTICIPANT_ID,
             my_name: name,
             peers: HashMap::new(),
-            next_participant_id: 2, // host is 1, guests start at 2
+            host_id: HOST_PARTICIPANT_ID,
             seq_counter: 0,
             start_time: Instant::now(),
             ended: false,
+            bwe: BandwidthEstimator::new(),
+            session_key: Some(crypto::generate_session_key()),
+            passphrase,
+            public_addr: None,
+            relay_pairs: HashMap::new(),
+            topology,
+            hidden_video: HashSet::new(),
+            preferred_layer: TOP_LAYER,
+            resume_token: None,
+            tokens_shared_with: None,
+            resume_exchange: None,
+            waiting_room: false,
+            waiting: Vec::new(),
+            room_locked: false,
+            host_requests: Vec::new(),
+            chat: ChatLog::default(),
+            handover_until: None,
         }
     }
 
     /// Create state for a guest joining a session.
-    /// participant_id and session_id are assigned after receiving Welcome.
-    pub fn new_guest(name: String) -> Self {
+    /// participant_id, session_id, session_key and topology are assigned after receiving Welcome.
+    pub fn new_guest(name: String, passphrase: String) -> Self {
         Self {
             role: Role::Guest,
             session_id: 0,
             my_participant_id: 0,
             my_name: name,
             peers: HashMap::new(),
-            next_participant_id: 0,
+            host_id: HOST_PARTICIPANT_ID,
             seq_counter: 0,
             start_time: Instant::now(),
             ended: false,
+            bwe: BandwidthEstimator::new(),
+            session_key: None,
+            passphrase,
+            public_addr: None,
+            relay_pairs: HashMap::new(),
+            topology: Topology::Mesh,
+            hidden_video: HashSet::new(),
+            preferred_layer: TOP_LAYER,
+            resume_token: None,
+            tokens_shared_with: None,
+            resume_exchange: None,
+            waiting_room: false,
+            waiting: Vec::new(),
+            room_locked: false,
+            host_requests: Vec::new(),
+            chat: ChatLog::default(),
+            handover_until: None,
         }
     }
 
-    /// Host assigns the next participant ID to a new guest.
-    pub fn assign_participant_id(&mut self) -> u8 {
-        let id = self.next_participant_id;
-        self.next_participant_id = self.next_participant_id.wrapping_add(1);
-        id
+    /// Host picks the lowest participant ID free for a new guest: not 0, not
+    /// ours and not held by a peer, even one that may still resume. None
+    /// once every ID is taken.
+    pub fn assign_participant_id(&self) -> Option<u8> {
+        (1..=u8::MAX).find(|id| *id != self.host_id && !self.peers.contains_key(id))
     }
 
     /// Get the next sequence number and increment.
@@ -93,10 +311,36 @@ impl SessionState {
         self.start_time.elapsed().as_millis() as u32
     }
 
-    /// Update last_seen for a peer. Sets state to Connected if Connecting.
+    /// Microseconds elapsed since session start, for clock sync exchanges.
+    pub fn clock_us(&self) -> u64 {
+        self.start_time.elapsed().as_micros() as u64
+    }
+
+    /// Our session clock, in microseconds, at `at`.
+    pub fn clock_us_at(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.start_time).as_micros() as u64
+    }
+
+    /// Microseconds elapsed since session start (wraps after ~71 minutes;
+    /// only meant for short intervals like Ping/Pong round trips).
+    pub fn elapsed_us(&self) -> u32 {
+        self.start_time.elapsed().as_micros() as u32
+    }
+
+    /// Update last_seen for a peer.
     pub fn touch_peer(&mut self, participant_id: u8) {
         if let Some(peer) = self.peers.get_mut(&participant_id) {
             peer.last_seen = Instant::now();
+        }
+    }
+
+    /// Mark a peer Connected once the path to it is known to work both ways:
+    /// by the Hello/Welcome handshake for host and guest, by a hole-punching
+    /// check between guests.
+    pub fn set_connected(&mut self, participant_id: u8) {
+        if let Some(peer) = self.peers.get_mut(&participant_id) {
+            peer.last_seen = Instant::now();
+            peer.punch_token = None;
             if peer.state == PeerState::Connecting {
                 peer.state = PeerState::Connected;
                 log::info!("Peer {} ({}) is now Connected", peer.name, participant_id);
@@ -105,27 +349,211 @@ impl SessionState {
     }
 
     /// Check for peers that have timed out (>5 seconds since last_seen).
-    /// Returns list of participant IDs that timed out.
+    /// Returns list of participant IDs that timed out. They stay resumable
+    /// for RESUME_WINDOW.
     pub fn check_timeouts(&mut self) -> Vec<u8> {
-        let timeout = std::time::Duration::from_secs(5);
         let now = Instant::now();
         let mut timed_out = Vec::new();
 
         for (id, peer) in &mut self.peers {
-            if peer.state != PeerState::Disconnected && now.duration_since(peer.last_seen) > timeout
+            if peer.state != PeerState::Disconnected
+                && now.duration_since(peer.last_seen) > PEER_TIMEOUT
             {
                 log::warn!("Peer {} ({}) timed out", peer.name, id);
                 peer.state = PeerState::Disconnected;
+                peer.resumable_until = Some(now + RESUME_WINDOW);
                 timed_out.push(*id);
             }
         }
+        self.waiting
+            .retain(|guest| now.duration_since(guest.last_hello) <= PEER_TIMEOUT);
 
         timed_out
     }
 
-    /// Add a new peer to the session.
+    /// A peer at `addr` stopped acking our reliable messages altogether:
+    /// drop it as if it had timed out. Returns its ID if it was still in the call.
+    pub fn give_up_on(&mut self, addr: SocketAddr) -> Option<u8> {
+        let peer = self
+            .peers
+            .values_mut()
+            .find(|p| p.addr == addr && p.state != PeerState::Disconnected)?;
+        log::warn!("Peer {} ({}) stopped acking", peer.name, peer.participant_id);
+        peer.state = PeerState::Disconnected;
+        peer.resumable_until = Some(Instant::now() + RESUME_WINDOW);
+        Some(peer.participant_id)
+    }
+
+    /// Everyone in the call, including ourselves and anyone dropped who may
+    /// still resume.
+    pub fn participant_count(&self) -> usize {
+        1 + self
+            .peers
+            .values()
+            .filter(|p| p.is_active() || p.is_resumable())
+            .count()
+    }
+
+    /// Whether any dropped peer may still come back, so the call should
+    /// stay up even with nobody connected.
+    pub fn awaiting_resume(&self) -> bool {
+        self.peers.values().any(|p| p.is_resumable())
+    }
+
+    /// Host: the guest a resume token was issued to, if it is still in the
+    /// call or within its resume window.
+    pub fn resumable_peer(&self, token: &ResumeToken) -> Option<u8> {
+        self.peers
+            .values()
+            .find(|p| p.resume_token.as_ref() == Some(token) && (p.is_active() || p.is_resumable()))
+            .map(|p| p.participant_id)
+    }
+
+    /// Host: give a guest just admitted a fresh resume token. Its old one,
+    /// if it resumed, is spent.
+    pub fn issue_resume_token(&mut self, participant_id: u8) -> Option<ResumeToken> {
+        let peer = self.peers.get_mut(&participant_id)?;
+        let token = crypto::generate_resume_token();
+        peer.resume_token = Some(token);
+        self.tokens_shared_with = None;
+        Some(token)
+    }
+
+    /// Host: our guests' resume tokens and where to send them, if our
+    /// successor doesn't have the current set yet. Whoever takes over if we
+    /// vanish can then let the guests resume with it.
+    pub fn resume_tokens_to_share(&mut self) -> Option<(SocketAddr, ResumeTokens)> {
+        if self.role != Role::Host {
+            return None;
+        }
+        let successor = self.successor()?;
+        let addr = self.peers.get(&successor).filter(|p| p.is_active())?.addr;
+        if self.tokens_shared_with == Some(successor) {
+            return None;
+        }
+        self.tokens_shared_with = Some(successor);
+        let tokens = self
+            .peers
+            .values()
+            .filter(|p| p.participant_id != successor && (p.is_active() || p.is_resumable()))
+            .filter_map(|p| Some((p.participant_id, p.resume_token?)))
+            .collect();
+        Some((addr, ResumeTokens { tokens }))
+    }
+
+    /// Guest: resume tokens from the host. Ours is for us to resume with;
+    /// the others are the guests' own, for us to honour if we take over.
+    pub fn accept_resume_tokens(&mut self, tokens: &[(u8, ResumeToken)]) {
+        for &(participant_id, token) in tokens {
+            if participant_id == self.my_participant_id {
+                self.resume_token = Some(token);
+            } else if let Some(peer) = self.peers.get_mut(&participant_id) {
+                peer.resume_token = Some(token);
+            }
+        }
+    }
+
+    /// Guest: whether we have lost the host but may still resume with it.
+    pub fn resuming(&self) -> bool {
+        self.role == Role::Guest
+            && self.resume_token.is_some()
+            && self
+                .peers
+                .get(&self.host_id)
+                .is_some_and(|host| host.is_resumable())
+    }
+
+    /// Who takes over if the host goes: the lowest participant ID still in
+    /// the call or within its resume window. Every guest works it out the same.
+    pub fn successor(&self) -> Option<u8> {
+        let me = (self.my_participant_id != self.host_id).then_some(self.my_participant_id);
+        self.peers
+            .values()
+            .filter(|p| p.participant_id != self.host_id && (p.is_active() || p.is_resumable()))
+            .map(|p| p.participant_id)
+            .chain(me)
+            .min()
+    }
+
+    /// Guest: the host to move on to, once ours has left without handing
+    /// over, or been silent for HOST_TAKEOVER_AFTER.
+    pub fn elect_host(&self) -> Option<u8> {
+        if self.role != Role::Guest {
+            return None;
+        }
+        let host = self.peers.get(&self.host_id)?;
+        // A host that sent BYE is gone; one that timed out may yet come back
+        let gone = host.state == PeerState::Disconnected
+            && (!host.is_resumable() || host.last_seen.elapsed() >= HOST_TAKEOVER_AFTER);
+        if !gone {
+            return None;
+        }
+        self.successor()
+    }
+
+    /// Guest: take over as host. The guests come back to us the way they
+    /// resume after a drop; in SFU mode we now reach each of them directly.
+    pub fn become_host(&mut self) {
+        let previous = self.host_id;
+        log::info!("Taking over as host from participant {previous}");
+        if let Some(old) = self.peers.get_mut(&previous) {
+            old.state = PeerState::Disconnected;
+            old.resumable_until = None;
+        }
+        self.role = Role::Host;
+        self.host_id = self.my_participant_id;
+        self.resume_exchange = None;
+        self.relay_pairs.clear();
+        for peer in self.peers.values_mut() {
+            peer.path = PeerPath::Direct;
+        }
+        self.handover_until = Some(Instant::now() + HANDOVER_ANNOUNCE);
+    }
+
+    /// Guest: another participant is taking over as host. Rejoin through it
+    /// as after a drop, which gets us its roster.
+    pub fn follow_host(&mut self, new_host: u8) {
+        let previous = self.host_id;
+        log::info!("Participant {new_host} takes over as host from {previous}");
+        if let Some(old) = self.peers.get_mut(&previous) {
+            old.state = PeerState::Disconnected;
+            old.resumable_until = None;
+        }
+        self.host_id = new_host;
+        self.resume_exchange = None;
+        if let Some(host) = self.peers.get_mut(&new_host) {
+            host.path = PeerPath::Direct;
+            host.state = PeerState::Disconnected;
+            host.last_seen = Instant::now();
+            host.resumable_until = Some(Instant::now() + RESUME_WINDOW);
+        }
+    }
+
+    /// New host: where to repeat our HostHandover, so guests still waiting on
+    /// the old host follow, and NATs on the way let their resume Hellos in.
+    pub fn handover_targets(&self) -> Vec<SocketAddr> {
+        if self.handover_until.is_none_or(|t| Instant::now() >= t) {
+            return Vec::new();
+        }
+        self.peers
+            .values()
+            .filter(|p| p.is_active() || p.is_resumable())
+            .map(|p| p.addr)
+            .collect()
+    }
+
+    /// Add a new peer to the session. In SFU mode a guest reaches the other
+    /// guests only through the host.
     pub fn add_peer(&mut self, participant_id: u8, name: String, addr: SocketAddr) {
         log::info!("Adding peer: {name} (id={participant_id}) at {addr}");
+        let path = if self.role == Role::Guest
+            && self.topology == Topology::Sfu
+            && participant_id != self.host_id
+        {
+            PeerPath::Relay
+        } else {
+            PeerPath::Direct
+        };
         self.peers.insert(
             participant_id,
             Peer {
@@ -134,20 +562,360 @@ impl SessionState {
                 addr,
                 state: PeerState::Connecting,
                 last_seen: Instant::now(),
+                stats: PeerStats::default(),
+                clock: None,
+                path,
+                punch_token: None,
+                hidden_video: Vec::new(),
+                forwarded: RelayStats::default(),
+                video_layers: HashMap::new(),
+                layers_seen: [None; VIDEO_LAYERS],
+                video_layer_in: None,
+                layer_cap: TOP_LAYER,
+                layer_cap_changed: Instant::now(),
+                max_layer: TOP_LAYER,
+                resume_token: None,
+                resumable_until: None,
             },
         );
     }
 
-    /// Get addresses of all active (non-disconnected) peers.
+    /// Host: a guest knocking on the waiting room with its Hello. Returns the
+    /// decision once we have made one, which takes it out of the room.
+    pub fn knock(&mut self, addr: SocketAddr, name: &str) -> Option<bool> {
+        let now = Instant::now();
+        match self.waiting.iter().position(|g| g.addr == addr) {
+            Some(i) => {
+                self.waiting[i].last_hello = now;
+                let decision = self.waiting[i].decision;
+                if decision.is_some() {
+                    self.waiting.remove(i);
+                }
+                decision
+            }
+            None => {
+                log::info!("{name} at {addr} is in the waiting room");
+                self.waiting.push(WaitingGuest {
+                    addr,
+                    name: name.to_string(),
+                    last_hello: now,
+                    decision: None,
+                });
+                None
+            }
+        }
+    }
+
+    /// Host: admit or deny a guest in the waiting room.
+    pub fn decide(&mut self, addr: SocketAddr, admit: bool) {
+        if let Some(guest) = self.waiting.iter_mut().find(|g| g.addr == addr) {
+            guest.decision = Some(admit);
+        }
+    }
+
+    /// Host: remove a guest from the call for good, without a resume window.
+    /// Returns its address, to tell it so.
+    pub fn kick(&mut self, participant_id: u8) -> Option<SocketAddr> {
+        let peer = self.peers.get_mut(&participant_id)?;
+        log::info!("Removing {} ({participant_id}) from the call", peer.name);
+        peer.state = PeerState::Disconnected;
+        peer.resumable_until = None;
+        let addr = peer.addr;
+        self.relay_pairs
+            .retain(|&(a, b), _| a != participant_id && b != participant_id);
+        Some(addr)
+    }
+
+    /// Take note of what a peer announced it can handle.
+    pub fn set_capabilities(&mut self, participant_id: u8, capabilities: &Capabilities) {
+        if let Some(peer) = self.peers.get_mut(&participant_id) {
+            peer.max_layer =
+                ladder::layer_within(capabilities.max_width.into(), capabilities.max_height.into());
+        }
+    }
+
+    /// How to turn a participant's session timestamps into host-reference time:
+    /// returns `(offset_ms, uncertainty_ms)` such that `their_ms + offset_ms` is
+    /// the original host's clock. Known for ourselves and that host, and — when
+    /// we are the host — for every guest we have synced with.
+    pub fn clock_offset_to_host(&self, participant_id: u8) -> Option<(f64, f64)> {
+        if participant_id == HOST_PARTICIPANT_ID {
+            return Some((0.0, 0.0));
+        }
+        if participant_id == self.my_participant_id {
+            // Host clock minus ours
+            let host = self.peers.get(&HOST_PARTICIPANT_ID)?.clock?;
+            return Some((host.offset_ms(), host.uncertainty_ms()));
+        }
+        if self.role == Role::Host {
+            // Our (host) clock minus theirs, then on to the original host's
+            // if we took over from it
+            let clock = self.peers.get(&participant_id)?.clock?;
+            let (to_reference, reference_uncertainty) = match self.my_participant_id {
+                HOST_PARTICIPANT_ID => (0.0, 0.0),
+                my_id => self.clock_offset_to_host(my_id)?,
+            };
+            return Some((
+                to_reference - clock.offset_ms(),
+                clock.uncertainty_ms() + reference_uncertainty,
+            ));
+        }
+        None
+    }
+
+    /// Convert one of our own session timestamps to host-reference time.
+    /// `None` until the sync exchange with the host has produced an estimate.
+    pub fn to_host_time_ms(&self, local_ms: u32) -> Option<u32> {
+        let (offset_ms, _) = self.clock_offset_to_host(self.my_participant_id)?;
+        Some((local_ms as i64 + offset_ms.round() as i64) as u32)
+    }
+
+    /// Feed the fresh receiver reports from connected peers to the bandwidth
+    /// estimator. The worst receiver sets the rate.
+    pub fn update_bandwidth_estimate(&mut self) {
+        let mut worst_loss: Option<f32> = None;
+        let mut worst_rtt: Option<f32> = None;
+        for peer in self.peers.values_mut() {
+            if peer.state == PeerState::Disconnected {
+                continue;
+            }
+            let Some(report) = peer.stats.remote_report.take() else {
+                continue;
+            };
+            let loss = report.audio_loss_percent.max(report.video_loss_percent) as f32;
+            worst_loss = Some(worst_loss.map_or(loss, |w| w.max(loss)));
+            if let Some(rtt) = peer.stats.rtt_ms {
+                worst_rtt = Some(worst_rtt.map_or(rtt, |w| w.max(rtt)));
+            }
+        }
+        if let Some(loss) = worst_loss {
+            self.bwe.update(loss, worst_rtt);
+        }
+    }
+
+    /// Count one outgoing packet of `bytes` towards each peer in `addrs`.
+    pub fn record_sent(&mut self, addrs: &[SocketAddr], bytes: usize) {
+        for peer in self.peers.values_mut() {
+            if addrs.contains(&peer.addr) {
+                peer.stats.record_out(bytes);
+            }
+        }
+    }
+
+    /// Host: whether we forward media for anyone.
+    pub fn is_relaying(&self) -> bool {
+        self.role == Role::Host && (self.topology == Topology::Sfu || !self.relay_pairs.is_empty())
+    }
+
+    /// Host: where to forward each guest's media. In a mesh, a guest's packets
+    /// go to every peer it is relayed with; in SFU mode, to every other guest.
+    /// Of the video, each receiver gets the one simulcast layer it asked for
+    /// (see `forward_layer`). Both ends must still be in the call.
+    pub fn relay_routes(&self) -> RelayRoutes {
+        let mut routes = RelayRoutes::default();
+        let mut add = |sender: &Peer, receiver: &Peer| {
+            let id = sender.participant_id;
+            routes.audio.entry(id).or_default().push(receiver.addr);
+            if let Some(layer) = forward_layer(sender, receiver) {
+                routes.video.entry((id, layer)).or_default().push(receiver.addr);
+            }
+        };
+        match self.topology {
+            Topology::Mesh => {
+                for &(a, b) in self.relay_pairs.keys() {
+                    let (Some(pa), Some(pb)) = (self.peers.get(&a), self.peers.get(&b)) else {
+                        continue;
+                    };
+                    if !pa.is_active() || !pb.is_active() {
+                        continue;
+                    }
+                    add(pa, pb);
+                    add(pb, pa);
+                }
+            }
+            Topology::Sfu => {
+                for sender in self.peers.values().filter(|p| p.is_active()) {
+                    for receiver in self.sfu_receivers(sender.participant_id) {
+                        add(sender, receiver);
+                    }
+                }
+            }
+        }
+        routes
+    }
+
+    /// Host: count a packet from `participant_id` of `bytes` on the wire
+    /// (`video_layer` for video) towards each peer it is forwarded to: relay
+    /// partners in a mesh, receivers in SFU mode.
+    pub fn record_relayed(&mut self, participant_id: u8, bytes: usize, video_layer: Option<u8>) {
+        if self.role != Role::Host {
+            return;
+        }
+        let Some(sender) = self.peers.get(&participant_id) else { return };
+        let forwarded_to = |receiver: &Peer| match video_layer {
+            Some(layer) => forward_layer(sender, receiver) == Some(layer),
+            None => true,
+        };
+        match self.topology {
+            Topology::Mesh => {
+                let pairs: Vec<(u8, u8)> = self
+                    .relay_pairs
+                    .keys()
+                    .filter(|&&(a, b)| {
+                        let partner = if a == participant_id { b } else { a };
+                        (a == participant_id || b == participant_id)
+                            && self.peers.get(&partner).is_some_and(forwarded_to)
+                    })
+                    .copied()
+                    .collect();
+                for pair in pairs {
+                    if let Some(stats) = self.relay_pairs.get_mut(&pair) {
+                        stats.packets += 1;
+                        stats.bytes += bytes as u64;
+                    }
+                }
+            }
+            Topology::Sfu => {
+                let receivers: Vec<u8> = self
+                    .sfu_receivers(participant_id)
+                    .filter(|p| forwarded_to(p))
+                    .map(|p| p.participant_id)
+                    .collect();
+                for id in receivers {
+                    if let Some(peer) = self.peers.get_mut(&id) {
+                        peer.forwarded.packets += 1;
+                        peer.forwarded.bytes += bytes as u64;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Host in SFU mode: the active guests a sender's media is forwarded to.
+    fn sfu_receivers(&self, sender: u8) -> impl Iterator<Item = &Peer> {
+        self.peers
+            .values()
+            .filter(move |p| p.is_active() && p.participant_id != sender)
+    }
+
+    /// The layer we want from a peer: what our tiles are sized for, within
+    /// what our link to it carries.
+    fn my_layer_for(&self, peer: &Peer) -> u8 {
+        self.preferred_layer.min(peer.layer_cap)
+    }
+
+    /// The layer of a peer's video to show: the one we want if it is being
+    /// sent, else the nearest below (or the lowest there is).
+    pub fn display_layer(&self, participant_id: u8) -> Option<u8> {
+        let peer = self.peers.get(&participant_id)?;
+        peer.pick_layer(self.my_layer_for(peer))
+    }
+
+    /// Note a video packet on `layer` from a peer. Returns true if the layer
+    /// wasn't arriving before, so the host's forwarding routes need redoing.
+    pub fn mark_layer_live(&mut self, participant_id: u8, layer: u8) -> bool {
+        let Some(seen) = self
+            .peers
+            .get_mut(&participant_id)
+            .and_then(|p| p.layers_seen.get_mut(layer as usize))
+        else {
+            return false;
+        };
+        let was_live = seen.is_some_and(|t| t.elapsed() < LAYER_LIVE_TIMEOUT);
+        *seen = Some(Instant::now());
+        !was_live
+    }
+
+    /// Step each peer's layer cap down when its video arrived lossy over the
+    /// last stats interval, and back up once it has been clean for a while.
+    pub fn update_layer_caps(&mut self) {
+        for peer in self.peers.values_mut().filter(|p| p.is_active()) {
+            let loss = peer.stats.video.loss_percent();
+            let held = peer.layer_cap_changed.elapsed() >= LAYER_UP_HOLD;
+            let cap = if loss > LAYER_DOWN_LOSS_PERCENT && peer.layer_cap > 0 {
+                peer.layer_cap - 1
+            } else if loss < LAYER_UP_LOSS_PERCENT && held && peer.layer_cap < TOP_LAYER {
+                peer.layer_cap + 1
+            } else {
+                continue;
+            };
+            log::info!(
+                "Video from {} at up to layer {cap} ({loss:.1}% loss)",
+                peer.name
+            );
+            peer.layer_cap = cap;
+            peer.layer_cap_changed = Instant::now();
+        }
+    }
+
+    /// The layer we want from every peer, for a LayerSelection.
+    pub fn layer_selection(&self) -> LayerSelection {
+        let mut layers: Vec<(u8, u8)> = self
+            .peers
+            .values()
+            .filter(|p| p.is_active())
+            .map(|p| (p.participant_id, self.my_layer_for(p)))
+            .collect();
+        layers.sort_unstable();
+        LayerSelection { layers }
+    }
+
+    /// Get addresses of all active (non-disconnected) peers we reach directly.
     /// Includes both Connecting and Connected peers so audio/heartbeats
-    /// flow immediately after handshake.
+    /// flow immediately after handshake (and help open NAT bindings while punching).
     pub fn connected_peer_addrs(&self) -> Vec<SocketAddr> {
         self.peers
             .values()
-            .filter(|p| p.state != PeerState::Disconnected)
+            .filter(|p| p.is_active() && p.path == PeerPath::Direct)
             .map(|p| p.addr)
             .collect()
     }
+
+    /// Where each of our simulcast layers goes, given that layers `0..count`
+    /// are being encoded: one address list per layer. A peer gets the layer it
+    /// asked for, or the best below it; peers that hid our video get none.
+    /// When the host forwards our video to anyone, it gets every layer.
+    pub fn video_layer_addrs(&self, count: usize) -> Vec<Vec<SocketAddr>> {
+        let mut addrs = vec![Vec::new(); count];
+        if count == 0 {
+            return addrs;
+        }
+        let my_id = self.my_participant_id;
+        let via_host = self.role == Role::Guest
+            && self
+                .peers
+                .values()
+                .any(|p| p.is_active() && p.path == PeerPath::Relay);
+        for peer in self
+            .peers
+            .values()
+            .filter(|p| p.is_active() && p.path == PeerPath::Direct)
+        {
+            if via_host && peer.participant_id == self.host_id {
+                for layer in &mut addrs {
+                    layer.push(peer.addr);
+                }
+            } else if peer.wants_video(my_id) {
+                let layer = (peer.wanted_layer(my_id) as usize).min(count - 1);
+                addrs[layer].push(peer.addr);
+            }
+        }
+        addrs
+    }
+}
+
+/// Host: which layer of `sender`'s video to forward to `receiver`, if any.
+fn forward_layer(sender: &Peer, receiver: &Peer) -> Option<u8> {
+    let id = sender.participant_id;
+    if !receiver.wants_video(id) {
+        return None;
+    }
+    sender.pick_layer(receiver.wanted_layer(id))
+}
+
+/// Key for a relayed pair of guests, independent of order.
+pub fn relay_pair(a: u8, b: u8) -> (u8, u8) {
+    (a.min(b), a.max(b))
 }
 
 fn rand_session_id() -> u32 {
@@ -157,3 +925,42 @@ fn rand_session_id() -> u32 {
         .unwrap_or_default();
     (t.as_nanos() & 0xFFFF_FFFF) as u32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(id: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, id], 5000))
+    }
+
+    #[test]
+    fn participant_ids_are_reused_lowest_first_until_none_is_free() {
+        let mut host = SessionState::new_host("Host".into(), String::new(), Topology::Mesh);
+        for _ in 0..3 {
+            let id = host.assign_participant_id().unwrap();
+            host.add_peer(id, format!("guest {id}"), addr(id));
+        }
+        assert_eq!(host.assign_participant_id(), Some(5));
+        host.peers.remove(&3);
+        assert_eq!(host.assign_participant_id(), Some(3));
+
+        while let Some(id) = host.assign_participant_id() {
+            assert!(id != 0 && id != host.host_id);
+            host.add_peer(id, format!("guest {id}"), addr(id));
+        }
+        assert_eq!(host.peers.len(), 254);
+    }
+
+    #[test]
+    fn new_host_hands_out_ids_around_its_own_and_the_old_hosts() {
+        let mut guest = SessionState::new_guest("Guest".into(), String::new());
+        guest.my_participant_id = 3;
+        guest.add_peer(HOST_PARTICIPANT_ID, "Host".into(), addr(1));
+        guest.add_peer(2, "guest 2".into(), addr(2));
+        guest.become_host();
+        assert_eq!(guest.assign_participant_id(), Some(4));
+        guest.peers.remove(&2);
+        assert_eq!(guest.assign_participant_id(), Some(2));
+    }
+}
@@ -0,0 +1,356 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use super::manager::{ConnectResult, NetworkManager};
+use super::memory::{LinkConditions, MemoryNetwork};
+use super::protocol::{Packet, PacketHeader, PacketType, HEADER_SIZE};
+use super::session::{SharedSessionState, Topology};
+use super::transport::Transport;
+use crate::audio::codec::{self, OPUS_FRAME_SAMPLES, SAMPLE_RATE};
+
+/// Every simulated participant uses this port, on its own address.
+const PORT: u16 = 5000;
+/// Guests join one after the other, this far apart.
+const JOIN_SPACING: Duration = Duration::from_millis(500);
+/// Pitch of the test tone each participant sends.
+const TONE_HZ: f32 = 440.0;
+
+const USAGE: &str = "Usage: hyperzoom simulate [--guests N] [--seconds N] [--seed N] [--sfu] \
+[--loss PERCENT] [--delay MS] [--jitter MS] [--reorder PERCENT] [--duplicate PERCENT] [--bandwidth KBPS]";
+
+/// A simulated call's settings, from the command line.
+struct Options {
+    guests: u8,
+    duration: Duration,
+    seed: u64,
+    topology: Topology,
+    conditions: LinkConditions,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self {
+            guests: 2,
+            duration: Duration::from_secs(10),
+            seed: 1,
+            topology: Topology::Mesh,
+            conditions: LinkConditions::default(),
+        };
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            if flag == "--sfu" {
+                options.topology = Topology::Sfu;
+                continue;
+            }
+            let value = args.next().ok_or_else(|| format!("{flag} needs a value\n{USAGE}"))?;
+            let number = value
+                .parse::<f64>()
+                .ok()
+                .filter(|v| *v >= 0.0)
+                .ok_or_else(|| format!("Bad value for {flag}: {value}\n{USAGE}"))?;
+            let c = &mut options.conditions;
+            match flag.as_str() {
+                "--guests" => options.guests = number.min(u8::MAX as f64) as u8,
+                "--seconds" => options.duration = Duration::from_secs_f64(number),
+                "--seed" => options.seed = number as u64,
+                "--loss" => c.loss = (number / 100.0).min(1.0),
+                "--delay" => c.delay = Duration::from_secs_f64(number / 1000.0),
+                "--jitter" => c.jitter = Duration::from_secs_f64(number / 1000.0),
+                "--reorder" => c.reorder = (number / 100.0).min(1.0),
+                "--duplicate" => c.duplicate = (number / 100.0).min(1.0),
+                "--bandwidth" => c.bandwidth_kbps = Some(number as u32),
+                _ => return Err(format!("Unknown option {flag}\n{USAGE}")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// One participant's end of the call. The stop senders are only held: the
+/// heartbeat and inbound tasks end when they are dropped.
+struct Participant {
+    state: SharedSessionState,
+    transport: Arc<dyn Transport>,
+    _heartbeat_stop: watch::Sender<bool>,
+    _inbound_stop: watch::Sender<bool>,
+}
+
+impl Participant {
+    fn from_result(result: ConnectResult) -> Result<Self, String> {
+        match result {
+            ConnectResult::Ready {
+                state,
+                transport,
+                heartbeat_stop,
+                inbound_stop,
+                ..
+            } => Ok(Self {
+                state,
+                transport,
+                _heartbeat_stop: heartbeat_stop,
+                _inbound_stop: inbound_stop,
+            }),
+            ConnectResult::Error(e) => Err(e),
+        }
+    }
+}
+
+/// `hyperzoom simulate`: run a host and its guests in this process over a
+/// `MemoryNetwork`, each sending a test tone as its audio, then print what
+/// every participant saw of the others. No audio or video devices are used.
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let options = Options::parse(args)?;
+    log::info!(
+        "Simulating a {:?} call with {} guest(s) for {:?}: {:?}, seed {}",
+        options.topology,
+        options.guests,
+        options.duration,
+        options.conditions,
+        options.seed
+    );
+    let network = MemoryNetwork::new(options.conditions, options.seed);
+
+    let host_addr = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), PORT));
+    let host = NetworkManager::host_on(
+        Arc::new(network.bind(host_addr)?),
+        None,
+        PORT,
+        "Host".into(),
+        String::new(),
+        options.topology,
+        false,
+    );
+    let mut participants = vec![Participant::from_result(host)?];
+
+    for i in 1..=options.guests {
+        tokio::time::sleep(JOIN_SPACING).await;
+        let addr = SocketAddr::from((Ipv4Addr::new(10, 0, i / 250, i % 250 + 1), PORT));
+        let (waiting_tx, _waiting_rx) = watch::channel(false);
+        let result = NetworkManager::join_on(
+            Arc::new(network.bind(addr)?),
+            format!("Guest {i}"),
+            String::new(),
+            host_addr,
+            PORT,
+            waiting_tx,
+        )
+        .await;
+        match Participant::from_result(result) {
+            Ok(guest) => participants.push(guest),
+            Err(e) => log::warn!("Guest {i} could not join: {e}"),
+        }
+    }
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    for participant in &participants {
+        spawn_tone(participant.state.clone(), participant.transport.clone(), stop_rx.clone())?;
+    }
+    tokio::time::sleep(options.duration).await;
+    let _ = stop_tx.send(true);
+
+    for participant in &participants {
+        print_summary(&participant.state);
+    }
+    Ok(())
+}
+
+/// Send a tone as Opus audio to every connected peer, as the audio
+/// pipeline's encode thread would, until `stop_rx` turns true.
+fn spawn_tone(
+    state: SharedSessionState,
+    transport: Arc<dyn Transport>,
+    mut stop_rx: watch::Receiver<bool>,
+) -> Result<(), String> {
+    let mut encoder = codec::create_encoder()?;
+    let frame_time = Duration::from_secs_f64(OPUS_FRAME_SAMPLES as f64 / SAMPLE_RATE as f64);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(frame_time);
+        let mut phase = 0.0f32;
+        loop {
+            tokio::select! {
+                _ = stop_rx.changed() => break,
+                _ = interval.tick() => {}
+            }
+            let mut frame = [0.0f32; OPUS_FRAME_SAMPLES];
+            for sample in &mut frame {
+                *sample = 0.2 * (phase * std::f32::consts::TAU).sin();
+                phase = (phase + TONE_HZ / SAMPLE_RATE as f32).fract();
+            }
+            let encoded = match codec::encode_frame(&mut encoder, &frame) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::warn!("Opus encode error: {e}");
+                    continue;
+                }
+            };
+            let (my_id, seq, ts, peer_addrs) = {
+                let mut s = state.lock().unwrap();
+                let seq = s.next_seq();
+                let ts = s.elapsed_ms();
+                let addrs = s.connected_peer_addrs();
+                s.record_sent(&addrs, HEADER_SIZE + encoded.len());
+                (s.my_participant_id, seq, ts, addrs)
+            };
+            let header = PacketHeader::new(PacketType::Audio, my_id, seq, ts, encoded.len() as u16);
+            let packet = Packet::new(header, encoded).to_bytes();
+            for addr in peer_addrs {
+                let _ = transport.send_audio(packet.clone(), addr);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Print one participant's view of the others, as the stats overlay shows it.
+fn print_summary(state: &SharedSessionState) {
+    let s = state.lock().unwrap();
+    println!("{} (participant {}, {:?}):", s.my_name, s.my_participant_id, s.role);
+    let mut peers: Vec<_> = s.peers.values().collect();
+    peers.sort_by_key(|p| p.participant_id);
+    for peer in peers {
+        let stats = &peer.stats;
+        let rtt = stats
+            .rtt_ms
+            .map_or_else(|| "-".to_string(), |rtt| format!("{rtt:.0} ms"));
+        println!(
+            "  {} ({}): {:?}, {:?}, RTT {rtt}, audio {} packets, {:.1}% lost, jitter {:.1} ms",
+            peer.name,
+            peer.participant_id,
+            peer.state,
+            peer.path,
+            stats.audio.packets,
+            stats.audio.total_loss_percent(),
+            stats.audio.jitter_ms()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::session::{PeerPath, PeerState, Role, SessionState};
+
+    const GUESTS: u8 = 3;
+    /// Enough attachment to need more than one send window of chunks.
+    const ATTACHMENT_BYTES: usize = 40 * 1024;
+
+    /// Lossy enough to need retransmissions everywhere, not so lossy that a
+    /// message runs out of them.
+    fn lossy() -> LinkConditions {
+        LinkConditions {
+            loss: 0.1,
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            reorder: 0.1,
+            duplicate: 0.05,
+            bandwidth_kbps: None,
+        }
+    }
+
+    /// A host and its guests, joined one after the other.
+    async fn call(network: &MemoryNetwork, topology: Topology) -> Vec<Participant> {
+        let host_addr = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), PORT));
+        let host = NetworkManager::host_on(
+            Arc::new(network.bind(host_addr).unwrap()),
+            None,
+            PORT,
+            "Host".into(),
+            "secret".into(),
+            topology,
+            false,
+        );
+        let mut participants = vec![Participant::from_result(host).unwrap()];
+        for i in 1..=GUESTS {
+            let addr = SocketAddr::from((Ipv4Addr::new(10, 0, 0, i + 1), PORT));
+            let (waiting_tx, _waiting_rx) = watch::channel(false);
+            let result = NetworkManager::join_on(
+                Arc::new(network.bind(addr).unwrap()),
+                format!("Guest {i}"),
+                "secret".into(),
+                host_addr,
+                PORT,
+                waiting_tx,
+            )
+            .await;
+            participants.push(Participant::from_result(result).unwrap());
+        }
+        participants
+    }
+
+    /// Wait until `check` holds for every participant.
+    async fn wait_for(participants: &[Participant], what: &str, check: impl Fn(&SessionState) -> bool) {
+        let all = || participants.iter().all(|p| check(&p.state.lock().unwrap()));
+        let waited = tokio::time::timeout(Duration::from_secs(30), async {
+            while !all() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(waited.is_ok(), "Timed out waiting for {what}");
+    }
+
+    fn connected_to_everyone(s: &SessionState, path: impl Fn(u8) -> PeerPath) -> bool {
+        s.peers.len() == GUESTS as usize
+            && s.peers
+                .values()
+                .all(|p| p.state == PeerState::Connected && p.path == path(p.participant_id))
+    }
+
+    /// Everyone posts a message, the first guest with an attachment, over
+    /// lossy links; everyone must end up with all of them, whole.
+    async fn chat_over_lossy_links(network: &MemoryNetwork, participants: &[Participant]) {
+        network.set_conditions(lossy());
+        let attachment: Vec<u8> = (0..ATTACHMENT_BYTES).map(|i| (i % 241) as u8).collect();
+        for (i, participant) in participants.iter().enumerate() {
+            let file = (i == 1).then(|| ("notes.bin".to_string(), attachment.clone()));
+            NetworkManager::send_chat(&participant.state, &participant.transport, &format!("Hello from {i}"), file);
+        }
+
+        wait_for(participants, "every chat message", |s| {
+            s.chat.entries.len() == participants.len()
+                && s.chat.entries.iter().all(|e| e.attachment.as_ref().is_none_or(|a| a.is_complete()))
+        })
+        .await;
+        for participant in participants {
+            let s = participant.state.lock().unwrap();
+            let mut texts: Vec<&str> = s.chat.entries.iter().map(|e| e.text.as_str()).collect();
+            texts.sort_unstable();
+            let expected: Vec<String> = (0..participants.len()).map(|i| format!("Hello from {i}")).collect();
+            assert_eq!(texts, expected, "Chat seen by {}", s.my_name);
+            let attachments: Vec<_> = s.chat.entries.iter().filter_map(|e| e.attachment.as_ref()).collect();
+            assert_eq!(attachments.len(), 1);
+            assert_eq!(attachments[0].data(), &attachment[..]);
+            assert!(
+                s.peers.values().all(|p| p.state == PeerState::Connected),
+                "{} lost a peer",
+                s.my_name
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mesh_call_delivers_chat_over_lossy_links() {
+        let network = MemoryNetwork::new(LinkConditions::default(), 1);
+        let participants = call(&network, Topology::Mesh).await;
+        wait_for(&participants, "everyone to punch through", |s| {
+            connected_to_everyone(s, |_| PeerPath::Direct)
+        })
+        .await;
+        chat_over_lossy_links(&network, &participants).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sfu_call_delivers_chat_over_lossy_links() {
+        let network = MemoryNetwork::new(LinkConditions::default(), 2);
+        let participants = call(&network, Topology::Sfu).await;
+        wait_for(&participants, "everyone to connect through the host", |s| {
+            let direct = |id| s.role == Role::Host || id == s.host_id;
+            connected_to_everyone(s, |id| if direct(id) { PeerPath::Direct } else { PeerPath::Relay })
+        })
+        .await;
+        chat_over_lossy_links(&network, &participants).await;
+    }
+}
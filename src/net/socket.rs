@@ -1,88 +1,399 @@
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
-
-use super::protocol::{Packet, PacketType, HEADER_SIZE};
-
-/// Events dispatched from the recv loop to consumers.
-#[derive(Debug, Clone)]
-pub enum InboundEvent {
-    /// Opus-encoded audio frame with sender info.
-    Audio {
-        participant_id: u8,
-        sequence: u16,
-        timestamp_ms: u32,
-        payload: Vec<u8>,
-    },
-    /// VP8 video frame fragment.
-    Video {
-        participant_id: u8,
-        sequence: u16,
-        timestamp_ms: u32,
-        is_keyframe: bool,
-        fragment_id: u8,
-        fragment_total: u8,
-        payload: Vec<u8>,
-    },
-    /// Control message payload (Hello, Welcome, PeerJoined, Heartbeat, Nack).
-    Control {
-        from: SocketAddr,
-        participant_id: u8,
-        payload: Vec<u8>,
-    },
-    /// Remote peer sent BYE.
-    Bye {
-        participant_id: u8,
-    },
+use tokio::sync::{mpsc, oneshot};
+
+use super::control::{self, Ack, ControlType, RelayForward, Reliable};
+use super::crypto::{PacketCipher, SessionKey};
+use super::protocol::{Packet, PacketHeader, PacketType, HEADER_SIZE, PROTOCOL_VERSION};
+use super::reliable::{Abandoned, ReliableChannels, Retransmit, RETRANSMIT_TICK};
+use super::sender::{Outgoing, SendClass, SendScheduler};
+use super::session::RelayRoutes;
+use super::stun::{self, StunResponder, StunServer, TransactionId};
+use super::transport::{InboundEvent, PublicAddrFuture, Transport};
+
+/// STUN retransmission timeouts: RFC 5389's 500 ms initial RTO, doubling.
+const STUN_RTO: [Duration; 3] = [
+    Duration::from_millis(500),
+    Duration::from_millis(1000),
+    Duration::from_millis(2000),
+];
+
+/// The datagram socket under a `PacketTransport`: a real UDP socket, or a
+/// node on a simulated network (see `memory::MemoryNetwork`).
+pub trait Datagram: Send + Sync + 'static {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send;
+
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    /// Our address as others see it, where the socket knows it without
+    /// asking a STUN server.
+    fn known_public_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Datagram for UdpSocket {
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send {
+        UdpSocket::send_to(self, buf, target)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        UdpSocket::recv_from(self, buf)
+    }
 }
 
-/// Thin wrapper around a tokio UdpSocket for send/recv.
-pub struct UdpTransport {
-    pub socket: Arc<UdpSocket>,
+/// The transport over a real UDP socket.
+pub type UdpTransport = PacketTransport<UdpSocket>;
+
+/// Thin wrapper around a datagram socket for send/recv.
+///
+/// Once a session key is installed, everything sent is sealed and everything
+/// received must open under it; the only plaintext packets accepted are the
+/// handshake (a joining guest's Hello and the host's Welcome). STUN responses
+/// are demultiplexed from the same socket. On the host, media from relayed
+/// guests (or from every guest, in SFU mode) is forwarded from the recv loop
+/// exactly as it arrived.
+///
+/// Control messages sent with `send_reliable` are acked, retransmitted and
+/// delivered in order (see `reliable::ReliableChannels`); the recv loop
+/// handles the acks and only dispatches each such message once.
+///
+/// Everything but STUN leaves through one send task, fed by a priority queue
+/// (see `sender::SendScheduler`): audio first, then control, then video,
+/// with our own video paced. Sending only queues, so the audio and video
+/// threads never block on the runtime. Packets are sealed as they go out,
+/// so nonces follow the order on the wire.
+///
+/// A UDP socket is dual-stack where the OS allows it. Addresses handed out by
+/// the transport are canonical: IPv4 peers appear as plain IPv4, never as
+/// IPv4-mapped IPv6.
+pub struct PacketTransport<S: Datagram> {
+    socket: Arc<S>,
+    /// Bound on `[::]`: IPv4 destinations must be mapped into IPv6.
+    dual_stack: bool,
+    cipher: Arc<Mutex<Option<PacketCipher>>>,
+    /// Our participant ID, which acks sent from the recv loop are sealed under.
+    participant_id: Arc<AtomicU8>,
+    /// Outstanding STUN Binding requests, completed by the recv loop.
+    stun_pending: Arc<Mutex<HashMap<TransactionId, oneshot::Sender<SocketAddr>>>>,
+    /// Host relay: participant ID → addresses its media is forwarded to.
+    relay_routes: Arc<Mutex<RelayRoutes>>,
+    reliable: Arc<Mutex<ReliableChannels>>,
+    sender: Arc<SendScheduler>,
 }
 
 impl UdpTransport {
-    /// Bind to `0.0.0.0:<port>`.
+    /// Bind a dual-stack socket on `[::]:<port>`, or `0.0.0.0:<port>` where
+    /// the system has no IPv6.
     pub async fn bind(port: u16) -> Result<Self, String> {
-        let addr = format!("0.0.0.0:{port}");
-        let socket = UdpSocket::bind(&addr)
-            .await
-            .map_err(|e| format!("Failed to bind UDP socket on {addr}: {e}"))?;
-        log::info!("UDP socket bound on {addr}");
-        Ok(Self {
+        let (socket, dual_stack) = match bind_dual_stack(port) {
+            Ok(socket) => (socket, true),
+            Err(e) => {
+                log::warn!("No dual-stack socket on port {port} ({e}); using IPv4 only");
+                let addr = format!("0.0.0.0:{port}");
+                let socket = UdpSocket::bind(&addr)
+                    .await
+                    .map_err(|e| format!("Failed to bind UDP socket on {addr}: {e}"))?;
+                (socket, false)
+            }
+        };
+        match socket.local_addr() {
+            Ok(addr) => log::info!("UDP socket bound on {addr}"),
+            Err(_) => log::info!("UDP socket bound on port {port}"),
+        }
+        Ok(Self::new(socket, dual_stack))
+    }
+}
+
+impl<S: Datagram> PacketTransport<S> {
+    /// Wrap a bound socket and start its send task. Must be called from
+    /// within the tokio runtime.
+    pub fn new(socket: S, dual_stack: bool) -> Self {
+        let transport = Self {
             socket: Arc::new(socket),
-        })
+            dual_stack,
+            cipher: Arc::new(Mutex::new(None)),
+            participant_id: Arc::new(AtomicU8::new(0)),
+            stun_pending: Arc::new(Mutex::new(HashMap::new())),
+            relay_routes: Arc::new(Mutex::new(RelayRoutes::default())),
+            reliable: Arc::new(Mutex::new(ReliableChannels::new())),
+            sender: Arc::new(SendScheduler::new()),
+        };
+        Self::spawn_send_loop(
+            transport.socket.clone(),
+            transport.cipher.clone(),
+            transport.sender.clone(),
+            dual_stack,
+        );
+        transport
+    }
+
+    fn enqueue(
+        &self,
+        class: SendClass,
+        packet: Vec<u8>,
+        target: SocketAddr,
+        seal: bool,
+        paced: bool,
+    ) -> Result<(), String> {
+        self.sender.push(
+            class,
+            Outgoing {
+                packet,
+                target,
+                seal,
+                paced,
+                relay: None,
+            },
+        )
+    }
+
+    /// Our public address: known outright on a simulated network, otherwise
+    /// asked of the STUN server `HYPERZOOM_STUN` names.
+    async fn discover_public_addr(&self) -> Option<SocketAddr> {
+        if let Some(addr) = self.socket.known_public_addr() {
+            return Some(addr);
+        }
+        // Held until the query completes when testing against loopback
+        let mut _responder = None;
+        let server = match StunServer::from_env() {
+            StunServer::Off => return None,
+            StunServer::Local => match StunResponder::start_local().await {
+                Ok(r) => {
+                    let addr = r.addr;
+                    _responder = Some(r);
+                    addr
+                }
+                Err(e) => {
+                    log::warn!("{e}");
+                    return None;
+                }
+            },
+            StunServer::Remote(host) => {
+                // Prefer IPv4: that is the address most guests will need
+                let resolved = tokio::net::lookup_host(&host)
+                    .await
+                    .ok()
+                    .and_then(|addrs| {
+                        addrs
+                            .filter(|a| a.is_ipv4() || self.dual_stack)
+                            .min_by_key(|a| a.is_ipv6())
+                    });
+                match resolved {
+                    Some(addr) => addr,
+                    None => {
+                        log::warn!("Could not resolve STUN server {host}");
+                        return None;
+                    }
+                }
+            }
+        };
+
+        match self.stun_binding(server).await {
+            Ok(addr) => {
+                log::info!("STUN: public address is {addr} (via {server})");
+                Some(addr)
+            }
+            Err(e) => {
+                log::warn!("STUN discovery failed: {e}");
+                None
+            }
+        }
     }
 
-    /// Send raw bytes to a specific address.
-    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<(), String> {
+    /// Ask a STUN server for our server-reflexive address: the public IP:port
+    /// this socket's traffic appears from. Requires the recv loop to be running.
+    async fn stun_binding(&self, server: SocketAddr) -> Result<SocketAddr, String> {
+        let txid = stun::new_transaction_id();
+        let (tx, mut rx) = oneshot::channel();
+        self.stun_pending.lock().unwrap().insert(txid, tx);
+        let request = stun::binding_request(&txid);
+
+        let mut result = Err(format!("No STUN response from {server}"));
+        for rto in STUN_RTO {
+            if let Err(e) = self.send_raw(&request, server).await {
+                result = Err(e);
+                break;
+            }
+            if let Ok(Ok(addr)) = tokio::time::timeout(rto, &mut rx).await {
+                result = Ok(addr);
+                break;
+            }
+        }
+        self.stun_pending.lock().unwrap().remove(&txid);
+        result
+    }
+
+    /// Send straight on the socket, bypassing the queue: for STUN only.
+    async fn send_raw(&self, buf: &[u8], target: SocketAddr) -> Result<(), String> {
         self.socket
-            .send_to(buf, target)
+            .send_to(buf, to_socket_addr(target, self.dual_stack))
             .await
             .map_err(|e| format!("UDP send_to failed: {e}"))?;
         Ok(())
     }
 
-    /// Spawn a tokio task that receives packets and dispatches them as InboundEvents.
-    /// Returns the mpsc receiver for the consumer.
-    pub fn spawn_recv_loop(
-        &self,
-    ) -> mpsc::UnboundedReceiver<InboundEvent> {
+    /// Send reliable control messages again as their timeouts run out, until
+    /// the recv loop stops.
+    fn spawn_retransmit_loop(
+        reliable: Arc<Mutex<ReliableChannels>>,
+        sender: Arc<SendScheduler>,
+        tx: mpsc::UnboundedSender<InboundEvent>,
+    ) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RETRANSMIT_TICK);
+            while !tx.is_closed() {
+                interval.tick().await;
+                let (due, abandoned) = reliable.lock().unwrap().due(std::time::Instant::now());
+                send_retransmits(&sender, due);
+                for Abandoned { addr, messages } in abandoned {
+                    let _ = tx.send(InboundEvent::Undelivered { to: addr, messages });
+                }
+            }
+        });
+    }
+
+    /// Drain the send queue onto the socket until the transport is dropped.
+    fn spawn_send_loop(
+        socket: Arc<S>,
+        cipher: Arc<Mutex<Option<PacketCipher>>>,
+        sender: Arc<SendScheduler>,
+        dual_stack: bool,
+    ) {
+        tokio::spawn(async move {
+            while let Some((batch, held)) = sender.next_batch() {
+                if batch.is_empty() {
+                    sender.wait(held).await;
+                    continue;
+                }
+                for outgoing in batch {
+                    let sealed = match seal_outgoing(&cipher, &outgoing) {
+                        Ok(sealed) => sealed,
+                        Err(e) => {
+                            log::debug!("Send to {} failed: {e}", outgoing.target);
+                            continue;
+                        }
+                    };
+                    let buf = sealed.as_deref().unwrap_or(&outgoing.packet);
+                    let dest = to_socket_addr(outgoing.target, dual_stack);
+                    if let Err(e) = socket.send_to(buf, dest).await {
+                        log::debug!("UDP send_to {} failed: {e}", outgoing.target);
+                    }
+                }
+            }
+            log::info!("Send loop stopped");
+        });
+    }
+}
+
+impl<S: Datagram> Transport for PacketTransport<S> {
+    fn set_session_key(&self, key: &SessionKey) {
+        *self.cipher.lock().unwrap() = Some(PacketCipher::new(key));
+    }
+
+    fn set_participant_id(&self, participant_id: u8) {
+        self.participant_id.store(participant_id, Ordering::Relaxed);
+    }
+
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<(), String> {
+        self.enqueue(SendClass::Control, buf.to_vec(), target, true, false)
+    }
+
+    fn send_audio(&self, packet: Vec<u8>, target: SocketAddr) -> Result<(), String> {
+        self.enqueue(SendClass::Audio, packet, target, true, false)
+    }
+
+    fn send_video(&self, packet: Vec<u8>, target: SocketAddr) -> Result<(), String> {
+        self.enqueue(SendClass::Video, packet, target, true, true)
+    }
+
+    fn set_video_pacing_kbps(&self, kbps: u32) {
+        self.sender.set_video_budget_kbps(kbps);
+    }
+
+    fn send_reliable(&self, my_id: u8, message: Vec<u8>, target: SocketAddr) -> Result<(), String> {
+        match self.reliable.lock().unwrap().push(my_id, message, target, false) {
+            Some(packet) => self.enqueue(SendClass::Control, packet, target, true, false),
+            None => Ok(()),
+        }
+    }
+
+    fn send_handshake_reliable(&self, my_id: u8, message: Vec<u8>, target: SocketAddr) -> Result<(), String> {
+        match self.reliable.lock().unwrap().push(my_id, message, target, true) {
+            Some(packet) => self.enqueue(SendClass::Control, packet, target, false, false),
+            None => Ok(()),
+        }
+    }
+
+    fn send_handshake_to(&self, buf: &[u8], target: SocketAddr) -> Result<(), String> {
+        self.enqueue(SendClass::Control, buf.to_vec(), target, false, false)
+    }
+
+    fn set_relay_routes(&self, routes: RelayRoutes) {
+        *self.relay_routes.lock().unwrap() = routes;
+    }
+
+    fn send_relayed(&self, packet: &[u8], my_id: u8, target: u8, host: SocketAddr) -> Result<(), String> {
+        self.sender.push(
+            SendClass::Control,
+            Outgoing {
+                packet: packet.to_vec(),
+                target: host,
+                seal: true,
+                paced: false,
+                relay: Some((my_id, target)),
+            },
+        )
+    }
+
+    fn forward_to(&self, sealed: &[u8], target: SocketAddr) -> Result<(), String> {
+        self.enqueue(SendClass::Control, sealed.to_vec(), target, false, false)
+    }
+
+    fn public_addr(&self) -> PublicAddrFuture<'_> {
+        Box::pin(self.discover_public_addr())
+    }
+
+    fn spawn_recv_loop(&self) -> mpsc::UnboundedReceiver<InboundEvent> {
         let (tx, rx) = mpsc::unbounded_channel();
         let socket = self.socket.clone();
+        let cipher = self.cipher.clone();
+        let participant_id = self.participant_id.clone();
+        let stun_pending = self.stun_pending.clone();
+        let relay_routes = self.relay_routes.clone();
+        let reliable = self.reliable.clone();
+        let sender = self.sender.clone();
+
+        Self::spawn_retransmit_loop(reliable.clone(), sender.clone(), tx.clone());
 
         tokio::spawn(async move {
             let mut buf = [0u8; 1500]; // MTU-sized buffer
             loop {
                 let (len, from) = match socket.recv_from(&mut buf).await {
-                    Ok(result) => result,
+                    Ok((len, from)) => (len, canonical_addr(from)),
                     Err(e) => {
                         log::warn!("UDP recv error: {e}");
                         continue;
                     }
                 };
+                let received_at = std::time::Instant::now();
+
+                if stun::is_stun(&buf[..len]) {
+                    if let Some((txid, addr)) = stun::parse_binding_response(&buf[..len]) {
+                        if let Some(waiter) = stun_pending.lock().unwrap().remove(&txid) {
+                            let _ = waiter.send(addr);
+                        }
+                    }
+                    continue;
+                }
 
                 if len < HEADER_SIZE {
                     log::debug!("Ignoring undersized packet ({len} bytes) from {from}");
@@ -97,6 +408,111 @@ impl UdpTransport {
                     }
                 };
 
+                // Another protocol version lays packets out differently. Only
+                // the handshake is read, so an incompatible guest can be told why
+                if packet.header.version != PROTOCOL_VERSION & 0x03 && !is_handshake(&packet) {
+                    log::debug!(
+                        "Dropping protocol v{} packet from {from}",
+                        packet.header.version
+                    );
+                    continue;
+                }
+
+                // Drop anything that isn't authenticated under the session key
+                let packet = if packet.header.encrypted {
+                    let opened = cipher
+                        .lock()
+                        .unwrap()
+                        .as_mut()
+                        .and_then(|c| c.open(&buf[..len]));
+                    match opened {
+                        Some((header, payload)) => Packet::new(header, payload),
+                        None => {
+                            log::debug!("Dropping unauthenticated or replayed packet from {from}");
+                            continue;
+                        }
+                    }
+                } else if is_handshake(&packet) {
+                    packet
+                } else {
+                    log::debug!("Dropping plaintext packet from {from}");
+                    continue;
+                };
+
+                // Host relay: pass media on still sealed, keeping the sender's
+                // header, in the same class as our own media but unpaced (the
+                // sender paced it). A BYE goes wherever the sender's audio does.
+                let (class, targets) = {
+                    let routes = relay_routes.lock().unwrap();
+                    let from_id = packet.header.participant_id;
+                    match packet.header.packet_type {
+                        PacketType::Audio | PacketType::Bye => {
+                            (SendClass::Audio, routes.audio.get(&from_id).cloned())
+                        }
+                        PacketType::VideoKeyframe | PacketType::VideoDelta => (
+                            SendClass::Video,
+                            routes.video.get(&(from_id, packet.header.layer)).cloned(),
+                        ),
+                        PacketType::Control => (SendClass::Control, None),
+                    }
+                };
+                for target in targets.into_iter().flatten() {
+                    let outgoing = Outgoing {
+                        packet: buf[..len].to_vec(),
+                        target,
+                        seal: false,
+                        paced: false,
+                        relay: None,
+                    };
+                    if let Err(e) = sender.push(class, outgoing) {
+                        log::debug!("Relay forward to {target} failed: {e}");
+                    }
+                }
+
+                // Reliable control: ack it, then pass on whatever is now in order
+                if packet.header.packet_type == PacketType::Control {
+                    match control::parse_control_type(&packet.payload) {
+                        Some(ControlType::Ack) => {
+                            if let Some(ack) = Ack::from_bytes(&packet.payload) {
+                                let opened = reliable.lock().unwrap().acked(from, ack, packet.header.encrypted);
+                                send_retransmits(&sender, opened);
+                            }
+                            continue;
+                        }
+                        Some(ControlType::Reliable) => {
+                            let Some(message) = Reliable::from_bytes(&packet.payload) else { continue };
+                            // A handshake in the clear could be forged: it
+                            // doesn't get to move the sealed stream along
+                            let (ack, messages) = if packet.header.encrypted {
+                                reliable.lock().unwrap().receive(from, message)
+                            } else {
+                                let ack = Ack {
+                                    stream: message.stream,
+                                    sequence: message.sequence,
+                                };
+                                (Some(ack), vec![message.message])
+                            };
+                            if let Some(ack) = ack {
+                                send_ack(&sender, participant_id.load(Ordering::Relaxed), ack, from);
+                            }
+                            for payload in messages {
+                                let event = InboundEvent::Control {
+                                    from,
+                                    participant_id: packet.header.participant_id,
+                                    payload,
+                                    received_at,
+                                };
+                                if tx.send(event).is_err() {
+                                    log::info!("Recv loop: channel closed, stopping");
+                                    return;
+                                }
+                            }
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+
                 let event = match packet.header.packet_type {
                     PacketType::Audio => InboundEvent::Audio {
                         participant_id: packet.header.participant_id,
@@ -106,6 +522,7 @@ impl UdpTransport {
                     },
                     PacketType::VideoKeyframe | PacketType::VideoDelta => InboundEvent::Video {
                         participant_id: packet.header.participant_id,
+                        layer: packet.header.layer,
                         sequence: packet.header.sequence,
                         timestamp_ms: packet.header.timestamp_ms,
                         is_keyframe: packet.header.packet_type == PacketType::VideoKeyframe,
@@ -117,6 +534,7 @@ impl UdpTransport {
                         from,
                         participant_id: packet.header.participant_id,
                         payload: packet.payload,
+                        received_at,
                     },
                     PacketType::Bye => InboundEvent::Bye {
                         participant_id: packet.header.participant_id,
@@ -133,3 +551,115 @@ impl UdpTransport {
         rx
     }
 }
+
+impl<S: Datagram> Drop for PacketTransport<S> {
+    fn drop(&mut self) {
+        self.sender.close();
+    }
+}
+
+/// Seal a packet under the session key; None if no key is set yet.
+fn seal(cipher: &Mutex<Option<PacketCipher>>, buf: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    match cipher.lock().unwrap().as_mut() {
+        Some(cipher) => cipher.seal(buf).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// What goes on the wire for `outgoing`, unless it is its packet as it is:
+/// sealed, and for the host to relay, wrapped in a RelayForward and sealed again.
+fn seal_outgoing(cipher: &Mutex<Option<PacketCipher>>, outgoing: &Outgoing) -> Result<Option<Vec<u8>>, String> {
+    let Some((my_id, target)) = outgoing.relay else {
+        return if outgoing.seal { seal(cipher, &outgoing.packet) } else { Ok(None) };
+    };
+    let inner = seal(cipher, &outgoing.packet)?.unwrap_or_else(|| outgoing.packet.clone());
+    let payload = RelayForward { target, packet: inner }.to_bytes();
+    let header = PacketHeader::new(PacketType::Control, my_id, 0, 0, payload.len() as u16);
+    let wrapped = Packet::new(header, payload).to_bytes();
+    Ok(Some(seal(cipher, &wrapped)?.unwrap_or(wrapped)))
+}
+
+/// Queue reliable messages that are due again, or that the send window has
+/// just let out.
+fn send_retransmits(sender: &SendScheduler, retransmits: Vec<Retransmit>) {
+    for retransmit in retransmits {
+        let outgoing = Outgoing {
+            packet: retransmit.packet,
+            target: retransmit.addr,
+            seal: !retransmit.handshake,
+            paced: false,
+            relay: None,
+        };
+        if let Err(e) = sender.push(SendClass::Control, outgoing) {
+            log::debug!("Retransmit to {} failed: {e}", retransmit.addr);
+        }
+    }
+}
+
+/// Ack a Reliable from the recv loop. Acks are matched by address, but are
+/// sealed under our own participant ID like any other packet of ours: the ID
+/// picks the nonce prefix and the receiver's replay window. Before a guest has
+/// the session key, its Ack of the Welcome goes in the clear.
+fn send_ack(sender: &SendScheduler, my_id: u8, ack: Ack, target: SocketAddr) {
+    let payload = ack.to_bytes();
+    let header = PacketHeader::new(PacketType::Control, my_id, 0, 0, payload.len() as u16);
+    let outgoing = Outgoing {
+        packet: Packet::new(header, payload).to_bytes(),
+        target,
+        seal: true,
+        paced: false,
+        relay: None,
+    };
+    if let Err(e) = sender.push(SendClass::Control, outgoing) {
+        log::debug!("Ack to {target} failed: {e}");
+    }
+}
+
+/// An IPv6 socket on `[::]:<port>` that also takes IPv4 traffic. Set up with
+/// socket2, since IPV6_V6ONLY defaults to on in some systems (e.g. Windows).
+fn bind_dual_stack(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// IPv4 peers reach a dual-stack socket as IPv4-mapped IPv6 (`::ffff:a.b.c.d`);
+/// turn those back into plain IPv4 so each peer has one address.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// The destination to pass the OS: on a dual-stack socket, IPv4 in mapped form.
+fn to_socket_addr(addr: SocketAddr, dual_stack: bool) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if dual_stack => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+/// The only packets that may arrive unencrypted: a Hello from a guest that
+/// has no participant ID yet, the Welcome (sent reliably), Reject or Waiting
+/// that answers it, and the guest's Ack of the Welcome, sent before it has
+/// the key (which only settles the handshake stream).
+fn is_handshake(packet: &Packet) -> bool {
+    if packet.header.packet_type != PacketType::Control {
+        return false;
+    }
+    match control::parse_control_type(&packet.payload) {
+        Some(ControlType::Hello) => packet.header.participant_id == 0,
+        Some(ControlType::Reject | ControlType::Waiting | ControlType::Ack) => true,
+        Some(ControlType::Reliable) => Reliable::from_bytes(&packet.payload)
+            .is_some_and(|r| control::parse_control_type(&r.message) == Some(ControlType::Welcome)),
+        _ => false,
+    }
+}
@@ -0,0 +1,231 @@
+use super::control::ReceiverReport;
+
+/// Smoothing factor for the RTT estimate (same as TCP's SRTT).
+const RTT_ALPHA: f32 = 0.125;
+
+/// Receive-side statistics for one media stream (audio or video) from one peer.
+///
+/// Loss is derived from sequence gaps and jitter from the spread between packet
+/// timestamps and arrival times, both as in RTP/RTCP (RFC 3550 §6.4.1, A.3, A.8).
+#[derive(Debug, Clone, Default)]
+pub struct StreamStats {
+    pub packets: u64,
+    pub bytes: u64,
+    /// First and highest sequence numbers seen, extended to 32 bits across u16 wraps.
+    base_seq: u32,
+    max_seq: Option<u32>,
+    /// Packets expected in earlier sequence runs (see `restart_sequence`).
+    expected_before: u64,
+    expected_prior: u64,
+    received_prior: u64,
+    loss_percent: f32,
+    jitter_ms: f32,
+    last_timestamp: Option<u32>,
+    last_transit: Option<i64>,
+}
+
+impl StreamStats {
+    /// Record a received packet: its sequence number, sender timestamp,
+    /// our own arrival time (both in ms) and its size on the wire.
+    pub fn record(&mut self, seq: u16, timestamp_ms: u32, arrival_ms: u32, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+
+        match self.max_seq {
+            None => {
+                self.base_seq = seq as u32;
+                self.max_seq = Some(seq as u32);
+            }
+            Some(max) => {
+                // Reordered and duplicate packets don't move the highest sequence
+                let delta = seq.wrapping_sub(max as u16) as i16;
+                if delta > 0 {
+                    self.max_seq = Some(max + delta as u32);
+                }
+            }
+        }
+
+        // Fragments of one video frame share a timestamp; only the first counts
+        if self.last_timestamp != Some(timestamp_ms) {
+            self.last_timestamp = Some(timestamp_ms);
+            let transit = arrival_ms as i64 - timestamp_ms as i64;
+            if let Some(prev) = self.last_transit {
+                let d = (transit - prev).abs() as f32;
+                self.jitter_ms += (d - self.jitter_ms) / 16.0;
+            }
+            self.last_transit = Some(transit);
+        }
+    }
+
+    /// Packets the sender has sent so far, judging by sequence numbers.
+    fn expected(&self) -> u64 {
+        self.expected_before
+            + self
+                .max_seq
+                .map(|max| (max - self.base_seq) as u64 + 1)
+                .unwrap_or(0)
+    }
+
+    /// The stream switched to an unrelated sequence space (another simulcast
+    /// layer): keep the totals, and count the next packet as the start of a new run.
+    pub fn restart_sequence(&mut self) {
+        self.expected_before = self.expected();
+        self.max_seq = None;
+    }
+
+    /// Close the current reporting interval, updating `loss_percent`.
+    pub fn roll_interval(&mut self) {
+        let expected = self.expected();
+        let expected_interval = expected - self.expected_prior;
+        let received_interval = self.packets - self.received_prior;
+        self.expected_prior = expected;
+        self.received_prior = self.packets;
+
+        // Retransmissions can make received exceed expected
+        let lost = expected_interval.saturating_sub(received_interval);
+        self.loss_percent = if expected_interval > 0 {
+            lost as f32 * 100.0 / expected_interval as f32
+        } else {
+            0.0
+        };
+    }
+
+    /// Packet loss over the last reporting interval, in percent.
+    pub fn loss_percent(&self) -> f32 {
+        self.loss_percent
+    }
+
+    /// Packet loss over the whole call, in percent.
+    pub fn total_loss_percent(&self) -> f32 {
+        let expected = self.expected();
+        if expected == 0 {
+            return 0.0;
+        }
+        expected.saturating_sub(self.packets) as f32 * 100.0 / expected as f32
+    }
+
+    /// Smoothed interarrival jitter, in milliseconds.
+    pub fn jitter_ms(&self) -> f32 {
+        self.jitter_ms
+    }
+}
+
+/// Link statistics for one peer, updated by the network tasks and read by the UI.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStats {
+    /// Smoothed round-trip time from Ping/Pong, in milliseconds.
+    pub rtt_ms: Option<f32>,
+    pub audio: StreamStats,
+    pub video: StreamStats,
+    /// Totals over all packet types, including control.
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    /// Latest feedback from this peer about our streams, not yet fed to the estimator.
+    pub remote_report: Option<ReceiverReport>,
+}
+
+impl PeerStats {
+    pub fn record_rtt(&mut self, sample_ms: f32) {
+        self.rtt_ms = Some(match self.rtt_ms {
+            Some(rtt) => rtt + RTT_ALPHA * (sample_ms - rtt),
+            None => sample_ms,
+        });
+    }
+
+    pub fn record_in(&mut self, bytes: usize) {
+        self.packets_in += 1;
+        self.bytes_in += bytes as u64;
+    }
+
+    pub fn record_out(&mut self, bytes: usize) {
+        self.packets_out += 1;
+        self.bytes_out += bytes as u64;
+    }
+
+    /// Close the current reporting interval on both media streams and
+    /// summarise it for the sender.
+    pub fn roll_interval(&mut self) -> ReceiverReport {
+        self.audio.roll_interval();
+        self.video.roll_interval();
+        ReceiverReport {
+            audio_loss_percent: self.audio.loss_percent().round() as u8,
+            video_loss_percent: self.video.loss_percent().round() as u8,
+            video_jitter_ms: self.video.jitter_ms().round().min(u16::MAX as f32) as u16,
+        }
+    }
+}
+
+/// Traffic the host has forwarded between one relayed pair of guests.
+#[derive(Debug, Clone, Default)]
+pub struct RelayStats {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loss_counts_sequence_gaps_per_interval_and_across_wraps() {
+        let mut stats = StreamStats::default();
+        // 10 sent, 2 lost, one reordered and one duplicated
+        for seq in [0, 1, 2, 4, 5, 8, 6, 9, 9] {
+            stats.record(seq, seq as u32 * 20, seq as u32 * 20, 100);
+        }
+        stats.roll_interval();
+        assert_eq!(stats.loss_percent(), 10.0, "the duplicate makes up for one loss");
+
+        // Across the u16 wrap: 65530..=65535 then 0..=3, nothing lost
+        let mut stats = StreamStats::default();
+        for seq in (65530..=65535).chain(0..=3) {
+            stats.record(seq, 0, 0, 100);
+        }
+        stats.roll_interval();
+        assert_eq!(stats.loss_percent(), 0.0);
+
+        // Next interval: 4..=13 with half missing
+        for seq in (4..14).step_by(2) {
+            stats.record(seq, 0, 0, 100);
+        }
+        stats.roll_interval();
+        assert_eq!(stats.loss_percent(), 4.0 * 100.0 / 9.0, "9 expected up to 12, 5 received");
+        assert_eq!(stats.total_loss_percent(), 4.0 * 100.0 / 19.0);
+    }
+
+    #[test]
+    fn a_new_sequence_run_keeps_the_totals() {
+        let mut stats = StreamStats::default();
+        for seq in [100, 102] {
+            stats.record(seq, 0, 0, 100);
+        }
+        stats.restart_sequence();
+        for seq in [7, 8, 9] {
+            stats.record(seq, 0, 0, 100);
+        }
+        stats.roll_interval();
+        assert_eq!(stats.loss_percent(), 100.0 / 6.0);
+        assert_eq!(stats.total_loss_percent(), 100.0 / 6.0);
+    }
+
+    #[test]
+    fn jitter_follows_rfc_3550_per_frame() {
+        let mut stats = StreamStats::default();
+        // Constant transit time: no jitter
+        for i in 0..5 {
+            stats.record(i, i as u32 * 33, 1000 + i as u32 * 33, 100);
+        }
+        assert_eq!(stats.jitter_ms(), 0.0);
+
+        // One frame 16 ms late, in three fragments: J += (|D| - J) / 16 once
+        for seq in 5..8 {
+            stats.record(seq, 5 * 33, 1000 + 5 * 33 + 16, 100);
+        }
+        assert_eq!(stats.jitter_ms(), 1.0);
+        // Back on time: the transit difference is 16 again
+        stats.record(8, 6 * 33, 1000 + 6 * 33, 100);
+        assert_eq!(stats.jitter_ms(), 1.0 + 15.0 / 16.0);
+    }
+}
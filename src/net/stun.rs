@@ -0,0 +1,219 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use tokio::net::UdpSocket;
+
+/// Public STUN server used when `HYPERZOOM_STUN` is not set.
+pub const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+
+/// Environment variable selecting the STUN server: `host:port`, `off`, or
+/// `local` to answer from an in-process responder on loopback.
+pub const STUN_SERVER_ENV: &str = "HYPERZOOM_STUN";
+
+const HEADER_SIZE: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+const FAMILY_V4: u8 = 0x01;
+const FAMILY_V6: u8 = 0x02;
+
+pub type TransactionId = [u8; 12];
+
+/// Where to send Binding requests, per `HYPERZOOM_STUN`.
+pub enum StunServer {
+    Off,
+    /// Start a `StunResponder` on loopback and query that.
+    Local,
+    Remote(String),
+}
+
+impl StunServer {
+    pub fn from_env() -> Self {
+        match std::env::var(STUN_SERVER_ENV) {
+            Ok(v) if v == "off" => Self::Off,
+            Ok(v) if v == "local" => Self::Local,
+            Ok(v) if !v.is_empty() => Self::Remote(v),
+            _ => Self::Remote(DEFAULT_STUN_SERVER.into()),
+        }
+    }
+}
+
+pub fn new_transaction_id() -> TransactionId {
+    let mut id = [0u8; 12];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
+/// True if `buf` looks like a STUN message rather than one of our packets:
+/// top two bits zero, magic cookie in place, length consistent (RFC 5389 §6).
+pub fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_SIZE
+        && buf[0] & 0xC0 == 0
+        && u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) == MAGIC_COOKIE
+        && u16::from_be_bytes([buf[2], buf[3]]) as usize == buf.len() - HEADER_SIZE
+}
+
+fn write_header(buf: &mut Vec<u8>, msg_type: u16, body_len: u16, txid: &TransactionId) {
+    buf.extend_from_slice(&msg_type.to_be_bytes());
+    buf.extend_from_slice(&body_len.to_be_bytes());
+    buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    buf.extend_from_slice(txid);
+}
+
+/// A Binding request with no attributes.
+pub fn binding_request(txid: &TransactionId) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE);
+    write_header(&mut buf, BINDING_REQUEST, 0, txid);
+    buf
+}
+
+/// A Binding success response telling the requester its address.
+pub fn binding_response(txid: &TransactionId, addr: SocketAddr) -> Vec<u8> {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let (family, ip): (u8, Vec<u8>) = match addr.ip() {
+        IpAddr::V4(ip) => (FAMILY_V4, (u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes().to_vec()),
+        IpAddr::V6(ip) => {
+            let mask = xor_mask_v6(txid);
+            (FAMILY_V6, ip.octets().iter().zip(mask).map(|(a, m)| a ^ m).collect())
+        }
+    };
+
+    let value_len = 4 + ip.len();
+    let mut buf = Vec::with_capacity(HEADER_SIZE + 4 + value_len);
+    write_header(&mut buf, BINDING_SUCCESS, (4 + value_len) as u16, txid);
+    buf.extend_from_slice(&ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+    buf.extend_from_slice(&(value_len as u16).to_be_bytes());
+    buf.push(0);
+    buf.push(family);
+    buf.extend_from_slice(&port.to_be_bytes());
+    buf.extend_from_slice(&ip);
+    buf
+}
+
+/// Parse a Binding request, returning its transaction ID.
+pub fn parse_binding_request(buf: &[u8]) -> Option<TransactionId> {
+    if !is_stun(buf) || u16::from_be_bytes([buf[0], buf[1]]) != BINDING_REQUEST {
+        return None;
+    }
+    buf[8..20].try_into().ok()
+}
+
+/// Parse a Binding success response into its transaction ID and mapped address.
+/// Prefers XOR-MAPPED-ADDRESS, falling back to the legacy MAPPED-ADDRESS.
+pub fn parse_binding_response(buf: &[u8]) -> Option<(TransactionId, SocketAddr)> {
+    if !is_stun(buf) || u16::from_be_bytes([buf[0], buf[1]]) != BINDING_SUCCESS {
+        return None;
+    }
+    let txid: TransactionId = buf[8..20].try_into().ok()?;
+
+    let mut mapped = None;
+    let mut offset = HEADER_SIZE;
+    while offset + 4 <= buf.len() {
+        let attr_type = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+        let attr_len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+        let value = buf.get(offset + 4..offset + 4 + attr_len)?;
+        match attr_type {
+            ATTR_XOR_MAPPED_ADDRESS => return Some((txid, read_address(value, Some(&txid))?)),
+            ATTR_MAPPED_ADDRESS => mapped = read_address(value, None),
+            _ => {}
+        }
+        // Attribute values are padded to a multiple of 4 bytes
+        offset += 4 + attr_len.div_ceil(4) * 4;
+    }
+    mapped.map(|addr| (txid, addr))
+}
+
+/// Decode a (XOR-)MAPPED-ADDRESS value; `xor_txid` is given for the XOR form.
+fn read_address(value: &[u8], xor_txid: Option<&TransactionId>) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let mut port = u16::from_be_bytes([value[2], value[3]]);
+    if xor_txid.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+    let ip = match value[1] {
+        FAMILY_V4 => {
+            let mut raw = u32::from_be_bytes(value.get(4..8)?.try_into().ok()?);
+            if xor_txid.is_some() {
+                raw ^= MAGIC_COOKIE;
+            }
+            IpAddr::V4(Ipv4Addr::from(raw))
+        }
+        FAMILY_V6 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            if let Some(txid) = xor_txid {
+                for (o, m) in octets.iter_mut().zip(xor_mask_v6(txid)) {
+                    *o ^= m;
+                }
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// IPv6 addresses are XORed with the magic cookie followed by the transaction ID.
+fn xor_mask_v6(txid: &TransactionId) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(txid);
+    mask
+}
+
+/// Minimal in-process STUN server: answers Binding requests with the address
+/// they came from. Lets the discovery flow run end to end on loopback.
+pub struct StunResponder {
+    pub addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl StunResponder {
+    /// Bind on `127.0.0.1` with an OS-assigned port and start answering.
+    pub async fn start_local() -> Result<Self, String> {
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .map_err(|e| format!("Failed to bind STUN responder: {e}"))?;
+        let addr = socket
+            .local_addr()
+            .map_err(|e| format!("STUN responder has no local address: {e}"))?;
+        let socket = Arc::new(socket);
+        log::info!("Local STUN responder listening on {addr}");
+
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 576];
+            loop {
+                let (len, from) = match socket.recv_from(&mut buf).await {
+                    Ok(result) => result,
+                    Err(e) => {
+                        log::warn!("STUN responder recv error: {e}");
+                        continue;
+                    }
+                };
+                let Some(txid) = parse_binding_request(&buf[..len]) else {
+                    continue;
+                };
+                let response = binding_response(&txid, from);
+                if let Err(e) = socket.send_to(&response, from).await {
+                    log::debug!("STUN responder send to {from} failed: {e}");
+                }
+            }
+        });
+
+        Ok(Self { addr, task })
+    }
+}
+
+impl Drop for StunResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
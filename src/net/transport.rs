@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Instant;
+
+use tokio::sync::mpsc;
+
+use super::crypto::SessionKey;
+use super::session::RelayRoutes;
+
+/// Events dispatched from the recv loop to consumers.
+#[derive(Debug, Clone)]
+pub enum InboundEvent {
+    /// Opus-encoded audio frame with sender info.
+    Audio {
+        participant_id: u8,
+        sequence: u16,
+        timestamp_ms: u32,
+        payload: Vec<u8>,
+    },
+    /// VP8 video frame fragment from one simulcast layer.
+    Video {
+        participant_id: u8,
+        layer: u8,
+        sequence: u16,
+        timestamp_ms: u32,
+        is_keyframe: bool,
+        fragment_id: u8,
+        fragment_total: u8,
+        payload: Vec<u8>,
+    },
+    /// Control message payload (handshake, heartbeat, media feedback, Ping/Pong, clock sync).
+    Control {
+        from: SocketAddr,
+        participant_id: u8,
+        payload: Vec<u8>,
+        /// When the packet came off the socket, for clock sync timestamps.
+        received_at: Instant,
+    },
+    /// Remote peer sent BYE.
+    Bye {
+        participant_id: u8,
+    },
+    /// Reliable control messages given up on, sent or not: `to` acked none
+    /// of ours for as many sends as one message gets.
+    Undelivered {
+        to: SocketAddr,
+        messages: Vec<Vec<u8>>,
+    },
+}
+
+/// Boxed so that `Transport` can be used as a trait object.
+pub type PublicAddrFuture<'a> = Pin<Box<dyn Future<Output = Option<SocketAddr>> + Send + 'a>>;
+
+/// What the network manager and the media pipelines send and receive
+/// through: a real UDP socket (`socket::UdpTransport`), or a node on an
+/// in-memory network with simulated loss, delay and the like
+/// (`memory::MemoryTransport`).
+///
+/// Sending only queues the packet (see `sender::SendScheduler`), so none of
+/// the send methods block. Errors mean the transport has shut down.
+pub trait Transport: Send + Sync {
+    /// Start sealing and requiring the given session key.
+    fn set_session_key(&self, key: &SessionKey);
+
+    /// Our participant ID, for the packets the transport sends on its own
+    /// (acks). Set whenever it is assigned or changes.
+    fn set_participant_id(&self, participant_id: u8);
+
+    /// Send a serialized control packet to a specific address, sealed if a
+    /// session key is set.
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<(), String>;
+
+    /// Queue an audio packet, ahead of everything else.
+    fn send_audio(&self, packet: Vec<u8>, target: SocketAddr) -> Result<(), String>;
+
+    /// Queue one of our video packets, behind audio and control and paced to
+    /// the budget set with `set_video_pacing_kbps`.
+    fn send_video(&self, packet: Vec<u8>, target: SocketAddr) -> Result<(), String>;
+
+    /// Pace our video to this send rate, all receivers together.
+    fn set_video_pacing_kbps(&self, kbps: u32);
+
+    /// Send a control message that must arrive: it is sent again until the
+    /// receiver acks it, and delivered there once and in order.
+    fn send_reliable(&self, my_id: u8, message: Vec<u8>, target: SocketAddr) -> Result<(), String>;
+
+    /// Send a Welcome reliably but in the clear, like `send_handshake_to`.
+    fn send_handshake_reliable(&self, my_id: u8, message: Vec<u8>, target: SocketAddr) -> Result<(), String>;
+
+    /// Send a Hello or Welcome in the clear: the receiver has no key yet.
+    fn send_handshake_to(&self, buf: &[u8], target: SocketAddr) -> Result<(), String>;
+
+    /// Replace the host relay's forwarding table (see `SessionState::relay_routes`).
+    fn set_relay_routes(&self, routes: RelayRoutes);
+
+    /// Send a packet to a guest we can't reach directly: sealed as if sent
+    /// straight to it, then wrapped in a RelayForward to the host.
+    fn send_relayed(&self, packet: &[u8], my_id: u8, target: u8, host: SocketAddr) -> Result<(), String>;
+
+    /// Host relay: pass an already-sealed packet on unchanged.
+    fn forward_to(&self, sealed: &[u8], target: SocketAddr) -> Result<(), String>;
+
+    /// The address guests should join us on, as seen from outside our NAT.
+    /// None if it can't be found. Requires the recv loop to be running.
+    fn public_addr(&self) -> PublicAddrFuture<'_>;
+
+    /// Spawn a tokio task that receives packets and dispatches them as InboundEvents.
+    /// Returns the mpsc receiver for the consumer.
+    fn spawn_recv_loop(&self) -> mpsc::UnboundedReceiver<InboundEvent>;
+}
@@ -1,5 +1,5 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use chrono::Utc;
 use serde::Serialize;
@@ -20,6 +20,11 @@ pub fn create_session_dir() -> Result<PathBuf, String> {
     Ok(dir)
 }
 
+/// Where chat attachments are saved when there is no session directory.
+pub fn scratch_dir() -> PathBuf {
+    std::env::temp_dir().join("HyperZoom")
+}
+
 /// The filename for the local audio recording within the session directory.
 pub fn recording_filename() -> &'static str {
     "local_recording.mp4"
@@ -31,6 +36,10 @@ pub struct SessionMetadata {
     pub start_time: String,
     pub end_time: String,
     pub duration_seconds: f64,
+    /// Where the local recording starts on the host's session clock, for aligning
+    /// tracks in post. Absent if clock sync with the host never completed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording_start_host_ms: Option<u32>,
     pub participants: Vec<ParticipantInfo>,
     pub recording: RecordingInfo,
 }
@@ -39,6 +48,27 @@ pub struct SessionMetadata {
 pub struct ParticipantInfo {
     pub id: u8,
     pub name: String,
+    /// Add to this participant's session timestamps to get the host's clock.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_offset_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock_uncertainty_ms: Option<f64>,
+    /// Link quality to this participant as seen from here (absent for ourselves).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkStatsInfo>,
+}
+
+#[derive(Serialize)]
+pub struct NetworkStatsInfo {
+    pub rtt_ms: Option<f32>,
+    pub audio_loss_percent: f32,
+    pub audio_jitter_ms: f32,
+    pub video_loss_percent: f32,
+    pub video_jitter_ms: f32,
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
 }
 
 #[derive(Serialize)]
@@ -59,3 +89,74 @@ pub fn write_metadata(dir: &PathBuf, metadata: &SessionMetadata) -> Result<(), S
     log::info!("Session metadata written: {}", path.display());
     Ok(())
 }
+
+/// One line of chat.jsonl.
+#[derive(Serialize)]
+pub struct ChatLine {
+    pub time: String,
+    pub participant_id: u8,
+    pub name: String,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<ChatAttachmentInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ChatAttachmentInfo {
+    pub name: String,
+    pub size: usize,
+    /// The saved copy, relative to the session directory. Absent if the file
+    /// never fully arrived.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// Write chat.jsonl to the session directory, one message per line.
+pub fn write_chat_log(dir: &Path, lines: &[ChatLine]) -> Result<(), String> {
+    let path = dir.join("chat.jsonl");
+    let mut jsonl = String::new();
+    for line in lines {
+        let json = serde_json::to_string(line).map_err(|e| format!("Failed to serialize chat: {e}"))?;
+        jsonl.push_str(&json);
+        jsonl.push('\n');
+    }
+    fs::write(&path, jsonl).map_err(|e| format!("Failed to write chat log: {e}"))?;
+    log::info!("Chat log written: {}", path.display());
+    Ok(())
+}
+
+/// Save a chat attachment in the session directory's attachments/, next to
+/// any earlier file of the same name rather than over it. Returns its path.
+pub fn save_attachment(dir: &Path, name: &str, data: &[u8]) -> Result<PathBuf, String> {
+    let attachments = dir.join("attachments");
+    fs::create_dir_all(&attachments).map_err(|e| format!("Failed to create attachments directory: {e}"))?;
+
+    // Whoever sent it chose the name: keep only a plain file name
+    let name: String = Path::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_control() || r#"\/:*?"<>|"#.contains(c) { '_' } else { c })
+        .collect();
+    let name = if name.is_empty() { "attachment".to_string() } else { name };
+    let stem = Path::new(&name)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = Path::new(&name).extension().map(|e| e.to_string_lossy().into_owned());
+
+    let mut path = attachments.join(&name);
+    let mut n = 1;
+    while path.exists() {
+        n += 1;
+        let numbered = match &extension {
+            Some(ext) => format!("{stem} ({n}).{ext}"),
+            None => format!("{stem} ({n})"),
+        };
+        path = attachments.join(numbered);
+    }
+    fs::write(&path, data).map_err(|e| format!("Failed to save attachment: {e}"))?;
+    log::info!("Chat attachment saved: {}", path.display());
+    Ok(path)
+}
@@ -4,7 +4,7 @@ use eframe::egui;
 
 use super::frame::VideoFrame;
 
-/// Manages per-participant egui textures and renders a 2x2 video grid.
+/// Manages per-participant egui textures and renders the video grid.
 pub struct VideoDisplay {
     local_texture: Option<egui::TextureHandle>,
     remote_textures: HashMap<u8, egui::TextureHandle>,
@@ -54,44 +54,52 @@ impl VideoDisplay {
         }
     }
 
-    /// Render the 2x2 video grid: local preview + up to 3 remote feeds.
+    /// Render the video grid: local preview + one cell per remote feed, laid
+    /// out as close to square as fits (at least 2x2). Returns the cell width
+    /// in points.
     pub fn show_grid(
         &self,
         ui: &mut egui::Ui,
         local_name: &str,
         peers: &[(u8, String)], // (participant_id, name) sorted by id
-    ) {
+    ) -> f32 {
+        // Build list of cells: [local, peer0, peer1, ...], padded to fill the grid
+        let total_cells = (1 + peers.len()).max(4);
+        let columns = (total_cells as f32).sqrt().ceil() as usize;
+        let rows = total_cells.div_ceil(columns);
+
         let available = ui.available_size();
-        let cell_w = (available.x - 8.0) / 2.0; // 8px gap
-        let cell_h = (available.y - 8.0) / 2.0;
+        let cell_w = (available.x - 4.0 * columns as f32) / columns as f32; // 4px gaps
+        let cell_h = (available.y - 4.0 * rows as f32) / rows as f32;
         let cell_size = egui::vec2(cell_w, cell_h);
 
-        // Build list of cells: [local, peer0, peer1, peer2]
-        // Up to 4 cells in a 2x2 grid
-        let total_cells = 1 + peers.len().min(3);
-
         egui::Grid::new("video_grid")
-            .num_columns(2)
+            .num_columns(columns)
             .spacing(egui::vec2(4.0, 4.0))
             .show(ui, |ui| {
-                for cell_idx in 0..4 {
-                    if cell_idx == 2 {
+                for cell_idx in 0..rows * columns {
+                    if cell_idx > 0 && cell_idx.is_multiple_of(columns) {
                         ui.end_row();
                     }
 
                     if cell_idx == 0 {
                         // Local preview
                         self.show_cell(ui, cell_size, &self.local_texture, local_name);
-                    } else if cell_idx - 1 < peers.len().min(3) {
-                        let (pid, ref name) = peers[cell_idx - 1];
-                        let tex = self.remote_textures.get(&pid);
+                    } else if let Some((pid, name)) = peers.get(cell_idx - 1) {
+                        let tex = self.remote_textures.get(pid);
                         self.show_cell(ui, cell_size, &tex.cloned(), name);
-                    } else if cell_idx < total_cells.max(4) {
+                    } else {
                         // Empty placeholder
                         self.show_placeholder(ui, cell_size, "");
                     }
                 }
             });
+        cell_w
+    }
+
+    /// Forget a peer's last frame, e.g. once its video is no longer received.
+    pub fn remove_remote(&mut self, participant_id: u8) {
+        self.remote_textures.remove(&participant_id);
     }
 
     fn show_cell(
@@ -1,9 +1,14 @@
 use std::collections::HashMap;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use crate::net::control::Nack;
 
 /// Maximum payload per UDP fragment (fits within typical MTU).
 pub const MAX_FRAGMENT_SIZE: usize = 1200;
 
+/// How many times a keyframe's missing fragments are NACKed before giving up.
+const MAX_NACKS_PER_FRAME: u8 = 3;
+
 /// Split an encoded video frame into MTU-sized fragments.
 ///
 /// Returns a list of `(fragment_id, fragment_total, data)` tuples.
@@ -30,17 +35,25 @@ struct PendingFrame {
     total: u8,
     is_keyframe: bool,
     created: Instant,
+    /// Sequence number of fragment 0 (fragments are sent with consecutive sequences).
+    base_seq: u16,
+    last_nack: Option<Instant>,
+    nacks_sent: u8,
 }
 
 /// Reassembles fragmented video frames from multiple peers.
 pub struct FragmentAssembler {
-    /// Keyed by (participant_id, timestamp_ms)
-    pending: HashMap<(u8, u32), PendingFrame>,
+    /// Keyed by (participant_id, layer, timestamp_ms)
+    pending: HashMap<(u8, u8, u32), PendingFrame>,
+    /// Recently completed frames, so late duplicates (e.g. a retransmission
+    /// that raced the original) don't start a new pending frame.
+    completed: HashMap<(u8, u8, u32), Instant>,
 }
 
 /// A fully reassembled frame ready for decoding.
 pub struct ReassembledFrame {
     pub participant_id: u8,
+    pub layer: u8,
     pub timestamp_ms: u32,
     pub data: Vec<u8>,
     pub is_keyframe: bool,
@@ -50,13 +63,17 @@ impl FragmentAssembler {
     pub fn new() -> Self {
         Self {
             pending: HashMap::new(),
+            completed: HashMap::new(),
         }
     }
 
     /// Push a fragment. Returns the complete frame if all fragments have arrived.
+    #[allow(clippy::too_many_arguments)]
     pub fn push(
         &mut self,
         participant_id: u8,
+        layer: u8,
+        sequence: u16,
         timestamp_ms: u32,
         fragment_id: u8,
         fragment_total: u8,
@@ -71,18 +88,25 @@ impl FragmentAssembler {
         if fragment_total == 1 {
             return Some(ReassembledFrame {
                 participant_id,
+                layer,
                 timestamp_ms,
                 data: data.to_vec(),
                 is_keyframe,
             });
         }
 
-        let key = (participant_id, timestamp_ms);
+        let key = (participant_id, layer, timestamp_ms);
+        if self.completed.contains_key(&key) {
+            return None;
+        }
         let pending = self.pending.entry(key).or_insert_with(|| PendingFrame {
             fragments: HashMap::new(),
             total: fragment_total,
             is_keyframe,
             created: Instant::now(),
+            base_seq: sequence.wrapping_sub(fragment_id as u16),
+            last_nack: None,
+            nacks_sent: 0,
         });
 
         pending.fragments.insert(fragment_id, data.to_vec());
@@ -90,6 +114,7 @@ impl FragmentAssembler {
         if pending.fragments.len() == pending.total as usize {
             // All fragments received — reassemble in order
             let frame = self.pending.remove(&key).unwrap();
+            self.completed.insert(key, Instant::now());
             let mut full_data = Vec::new();
             for i in 0..frame.total {
                 if let Some(frag) = frame.fragments.get(&i) {
@@ -98,6 +123,7 @@ impl FragmentAssembler {
             }
             Some(ReassembledFrame {
                 participant_id,
+                layer,
                 timestamp_ms,
                 data: full_data,
                 is_keyframe: frame.is_keyframe,
@@ -107,10 +133,168 @@ impl FragmentAssembler {
         }
     }
 
+    /// NACKs for keyframes still missing fragments `delay` after they started
+    /// arriving, one per contiguous run of missing sequence numbers.
+    ///
+    /// Each frame is re-requested at most once per `delay` and at most
+    /// `MAX_NACKS_PER_FRAME` times. Delta frames are never NACKed — the decoder
+    /// conceals them.
+    pub fn missing_keyframe_fragments(&mut self, delay: Duration) -> Vec<(u8, Nack)> {
+        let now = Instant::now();
+        let mut nacks = Vec::new();
+
+        for (&(participant_id, layer, _), pf) in self.pending.iter_mut() {
+            if !pf.is_keyframe || pf.nacks_sent >= MAX_NACKS_PER_FRAME {
+                continue;
+            }
+            let since = pf.last_nack.unwrap_or(pf.created);
+            if now.duration_since(since) < delay {
+                continue;
+            }
+
+            let mut run: Option<(u8, u8)> = None; // (first missing id, count)
+            for id in 0..pf.total {
+                if !pf.fragments.contains_key(&id) {
+                    run = match run {
+                        Some((first, count)) => Some((first, count + 1)),
+                        None => Some((id, 1)),
+                    };
+                    continue;
+                }
+                if let Some((first, count)) = run.take() {
+                    nacks.push((participant_id, Nack {
+                        layer,
+                        seq_start: pf.base_seq.wrapping_add(first as u16),
+                        count,
+                    }));
+                }
+            }
+            if let Some((first, count)) = run {
+                nacks.push((participant_id, Nack {
+                    layer,
+                    seq_start: pf.base_seq.wrapping_add(first as u16),
+                    count,
+                }));
+            }
+
+            pf.last_nack = Some(now);
+            pf.nacks_sent += 1;
+        }
+
+        nacks
+    }
+
     /// Drop incomplete frames older than the given duration.
-    pub fn expire_stale(&mut self, max_age: std::time::Duration) {
+    /// Returns the participants that lost a frame, so they can be asked for a keyframe.
+    pub fn expire_stale(&mut self, max_age: Duration) -> Vec<u8> {
         let now = Instant::now();
-        self.pending
-            .retain(|_, pf| now.duration_since(pf.created) < max_age);
+        let mut lost = Vec::new();
+        self.pending.retain(|&(participant_id, _, _), pf| {
+            let keep = now.duration_since(pf.created) < max_age;
+            if !keep && !lost.contains(&participant_id) {
+                lost.push(participant_id);
+            }
+            keep
+        });
+        self.completed
+            .retain(|_, done| now.duration_since(*done) < max_age);
+        lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame of `len` bytes whose content shows where each byte came from.
+    fn frame(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn fragments_reassemble_in_any_order() {
+        let encoded = frame(3 * MAX_FRAGMENT_SIZE + 17);
+        let fragments = fragment_payload(&encoded);
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|(_, total, data)| *total == 4 && data.len() <= MAX_FRAGMENT_SIZE));
+
+        let mut assembler = FragmentAssembler::new();
+        let mut done = None;
+        for &i in &[2usize, 0, 3, 1] {
+            let (id, total, data) = &fragments[i];
+            assert!(done.is_none());
+            done = assembler.push(7, 1, 100 + *id as u16, 5000, *id, *total, data, true);
+        }
+        let done = done.unwrap();
+        assert_eq!((done.participant_id, done.layer, done.timestamp_ms), (7, 1, 5000));
+        assert!(done.is_keyframe);
+        assert_eq!(done.data, encoded);
+    }
+
+    #[test]
+    fn late_duplicates_do_not_start_a_new_frame() {
+        let fragments = fragment_payload(&frame(2 * MAX_FRAGMENT_SIZE));
+        let mut assembler = FragmentAssembler::new();
+        for (id, total, data) in &fragments {
+            assembler.push(1, 0, *id as u16, 40, *id, *total, data, false);
+        }
+        let (id, total, data) = &fragments[0];
+        assert!(assembler.push(1, 0, 0, 40, *id, *total, data, false).is_none());
+        assert!(assembler.missing_keyframe_fragments(Duration::ZERO).is_empty());
+        assert!(assembler.expire_stale(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn frames_are_kept_apart_by_sender_and_layer() {
+        let fragments = fragment_payload(&frame(2 * MAX_FRAGMENT_SIZE));
+        let mut assembler = FragmentAssembler::new();
+        let (_, total, data) = &fragments[0];
+        assert!(assembler.push(1, 0, 0, 40, 0, *total, data, false).is_none());
+        let (_, total, data) = &fragments[1];
+        assert!(assembler.push(2, 0, 1, 40, 1, *total, data, false).is_none());
+        assert!(assembler.push(1, 1, 1, 40, 1, *total, data, false).is_none());
+        assert!(assembler.push(1, 0, 1, 40, 1, *total, data, false).is_some());
+    }
+
+    #[test]
+    fn missing_keyframe_fragments_are_nacked_by_run() {
+        let fragments = fragment_payload(&frame(6 * MAX_FRAGMENT_SIZE));
+        let mut assembler = FragmentAssembler::new();
+        // Fragments 1, 2 and 4 are lost; sequences start at 65534 and wrap
+        for &i in &[0usize, 3, 5] {
+            let (id, total, data) = &fragments[i];
+            assembler.push(3, 2, 65534u16.wrapping_add(*id as u16), 90, *id, *total, data, true);
+        }
+        // A delta frame missing fragments is left to the decoder
+        let (id, total, data) = &fragments[0];
+        assembler.push(3, 2, 200, 120, *id, *total, data, false);
+
+        let mut nacks: Vec<(u8, u16, u8)> = assembler
+            .missing_keyframe_fragments(Duration::ZERO)
+            .into_iter()
+            .map(|(participant_id, nack)| {
+                assert_eq!(nack.layer, 2);
+                (participant_id, nack.seq_start, nack.count)
+            })
+            .collect();
+        nacks.sort_unstable();
+        assert_eq!(nacks, vec![(3, 2, 1), (3, 65535, 2)]);
+
+        for _ in 1..MAX_NACKS_PER_FRAME {
+            assert_eq!(assembler.missing_keyframe_fragments(Duration::ZERO).len(), 2);
+        }
+        assert!(assembler.missing_keyframe_fragments(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn stale_frames_expire_once_per_sender() {
+        let fragments = fragment_payload(&frame(2 * MAX_FRAGMENT_SIZE));
+        let mut assembler = FragmentAssembler::new();
+        let (id, total, data) = &fragments[0];
+        assembler.push(4, 0, 0, 10, *id, *total, data, false);
+        assembler.push(4, 1, 0, 10, *id, *total, data, false);
+        assert!(assembler.expire_stale(Duration::from_secs(60)).is_empty());
+        assert_eq!(assembler.expire_stale(Duration::ZERO), vec![4]);
+        assert!(assembler.expire_stale(Duration::ZERO).is_empty());
     }
 }
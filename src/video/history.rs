@@ -0,0 +1,45 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Number of sent keyframe fragments kept for retransmission
+/// (a few keyframes' worth at MAX_FRAGMENT_SIZE).
+const MAX_HISTORY_PACKETS: usize = 256;
+
+/// Shared send history: the encode thread records fragments, the decode task
+/// answers NACKs from it.
+pub type SharedSendHistory = Arc<Mutex<SendHistory>>;
+
+/// Recently sent keyframe fragments, keyed by simulcast layer and video
+/// sequence number (each layer numbers its packets separately).
+///
+/// Stores the complete wire packet so a retransmission is byte-identical to
+/// the original (same sequence, timestamp and fragment ids).
+pub struct SendHistory {
+    packets: VecDeque<(u8, u16, Vec<u8>)>,
+}
+
+impl SendHistory {
+    pub fn new() -> Self {
+        Self {
+            packets: VecDeque::with_capacity(MAX_HISTORY_PACKETS),
+        }
+    }
+
+    /// Remember a sent packet, evicting the oldest when full.
+    pub fn record(&mut self, layer: u8, seq: u16, packet: Vec<u8>) {
+        if self.packets.len() >= MAX_HISTORY_PACKETS {
+            self.packets.pop_front();
+        }
+        self.packets.push_back((layer, seq, packet));
+    }
+
+    /// Packets of `layer` for `count` sequence numbers starting at `seq_start`.
+    /// Sequence numbers no longer in the history are skipped.
+    pub fn get_range(&self, layer: u8, seq_start: u16, count: u8) -> Vec<Vec<u8>> {
+        self.packets
+            .iter()
+            .filter(|(l, seq, _)| *l == layer && seq.wrapping_sub(seq_start) < count as u16)
+            .map(|(_, _, packet)| packet.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,137 @@
+use crate::net::protocol::VIDEO_LAYERS;
+
+/// Encoding parameters for one simulcast layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoQuality {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub bitrate_kbps: u32,
+}
+
+/// One simulcast layer's picture and the budget it takes.
+struct Layer {
+    width: u32,
+    height: u32,
+    fps: u32,
+    /// Least bitrate at `fps`; below it the layer gives up framerate.
+    min_kbps: u32,
+    reduced_fps: u32,
+    /// Least bitrate at `reduced_fps`; below it the layer is dropped.
+    reduced_min_kbps: u32,
+    max_kbps: u32,
+}
+
+/// Per simulcast layer, lowest first: a thumbnail, a grid tile and the full
+/// 480p picture.
+const LAYERS: [Layer; VIDEO_LAYERS] = [
+    Layer { width: 320, height: 180, fps: 15, min_kbps: 60, reduced_fps: 10, reduced_min_kbps: 40, max_kbps: 100 },
+    Layer { width: 640, height: 360, fps: 20, min_kbps: 150, reduced_fps: 15, reduced_min_kbps: 110, max_kbps: 250 },
+    Layer { width: 854, height: 480, fps: 24, min_kbps: 250, reduced_fps: 15, reduced_min_kbps: 180, max_kbps: 450 },
+];
+
+/// The layers a send budget can carry, lowest first; empty to stop sending
+/// video. Lower layers are filled to their maximum before a higher one starts,
+/// so a tight budget keeps the thumbnail every receiver can fall back on.
+///
+/// As the budget falls, the top layer gives up bitrate, then framerate, and
+/// only then its resolution (the layer below becomes the top); with the
+/// thumbnail gone too, video stops.
+pub fn simulcast_layers(target_kbps: u32) -> Vec<VideoQuality> {
+    let mut remaining = target_kbps;
+    let mut layers = Vec::with_capacity(VIDEO_LAYERS);
+    for layer in &LAYERS {
+        let fps = if remaining >= layer.min_kbps {
+            layer.fps
+        } else if remaining >= layer.reduced_min_kbps {
+            layer.reduced_fps
+        } else {
+            break;
+        };
+        let bitrate_kbps = remaining.min(layer.max_kbps);
+        remaining -= bitrate_kbps;
+        layers.push(VideoQuality {
+            width: layer.width,
+            height: layer.height,
+            fps,
+            bitrate_kbps,
+        });
+    }
+    layers
+}
+
+/// The smallest layer at least `width_px` wide, for a video tile that size.
+pub fn layer_for_width(width_px: f32) -> u8 {
+    LAYERS
+        .iter()
+        .position(|layer| layer.width as f32 >= width_px)
+        .unwrap_or(VIDEO_LAYERS - 1) as u8
+}
+
+/// The largest layer that fits within `width` x `height`; the thumbnail if none does.
+pub fn layer_within(width: u32, height: u32) -> u8 {
+    LAYERS
+        .iter()
+        .rposition(|layer| layer.width <= width && layer.height <= height)
+        .unwrap_or(0) as u8
+}
+
+/// Resolution of the top layer, the largest picture we send or want.
+pub fn max_resolution() -> (u32, u32) {
+    let top = &LAYERS[VIDEO_LAYERS - 1];
+    (top.width, top.height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falling_budget_gives_up_bitrate_then_framerate_then_resolution() {
+        let mut previous = simulcast_layers(800);
+        assert_eq!(previous.len(), VIDEO_LAYERS);
+        for target_kbps in (0..800).rev() {
+            let layers = simulcast_layers(target_kbps);
+            let total: u32 = layers.iter().map(|q| q.bitrate_kbps).sum();
+            assert!(total <= target_kbps);
+            // Only the top layer ever changes; those below stay at their maximum
+            for (layer, q) in layers.iter().enumerate().rev().skip(1) {
+                assert_eq!(q.bitrate_kbps, LAYERS[layer].max_kbps);
+            }
+
+            let top = previous.len() - 1;
+            let (was, spec) = (previous[top], &LAYERS[top]);
+            if layers.len() < previous.len() {
+                // Resolution goes only once framerate is down as far as it goes
+                assert_eq!(layers.len(), previous.len() - 1, "at {target_kbps} kbps");
+                assert_eq!(was.fps, spec.reduced_fps, "at {target_kbps} kbps");
+                assert_eq!(was.bitrate_kbps, spec.reduced_min_kbps, "at {target_kbps} kbps");
+            } else {
+                let now = layers[top];
+                assert_eq!((now.width, now.height), (was.width, was.height));
+                if now.fps != was.fps {
+                    // Framerate goes only once bitrate is down as far as it goes
+                    assert_eq!((was.fps, now.fps), (spec.fps, spec.reduced_fps), "at {target_kbps} kbps");
+                    assert_eq!(was.bitrate_kbps, spec.min_kbps, "at {target_kbps} kbps");
+                }
+                assert!(now.bitrate_kbps <= was.bitrate_kbps);
+            }
+            if layers.is_empty() {
+                assert_eq!(target_kbps, LAYERS[0].reduced_min_kbps - 1);
+                return;
+            }
+            previous = layers;
+        }
+        panic!("Video never stopped");
+    }
+
+    #[test]
+    fn layers_by_size() {
+        assert_eq!(layer_for_width(100.0), 0);
+        assert_eq!(layer_for_width(500.0), 1);
+        assert_eq!(layer_for_width(2000.0), VIDEO_LAYERS as u8 - 1);
+        assert_eq!(layer_within(640, 480), 1);
+        assert_eq!(layer_within(100, 100), 0);
+        assert_eq!(max_resolution(), (854, 480));
+    }
+}
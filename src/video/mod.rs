@@ -2,34 +2,63 @@ pub mod frame;
 pub mod vp8_encode;
 pub mod vp8_decode;
 pub mod fragment;
+pub mod history;
+pub mod ladder;
 pub mod capture;
 pub mod display;
 
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use ringbuf::traits::{Consumer, Split};
 use ringbuf::HeapRb;
-use tokio::net::UdpSocket;
 use tokio::runtime::Handle;
 
-use crate::net::protocol::{Packet, PacketHeader, PacketType};
-use crate::net::session::SharedSessionState;
-use crate::net::socket::InboundEvent;
+use crate::net::control::{self, ControlType, KeyframeRequest, Nack};
+use crate::net::protocol::{Packet, PacketHeader, PacketType, VIDEO_LAYERS};
+use crate::net::session::{PeerPath, SharedSessionState};
+use crate::net::transport::{InboundEvent, Transport};
 
 use capture::CameraCapture;
 use display::VideoDisplay;
 use frame::{downscale_rgb, i420_to_rgb, rgb_to_i420, VideoFrame};
 use fragment::{fragment_payload, FragmentAssembler};
+use history::{SendHistory, SharedSendHistory};
+use ladder::VideoQuality;
 use vp8_decode::Vp8Decoder;
 use vp8_encode::Vp8Encoder;
 
-/// Target encode resolution (480p, 16:9)
-const ENCODE_WIDTH: u32 = 854;
-const ENCODE_HEIGHT: u32 = 480;
+/// Local preview resolution (480p, 16:9) — also the top simulcast layer.
+const PREVIEW_WIDTH: u32 = 854;
+const PREVIEW_HEIGHT: u32 = 480;
+
+/// Minimum time between simulcast layer changes before adding a layer back, so
+/// a link at the edge of a layer's budget doesn't flap. Dropping one is immediate.
+const QUALITY_UPGRADE_HOLD: Duration = Duration::from_secs(5);
+
+/// How often the decode task checks for incomplete keyframes to NACK.
+const NACK_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a keyframe may sit incomplete before its missing fragments are NACKed.
+const NACK_DELAY: Duration = Duration::from_millis(30);
+
+/// How often incomplete frames are expired (and their senders asked for a keyframe).
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Incomplete frames older than this are dropped.
+const FRAGMENT_MAX_AGE: Duration = Duration::from_millis(200);
+
+/// Minimum gap between keyframe requests we send to the same participant.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Minimum gap between keyframes we force for requests, however many
+/// receivers ask — requests arriving sooner are served together.
+const FORCED_KEYFRAME_INTERVAL: Duration = Duration::from_millis(500);
 
 /// The video pipeline: camera capture → VP8 encode → UDP send,
 /// and inbound VP8 decode → display frames.
@@ -52,13 +81,14 @@ impl VideoPipeline {
     ///
     /// - `camera_enabled`: whether to start capturing from the camera
     /// - `state`: shared session state for peer info
-    /// - `socket`: UDP socket for sending video packets
-    /// - `handle`: tokio runtime handle for async sends
-    /// - `video_rx`: channel receiving inbound video events from the network
+    /// - `transport`: network transport for sending video packets
+    /// - `handle`: tokio runtime handle for the decode task
+    /// - `video_rx`: channel receiving inbound video events (and video NACKs) from the network,
+    ///   plus a Bye for each peer that leaves or rejoins
     pub fn new(
         camera_enabled: bool,
         state: SharedSessionState,
-        socket: Arc<UdpSocket>,
+        transport: Arc<dyn Transport>,
         handle: Handle,
         video_rx: tokio::sync::mpsc::UnboundedReceiver<InboundEvent>,
     ) -> Result<Self, String> {
@@ -88,6 +118,11 @@ impl VideoPipeline {
             None
         };
 
+        // Keyframe fragments we sent, for answering NACKs
+        let history: SharedSendHistory = Arc::new(Mutex::new(SendHistory::new()));
+        // Set by the decode task when a receiver asks for a keyframe
+        let keyframe_requested = Arc::new(AtomicBool::new(false));
+
         // Spawn VP8 encode thread
         let encode_stop = Arc::new(AtomicBool::new(false));
         let encode_thread = Self::spawn_encode_thread(
@@ -95,9 +130,10 @@ impl VideoPipeline {
             camera_flag.clone(),
             consumer,
             local_frame.clone(),
-            state,
-            socket,
-            handle.clone(),
+            state.clone(),
+            transport.clone(),
+            history.clone(),
+            keyframe_requested.clone(),
         )?;
 
         // Spawn video decode task (tokio)
@@ -107,6 +143,10 @@ impl VideoPipeline {
             decode_stop_rx,
             video_rx,
             remote_frames.clone(),
+            state,
+            transport,
+            history,
+            keyframe_requested,
         );
 
         Ok(Self {
@@ -130,28 +170,32 @@ impl VideoPipeline {
         self.camera_enabled.load(Ordering::Relaxed)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn spawn_encode_thread(
         stop: Arc<AtomicBool>,
         camera_enabled: Arc<AtomicBool>,
         mut consumer: ringbuf::HeapCons<VideoFrame>,
         local_frame: Arc<Mutex<Option<VideoFrame>>>,
         state: SharedSessionState,
-        socket: Arc<UdpSocket>,
-        handle: Handle,
+        transport: Arc<dyn Transport>,
+        history: SharedSendHistory,
+        keyframe_requested: Arc<AtomicBool>,
     ) -> Result<JoinHandle<()>, String> {
         thread::Builder::new()
             .name("video-encode".into())
             .spawn(move || {
-                let mut encoder = match Vp8Encoder::new(ENCODE_WIDTH, ENCODE_HEIGHT) {
-                    Ok(e) => e,
-                    Err(e) => {
-                        log::error!("Failed to create VP8 encoder: {e}");
-                        return;
-                    }
-                };
-
-                let mut video_seq: u16 = 0;
-                let mut frame_count: i64 = 0;
+                // One encoder per simulcast layer, lowest first; None while no
+                // receiver wants that layer
+                let mut encoders: Vec<Option<Vp8Encoder>> = Vec::new();
+                let mut layers: Vec<VideoQuality> = Vec::new();
+                let mut last_layer_change: Option<Instant> = None;
+                let mut next_frame_due = [Instant::now(); VIDEO_LAYERS];
+                let mut last_encoded: [Option<Instant>; VIDEO_LAYERS] = [None; VIDEO_LAYERS];
+                let started = Instant::now();
+
+                // Each layer numbers its packets separately
+                let mut video_seq = [0u16; VIDEO_LAYERS];
+                let mut last_forced_keyframe: Option<Instant> = None;
 
                 while !stop.load(Ordering::Relaxed) {
                     // Skip encoding if camera is disabled
@@ -160,56 +204,169 @@ impl VideoPipeline {
                         continue;
                     }
 
-                    match consumer.try_pop() {
-                        Some(raw_frame) => {
-                            // Downscale to encode resolution
-                            let scaled =
-                                downscale_rgb(&raw_frame, ENCODE_WIDTH, ENCODE_HEIGHT);
+                    let Some(raw_frame) = consumer.try_pop() else {
+                        thread::sleep(Duration::from_millis(5));
+                        continue;
+                    };
+
+                    // Downscale for local preview (and the top simulcast layer)
+                    let preview = downscale_rgb(&raw_frame, PREVIEW_WIDTH, PREVIEW_HEIGHT);
+
+                    // Follow the bandwidth estimate down immediately, but hold
+                    // before adding a layer back
+                    let target_kbps = state.lock().unwrap().bwe.target_kbps();
+                    let wanted = ladder::simulcast_layers(target_kbps);
+                    let held = last_layer_change
+                        .is_none_or(|t| t.elapsed() >= QUALITY_UPGRADE_HOLD);
+                    let count = if wanted.len() > layers.len() && !held {
+                        layers.len()
+                    } else {
+                        wanted.len()
+                    };
+                    if count != layers.len() {
+                        match wanted.get(..count).and_then(|w| w.last()) {
+                            Some(top) => log::info!(
+                                "Live video: {count} layer(s), up to {}x{} @ {} fps ({target_kbps} kbps)",
+                                top.width,
+                                top.height,
+                                top.fps
+                            ),
+                            None => log::info!("Live video paused ({target_kbps} kbps available)"),
+                        }
+                        last_layer_change = Some(Instant::now());
+                    }
+                    layers = wanted[..count].to_vec();
+                    encoders.truncate(count);
+                    encoders.resize_with(count, || None);
+
+                    let destinations = if layers.is_empty() {
+                        Vec::new()
+                    } else {
+                        state.lock().unwrap().video_layer_addrs(layers.len())
+                    };
+
+                    // Scale and convert each layer that is due a frame
+                    let now = Instant::now();
+                    let mut due = Vec::with_capacity(layers.len());
+                    for (layer, q) in layers.iter().enumerate() {
+                        if destinations[layer].is_empty() {
+                            // Nobody wants this layer: drop its encoder, and
+                            // start again from a keyframe when someone does
+                            encoders[layer] = None;
+                            continue;
+                        }
 
-                            // Convert to I420 for VP8 encoder
-                            let i420 =
-                                rgb_to_i420(&scaled.data, scaled.width, scaled.height);
+                        // Frame pacing for the layer's framerate
+                        if now < next_frame_due[layer] {
+                            continue;
+                        }
+                        let frame_interval = Duration::from_secs(1) / q.fps;
+                        next_frame_due[layer] += frame_interval;
+                        if next_frame_due[layer] + frame_interval < now {
+                            next_frame_due[layer] = now;
+                        }
 
-                            // Store downscaled frame for local preview
-                            if let Ok(mut lf) = local_frame.lock() {
-                                *lf = Some(scaled);
+                        // (Re)create the encoder on resolution change; a new encoder
+                        // starts with a keyframe, so receivers pick up the new size
+                        let encoder = &mut encoders[layer];
+                        if encoder
+                            .as_ref()
+                            .is_none_or(|e| e.width() != q.width || e.height() != q.height)
+                        {
+                            *encoder = match Vp8Encoder::new(q.width, q.height, q.bitrate_kbps) {
+                                Ok(e) => Some(e),
+                                Err(e) => {
+                                    log::error!("Failed to create VP8 encoder: {e}");
+                                    None
+                                }
+                            };
+                        }
+                        let Some(enc) = encoder.as_mut() else {
+                            continue;
+                        };
+
+                        // Retune bitrate within the layer when it moves noticeably
+                        if q.bitrate_kbps.abs_diff(enc.bitrate_kbps()) * 20 > enc.bitrate_kbps() {
+                            if let Err(e) = enc.set_bitrate(q.bitrate_kbps) {
+                                log::warn!("{e}");
                             }
+                        }
 
-                            // VP8 encode
-                            let pts = frame_count;
-                            frame_count += 1;
-
-                            match encoder.encode(&i420, pts) {
-                                Ok(packets) => {
-                                    for pkt in packets {
-                                        Self::send_video_packet(
-                                            &pkt,
-                                            &state,
-                                            &socket,
-                                            &handle,
-                                            &mut video_seq,
-                                        );
-                                    }
+                        // Convert to I420 for VP8 encoder
+                        let scaled = downscale_rgb(&preview, q.width, q.height);
+                        let i420 = rgb_to_i420(&scaled.data, scaled.width, scaled.height);
+                        due.push((layer, i420, frame_interval));
+                    }
+
+                    // Store downscaled frame for local preview
+                    if let Ok(mut lf) = local_frame.lock() {
+                        *lf = Some(preview);
+                    }
+
+                    // Serve keyframe requests on every layer, at most once per
+                    // FORCED_KEYFRAME_INTERVAL; we can't tell which layer the
+                    // requester receives
+                    let may_force = last_forced_keyframe
+                        .is_none_or(|t| t.elapsed() >= FORCED_KEYFRAME_INTERVAL);
+                    if may_force && keyframe_requested.swap(false, Ordering::Relaxed) {
+                        log::debug!("Forcing VP8 keyframe on request");
+                        for enc in encoders.iter_mut().flatten() {
+                            enc.force_keyframe();
+                        }
+                        last_forced_keyframe = Some(Instant::now());
+                    }
+
+                    // VP8 encode
+                    let pts_ms = now.duration_since(started).as_millis() as i64;
+                    for (layer, i420, frame_interval) in due {
+                        let Some(enc) = encoders[layer].as_mut() else {
+                            continue;
+                        };
+                        let duration_ms = last_encoded[layer]
+                            .map(|t| now.duration_since(t))
+                            .unwrap_or(frame_interval)
+                            .as_millis() as u64;
+                        last_encoded[layer] = Some(now);
+
+                        match enc.encode(&i420, pts_ms, duration_ms) {
+                            Ok(packets) => {
+                                for pkt in packets {
+                                    Self::send_video_packet(
+                                        &pkt,
+                                        layer as u8,
+                                        &destinations[layer],
+                                        &state,
+                                        &transport,
+                                        &history,
+                                        &mut video_seq[layer],
+                                    );
                                 }
-                                Err(e) => log::warn!("VP8 encode error: {e}"),
                             }
-                        }
-                        None => {
-                            thread::sleep(Duration::from_millis(5));
+                            Err(e) => log::warn!("VP8 encode error: {e}"),
                         }
                     }
                 }
 
                 // Flush remaining encoder packets
-                match encoder.finish() {
-                    Ok(remaining) => {
-                        for pkt in remaining {
-                            Self::send_video_packet(
-                                &pkt, &state, &socket, &handle, &mut video_seq,
-                            );
+                let destinations = state.lock().unwrap().video_layer_addrs(encoders.len());
+                for (layer, encoder) in encoders.into_iter().enumerate() {
+                    let Some(enc) = encoder else { continue };
+                    match enc.finish() {
+                        Ok(remaining) => {
+                            for pkt in remaining {
+                                Self::send_video_packet(
+                                    &pkt,
+                                    layer as u8,
+                                    &destinations[layer],
+                                    &state,
+                                    &transport,
+                                    &history,
+                                    &mut video_seq[layer],
+                                );
+                            }
                         }
+                        Err(e) => log::debug!("VP8 finish error: {e}"),
                     }
-                    Err(e) => log::debug!("VP8 finish error: {e}"),
                 }
 
                 log::info!("Video encode thread stopped");
@@ -217,22 +374,26 @@ impl VideoPipeline {
             .map_err(|e| format!("Failed to spawn video encode thread: {e}"))
     }
 
+    /// Fragment and send one encoded packet of a simulcast layer to the
+    /// addresses receiving that layer.
     fn send_video_packet(
         pkt: &vp8_encode::EncodedFrame,
+        layer: u8,
+        peer_addrs: &[SocketAddr],
         state: &SharedSessionState,
-        socket: &Arc<UdpSocket>,
-        handle: &Handle,
+        transport: &Arc<dyn Transport>,
+        history: &SharedSendHistory,
         video_seq: &mut u16,
     ) {
-        let (my_id, ts, peer_addrs) = {
-            let s = state.lock().unwrap();
-            (s.my_participant_id, s.elapsed_ms(), s.connected_peer_addrs())
-        };
-
         if peer_addrs.is_empty() {
             return;
         }
 
+        let (my_id, ts) = {
+            let s = state.lock().unwrap();
+            (s.my_participant_id, s.elapsed_ms())
+        };
+
         let packet_type = if pkt.is_keyframe {
             PacketType::VideoKeyframe
         } else {
@@ -240,6 +401,7 @@ impl VideoPipeline {
         };
 
         let fragments = fragment_payload(&pkt.data);
+        let mut sent_sizes = Vec::with_capacity(fragments.len());
 
         for (frag_id, frag_total, frag_data) in &fragments {
             let seq = *video_seq;
@@ -252,96 +414,231 @@ impl VideoPipeline {
                 ts,
                 frag_data.len() as u16,
             );
+            header.layer = layer;
             header.fragment_id = *frag_id;
             header.fragment_total = *frag_total;
 
             let packet_bytes = Packet::new(header, frag_data.clone()).to_bytes();
 
-            for addr in &peer_addrs {
-                let bytes = packet_bytes.clone();
-                let sock = socket.clone();
-                let target = *addr;
-                let _ = handle.block_on(async move {
-                    sock.send_to(&bytes, target).await
-                });
+            // Keep keyframe fragments around in case a receiver NACKs them
+            if pkt.is_keyframe {
+                if let Ok(mut h) = history.lock() {
+                    h.record(layer, seq, packet_bytes.clone());
+                }
+            }
+
+            sent_sizes.push(packet_bytes.len());
+            // Paced behind any queued audio; never waits on the network
+            for addr in peer_addrs {
+                if let Err(e) = transport.send_video(packet_bytes.clone(), *addr) {
+                    log::debug!("Video send to {addr} failed: {e}");
+                }
             }
         }
+
+        let mut s = state.lock().unwrap();
+        for size in sent_sizes {
+            s.record_sent(peer_addrs, size);
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn spawn_decode_task(
         handle: Handle,
         mut stop_rx: tokio::sync::watch::Receiver<bool>,
         mut video_rx: tokio::sync::mpsc::UnboundedReceiver<InboundEvent>,
         remote_frames: Arc<Mutex<HashMap<u8, VideoFrame>>>,
+        state: SharedSessionState,
+        transport: Arc<dyn Transport>,
+        history: SharedSendHistory,
+        keyframe_requested: Arc<AtomicBool>,
     ) {
         handle.spawn(async move {
             let mut assembler = FragmentAssembler::new();
             let mut decoders: HashMap<u8, Vp8Decoder> = HashMap::new();
+            // The simulcast layer each peer's decoder is following
+            let mut decoded_layers: HashMap<u8, u8> = HashMap::new();
             let mut last_expire = tokio::time::Instant::now();
+            let mut nack_tick = tokio::time::interval(NACK_CHECK_INTERVAL);
+            let mut keyframe_requests: HashMap<u8, Instant> = HashMap::new();
 
             loop {
                 tokio::select! {
                     _ = stop_rx.changed() => break,
+                    _ = nack_tick.tick() => {
+                        for (participant_id, nack) in assembler.missing_keyframe_fragments(NACK_DELAY) {
+                            log::debug!(
+                                "NACK to peer {participant_id}: layer {} seq {}+{}",
+                                nack.layer,
+                                nack.seq_start,
+                                nack.count
+                            );
+                            Self::send_control(&state, &transport, participant_id, nack.to_bytes());
+                        }
+
+                        // Periodically expire stale fragments; a lost frame
+                        // leaves the decoder out of sync until the next keyframe
+                        if last_expire.elapsed() >= EXPIRE_INTERVAL {
+                            for participant_id in assembler.expire_stale(FRAGMENT_MAX_AGE) {
+                                Self::request_keyframe(
+                                    &state,
+                                    &transport,
+                                    &mut keyframe_requests,
+                                    participant_id,
+                                );
+                            }
+                            last_expire = tokio::time::Instant::now();
+                        }
+                    }
                     event = video_rx.recv() => {
                         let Some(event) = event else { break };
-                        if let InboundEvent::Video {
-                            participant_id,
-                            timestamp_ms,
-                            is_keyframe,
-                            fragment_id,
-                            fragment_total,
-                            payload,
-                            ..
-                        } = event {
-                            if let Some(reassembled) = assembler.push(
+                        match event {
+                            InboundEvent::Video {
                                 participant_id,
+                                layer,
+                                sequence,
                                 timestamp_ms,
+                                is_keyframe,
                                 fragment_id,
                                 fragment_total,
-                                &payload,
-                                is_keyframe,
-                            ) {
-                                // Get or create decoder for this peer
-                                let decoder = decoders
-                                    .entry(reassembled.participant_id)
-                                    .or_insert_with(|| {
-                                        Vp8Decoder::new().expect("VP8 decoder init failed")
-                                    });
-
-                                match decoder.decode(&reassembled.data) {
-                                    Ok(Some(decoded)) => {
-                                        let rgb = i420_to_rgb(
-                                            &decoded.data,
-                                            decoded.width,
-                                            decoded.height,
-                                        );
-                                        let frame = VideoFrame {
-                                            data: rgb,
-                                            width: decoded.width,
-                                            height: decoded.height,
-                                        };
-                                        if let Ok(mut rf) = remote_frames.lock() {
-                                            rf.insert(
+                                payload,
+                            } => {
+                                if let Some(reassembled) = assembler.push(
+                                    participant_id,
+                                    layer,
+                                    sequence,
+                                    timestamp_ms,
+                                    fragment_id,
+                                    fragment_total,
+                                    &payload,
+                                    is_keyframe,
+                                ) {
+                                    // Switching layers means a different stream:
+                                    // wait for its keyframe before decoding
+                                    let pid = reassembled.participant_id;
+                                    if decoded_layers.get(&pid) != Some(&reassembled.layer) {
+                                        if !reassembled.is_keyframe {
+                                            Self::request_keyframe(
+                                                &state,
+                                                &transport,
+                                                &mut keyframe_requests,
+                                                pid,
+                                            );
+                                            continue;
+                                        }
+                                        log::debug!("Peer {pid}: now decoding layer {}", reassembled.layer);
+                                        decoded_layers.insert(pid, reassembled.layer);
+                                    }
+
+                                    // Get or create decoder for this peer; if libvpx
+                                    // won't give us one, skip the frame and try again
+                                    // on the next, as the encoder does
+                                    let decoder = match decoders.entry(reassembled.participant_id) {
+                                        Entry::Occupied(entry) => entry.into_mut(),
+                                        Entry::Vacant(entry) => match Vp8Decoder::new() {
+                                            Ok(decoder) => entry.insert(decoder),
+                                            Err(e) => {
+                                                log::error!("Failed to create VP8 decoder: {e}");
+                                                continue;
+                                            }
+                                        },
+                                    };
+
+                                    match decoder.decode(&reassembled.data) {
+                                        Ok(Some(decoded)) => {
+                                            let rgb = i420_to_rgb(
+                                                &decoded.data,
+                                                decoded.width,
+                                                decoded.height,
+                                            );
+                                            let frame = VideoFrame {
+                                                data: rgb,
+                                                width: decoded.width,
+                                                height: decoded.height,
+                                            };
+                                            if let Ok(mut rf) = remote_frames.lock() {
+                                                rf.insert(
+                                                    reassembled.participant_id,
+                                                    frame,
+                                                );
+                                            }
+                                        }
+                                        Ok(None) => {}
+                                        Err(e) => {
+                                            log::debug!(
+                                                "VP8 decode error for peer {}: {e}",
+                                                reassembled.participant_id
+                                            );
+                                            Self::request_keyframe(
+                                                &state,
+                                                &transport,
+                                                &mut keyframe_requests,
                                                 reassembled.participant_id,
-                                                frame,
                                             );
                                         }
                                     }
-                                    Ok(None) => {}
-                                    Err(e) => {
-                                        log::debug!(
-                                            "VP8 decode error for peer {}: {e}",
-                                            reassembled.participant_id
-                                        );
+                                }
+                            }
+                            InboundEvent::Control { from, participant_id, payload, .. } => {
+                                match control::parse_control_type(&payload) {
+                                    Some(ControlType::Nack) => {}
+                                    Some(ControlType::KeyframeRequest) => {
+                                        if KeyframeRequest::from_bytes(&payload).is_some() {
+                                            log::debug!("Keyframe request from {from}");
+                                            keyframe_requested.store(true, Ordering::Relaxed);
+                                        }
+                                        continue;
+                                    }
+                                    _ => continue,
+                                }
+                                let Some(nack) = Nack::from_bytes(&payload) else { continue };
+                                // Retransmit whatever we still have, byte-identical to the original
+                                let packets = match history.lock() {
+                                    Ok(h) => h.get_range(nack.layer, nack.seq_start, nack.count),
+                                    Err(_) => continue,
+                                };
+                                log::debug!(
+                                    "NACK from {from}: layer {} seq {}+{}, resending {}",
+                                    nack.layer,
+                                    nack.seq_start,
+                                    nack.count,
+                                    packets.len()
+                                );
+                                // A relayed NACK came through the host, which would pass
+                                // plain video on to every subscriber: address the
+                                // resend to the one that asked
+                                let (my_id, relay_via) = {
+                                    let s = state.lock().unwrap();
+                                    let relay_via = match s.peers.get(&participant_id) {
+                                        Some(peer) if peer.path == PeerPath::Relay => {
+                                            s.peers.get(&s.host_id).map(|h| h.addr)
+                                        }
+                                        _ => None,
+                                    };
+                                    (s.my_participant_id, relay_via)
+                                };
+                                for packet in packets {
+                                    let len = packet.len();
+                                    let sent = match relay_via {
+                                        Some(host) => transport.send_relayed(&packet, my_id, participant_id, host),
+                                        None => transport.send_video(packet, from),
+                                    };
+                                    if sent.is_ok() {
+                                        state.lock().unwrap().record_sent(&[from], len);
                                     }
                                 }
                             }
-
-                            // Periodically expire stale fragments
-                            if last_expire.elapsed() > Duration::from_millis(500) {
-                                assembler.expire_stale(Duration::from_millis(200));
-                                last_expire = tokio::time::Instant::now();
+                            InboundEvent::Bye { participant_id } => {
+                                // Left, or rejoining: its next stream starts
+                                // from a keyframe on a fresh decoder
+                                decoders.remove(&participant_id);
+                                decoded_layers.remove(&participant_id);
+                                keyframe_requests.remove(&participant_id);
+                                if let Ok(mut rf) = remote_frames.lock() {
+                                    rf.remove(&participant_id);
+                                }
                             }
+                            _ => {}
                         }
                     }
                 }
@@ -350,6 +647,60 @@ impl VideoPipeline {
             log::info!("Video decode task stopped");
         });
     }
+
+    /// Ask a participant for a keyframe, at most once per KEYFRAME_REQUEST_INTERVAL.
+    fn request_keyframe(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        last_requests: &mut HashMap<u8, Instant>,
+        participant_id: u8,
+    ) {
+        if last_requests
+            .get(&participant_id)
+            .is_some_and(|t| t.elapsed() < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return;
+        }
+        last_requests.insert(participant_id, Instant::now());
+        log::debug!("Requesting keyframe from peer {participant_id}");
+        Self::send_control(state, transport, participant_id, KeyframeRequest.to_bytes());
+    }
+
+    /// Send a video feedback control message (NACK, keyframe request) to a participant.
+    fn send_control(
+        state: &SharedSessionState,
+        transport: &Arc<dyn Transport>,
+        participant_id: u8,
+        payload: Vec<u8>,
+    ) {
+        let (my_id, addr, relay_via) = {
+            let s = state.lock().unwrap();
+            let Some(peer) = s.peers.get(&participant_id) else { return };
+            // Relayed peers are reached through the host
+            let relay_via = match peer.path {
+                PeerPath::Relay => s.peers.get(&s.host_id).map(|h| h.addr),
+                PeerPath::Direct => None,
+            };
+            (s.my_participant_id, peer.addr, relay_via)
+        };
+
+        let header = PacketHeader::new(
+            PacketType::Control,
+            my_id,
+            0,
+            0,
+            payload.len() as u16,
+        );
+        let packet = Packet::new(header, payload).to_bytes();
+        let sent = match relay_via {
+            Some(host) => transport.send_relayed(&packet, my_id, participant_id, host),
+            None => transport.send_to(&packet, addr),
+        };
+        match sent {
+            Ok(_) => state.lock().unwrap().record_sent(&[addr], packet.len()),
+            Err(e) => log::debug!("Failed to send video feedback to {addr}: {e}"),
+        }
+    }
 }
 
 impl Drop for VideoPipeline {
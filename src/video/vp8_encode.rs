@@ -1,4 +1,9 @@
-/// Thin wrapper around `vpx-encode` for VP8 encoding.
+/// VP8 encoder wrapper using raw `env-libvpx-sys` FFI.
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::slice;
+
+use vpx_sys as vpx;
 
 pub struct EncodedFrame {
     pub data: Vec<u8>,
@@ -7,67 +12,212 @@ pub struct EncodedFrame {
 }
 
 pub struct Vp8Encoder {
-    inner: vpx_encode::Encoder,
+    ctx: vpx::vpx_codec_ctx_t,
+    /// Kept so rate control can be retuned at runtime via `vpx_codec_enc_config_set`.
+    cfg: vpx::vpx_codec_enc_cfg_t,
+    width: u32,
+    height: u32,
+    /// Set by `force_keyframe`, consumed by the next `encode`.
+    force_keyframe: bool,
 }
 
+// SAFETY: The encoder context is only used from the encode thread.
+// vpx_codec_ctx_t contains internal pointers that are not inherently Send,
+// but exclusive access guarantees safety.
+unsafe impl Send for Vp8Encoder {}
+
 impl Vp8Encoder {
-    /// Create a VP8 encoder for the given resolution.
+    /// Create a VP8 encoder for the given resolution and target bitrate.
     ///
-    /// Default config: 400 kbps VBR, timebase 1/24, keyframe every ~2s.
-    pub fn new(width: u32, height: u32) -> Result<Self, String> {
-        let config = vpx_encode::Config {
-            width,
-            height,
-            timebase: [1, 24],
-            bitrate: 400,
-            codec: vpx_encode::VideoCodecId::VP8,
-        };
-        let encoder = vpx_encode::Encoder::new(config)
-            .map_err(|e| format!("VP8 encoder init failed: {e}"))?;
-        Ok(Self { inner: encoder })
-    }
+    /// VBR, millisecond timebase (pts/duration are wall-clock ms, so dropping
+    /// frames doesn't skew rate control), keyframe every ~2s.
+    pub fn new(width: u32, height: u32, bitrate_kbps: u32) -> Result<Self, String> {
+        if (width | height) & 1 != 0 {
+            return Err(format!("VP8 encoder needs even dimensions, got {width}x{height}"));
+        }
+
+        unsafe {
+            let iface = vpx::vpx_codec_vp8_cx();
+            if iface.is_null() {
+                return Err("vpx_codec_vp8_cx returned null".into());
+            }
 
-    /// Encode a single I420 frame. Returns zero or more encoded packets.
-    pub fn encode(&mut self, i420_data: &[u8], pts: i64) -> Result<Vec<EncodedFrame>, String> {
-        let packets = self
-            .inner
-            .encode(pts, i420_data)
-            .map_err(|e| format!("VP8 encode failed: {e}"))?;
-
-        let frames: Vec<EncodedFrame> = packets
-            .into_iter()
-            .map(|pkt| EncodedFrame {
-                data: pkt.data.to_vec(),
-                is_keyframe: pkt.key,
-                pts: pkt.pts,
+            let mut cfg: vpx::vpx_codec_enc_cfg_t = MaybeUninit::zeroed().assume_init();
+            let err = vpx::vpx_codec_enc_config_default(iface, &mut cfg, 0);
+            if err != vpx::VPX_CODEC_OK {
+                return Err(format!("vpx_codec_enc_config_default failed: error {err:?}"));
+            }
+
+            cfg.g_w = width;
+            cfg.g_h = height;
+            cfg.g_timebase.num = 1;
+            cfg.g_timebase.den = 1000;
+            cfg.rc_target_bitrate = bitrate_kbps;
+            cfg.g_threads = 8;
+            cfg.g_error_resilient = vpx::VPX_ERROR_RESILIENT_DEFAULT;
+
+            let mut ctx: vpx::vpx_codec_ctx_t = MaybeUninit::zeroed().assume_init();
+            let err = vpx::vpx_codec_enc_init_ver(
+                &mut ctx,
+                iface,
+                &cfg,
+                0,
+                vpx::VPX_ENCODER_ABI_VERSION as i32,
+            );
+            if err != vpx::VPX_CODEC_OK {
+                return Err(format!("VP8 encoder init failed: error {err:?}"));
+            }
+
+            Ok(Self {
+                ctx,
+                cfg,
+                width,
+                height,
+                force_keyframe: false,
             })
-            .collect();
+        }
+    }
 
-        Ok(frames)
+    pub fn width(&self) -> u32 {
+        self.width
     }
 
-    /// Flush remaining packets from the encoder.
-    pub fn finish(self) -> Result<Vec<EncodedFrame>, String> {
-        let mut finish = self
-            .inner
-            .finish()
-            .map_err(|e| format!("VP8 finish failed: {e}"))?;
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn bitrate_kbps(&self) -> u32 {
+        self.cfg.rc_target_bitrate
+    }
+
+    /// Change the target bitrate without restarting the stream.
+    pub fn set_bitrate(&mut self, bitrate_kbps: u32) -> Result<(), String> {
+        let previous = self.cfg.rc_target_bitrate;
+        self.cfg.rc_target_bitrate = bitrate_kbps;
+        let err = unsafe { vpx::vpx_codec_enc_config_set(&mut self.ctx, &self.cfg) };
+        if err != vpx::VPX_CODEC_OK {
+            self.cfg.rc_target_bitrate = previous;
+            return Err(format!("VP8 bitrate change failed: error {err:?}"));
+        }
+        Ok(())
+    }
+
+    /// Make the next encoded frame a keyframe (e.g. a receiver lost sync).
+    pub fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    /// Encode a single I420 frame shown at `pts_ms` for `duration_ms`.
+    /// Returns zero or more encoded packets.
+    pub fn encode(
+        &mut self,
+        i420_data: &[u8],
+        pts_ms: i64,
+        duration_ms: u64,
+    ) -> Result<Vec<EncodedFrame>, String> {
+        let expected = (self.width * self.height * 3 / 2) as usize;
+        if i420_data.len() < expected {
+            return Err(format!(
+                "VP8 encode failed: I420 buffer is {} bytes, need {expected}",
+                i420_data.len()
+            ));
+        }
+
+        let flags = if self.force_keyframe {
+            vpx::VPX_EFLAG_FORCE_KF as vpx::vpx_enc_frame_flags_t
+        } else {
+            0
+        };
 
+        unsafe {
+            let mut image: vpx::vpx_image_t = MaybeUninit::zeroed().assume_init();
+            let wrapped = vpx::vpx_img_wrap(
+                &mut image,
+                vpx::vpx_img_fmt::VPX_IMG_FMT_I420,
+                self.width,
+                self.height,
+                1,
+                // libvpx only reads from the wrapped buffer
+                i420_data.as_ptr() as *mut u8,
+            );
+            if wrapped.is_null() {
+                return Err("vpx_img_wrap failed".into());
+            }
+
+            let err = vpx::vpx_codec_encode(
+                &mut self.ctx,
+                &image,
+                pts_ms,
+                duration_ms.max(1) as _,
+                flags,
+                vpx::VPX_DL_REALTIME as _,
+            );
+            if err != vpx::VPX_CODEC_OK {
+                return Err(format!("VP8 encode failed: error {err:?}"));
+            }
+        }
+
+        self.force_keyframe = false;
+        Ok(self.drain_packets())
+    }
+
+    /// Flush remaining packets from the encoder.
+    pub fn finish(mut self) -> Result<Vec<EncodedFrame>, String> {
         let mut frames = Vec::new();
         loop {
-            match finish.next() {
-                Ok(Some(pkt)) => {
-                    frames.push(EncodedFrame {
-                        data: pkt.data.to_vec(),
-                        is_keyframe: pkt.key,
-                        pts: pkt.pts,
-                    });
-                }
-                Ok(None) => break,
-                Err(e) => return Err(format!("VP8 finish packet error: {e}")),
+            let err = unsafe {
+                vpx::vpx_codec_encode(
+                    &mut self.ctx,
+                    ptr::null(),
+                    -1, // pts
+                    1,  // duration
+                    0,  // flags
+                    vpx::VPX_DL_REALTIME as _,
+                )
+            };
+            if err != vpx::VPX_CODEC_OK {
+                return Err(format!("VP8 finish failed: error {err:?}"));
             }
+
+            let flushed = self.drain_packets();
+            if flushed.is_empty() {
+                break;
+            }
+            frames.extend(flushed);
         }
 
         Ok(frames)
     }
+
+    /// Collect the compressed frames produced by the last `vpx_codec_encode` call.
+    fn drain_packets(&mut self) -> Vec<EncodedFrame> {
+        let mut frames = Vec::new();
+        let mut iter: vpx::vpx_codec_iter_t = ptr::null();
+        unsafe {
+            loop {
+                let pkt = vpx::vpx_codec_get_cx_data(&mut self.ctx, &mut iter);
+                if pkt.is_null() {
+                    break;
+                }
+                if (*pkt).kind != vpx::vpx_codec_cx_pkt_kind::VPX_CODEC_CX_FRAME_PKT {
+                    continue;
+                }
+                let f = &(*pkt).data.frame;
+                frames.push(EncodedFrame {
+                    data: slice::from_raw_parts(f.buf as *const u8, f.sz).to_vec(),
+                    is_keyframe: (f.flags & vpx::VPX_FRAME_IS_KEY) != 0,
+                    pts: f.pts,
+                });
+            }
+        }
+        frames
+    }
+}
+
+impl Drop for Vp8Encoder {
+    fn drop(&mut self) {
+        unsafe {
+            vpx::vpx_codec_destroy(&mut self.ctx);
+        }
+    }
 }